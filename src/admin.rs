@@ -0,0 +1,194 @@
+use crate::storage_manager::{StorageError, StorageManager};
+use crate::transactions::{ImportSummary, InternalMessage, StorageOverview};
+use axum::extract::{Path, Query};
+use axum::http::StatusCode;
+use axum::response::{IntoResponse, Response};
+use axum::{Extension, Json};
+use chrono::Utc;
+use serde::Deserialize;
+use std::sync::Arc;
+use tracing::instrument;
+
+/// How far back the audit log reaches when no `since` is given.
+const AUDIT_DEFAULT_WINDOW_MICROS: i64 = 24 * 60 * 60 * 1_000_000;
+
+#[instrument(skip(storage_manager, body))]
+pub async fn import(
+    storage_manager: Extension<Arc<StorageManager>>,
+    body: String,
+) -> AdminResponse {
+    match storage_manager.import_transactions(&body).await {
+        Ok(summary) => AdminResponse::Imported(summary),
+        Err(e) => match e.downcast::<StorageError>() {
+            Ok(e @ StorageError::BatchTooLarge(_)) => AdminResponse::TooLarge(e.to_string()),
+            _ => AdminResponse::FailedToImport,
+        },
+    }
+}
+
+/// Transactions written after `since`, or in the last day without it, oldest
+/// first, each with the user whose request wrote it where known.
+#[instrument(skip(storage_manager))]
+pub async fn audit(
+    Query(query): Query<AuditQuery>,
+    storage_manager: Extension<Arc<StorageManager>>,
+) -> AdminResponse {
+    let since = query
+        .since
+        .unwrap_or_else(|| Utc::now().timestamp_micros() - AUDIT_DEFAULT_WINDOW_MICROS);
+
+    match storage_manager.transactions_since(since).await {
+        Ok(transactions) => AdminResponse::Audit(transactions),
+        Err(_) => AdminResponse::FailedToRead,
+    }
+}
+
+/// The full history of one item, by its alt key, oldest first.
+#[instrument(skip(storage_manager))]
+pub async fn history(
+    Path(alt_key): Path<String>,
+    storage_manager: Extension<Arc<StorageManager>>,
+) -> AdminResponse {
+    match storage_manager.transactions_for_alt_key(&alt_key).await {
+        Ok(transactions) if transactions.is_empty() => AdminResponse::NotFound,
+        Ok(transactions) => AdminResponse::Audit(transactions),
+        Err(_) => AdminResponse::FailedToRead,
+    }
+}
+
+/// Rehashes every live item and lists the files that no longer match the
+/// checksum recorded when they were written.
+#[instrument(skip(storage_manager))]
+pub async fn verify(storage_manager: Extension<Arc<StorageManager>>) -> AdminResponse {
+    match storage_manager.verify_blobs().await {
+        Ok(bad) => AdminResponse::Verified(bad),
+        Err(_) => AdminResponse::FailedToRead,
+    }
+}
+
+#[instrument(skip(storage_manager))]
+pub async fn stats(storage_manager: Extension<Arc<StorageManager>>) -> AdminResponse {
+    match storage_manager.overview().await {
+        Ok(overview) => AdminResponse::Stats(overview),
+        Err(_) => AdminResponse::FailedToRead,
+    }
+}
+
+#[derive(Debug, Deserialize)]
+pub struct AuditQuery {
+    /// Unix micros.
+    since: Option<i64>,
+}
+
+#[derive(Debug)]
+pub enum AdminResponse {
+    Imported(ImportSummary),
+    Audit(Vec<InternalMessage>),
+    Verified(Vec<String>),
+    Stats(StorageOverview),
+    NotFound,
+    TooLarge(String),
+    FailedToImport,
+    FailedToRead,
+}
+
+impl IntoResponse for AdminResponse {
+    fn into_response(self) -> Response {
+        match self {
+            AdminResponse::Imported(s) => (StatusCode::OK, Json(s)).into_response(),
+            AdminResponse::Audit(t) => (StatusCode::OK, Json(t)).into_response(),
+            AdminResponse::Verified(bad) => (StatusCode::OK, Json(bad)).into_response(),
+            AdminResponse::Stats(o) => (StatusCode::OK, Json(o)).into_response(),
+            AdminResponse::NotFound => StatusCode::NOT_FOUND.into_response(),
+            AdminResponse::TooLarge(message) => {
+                (StatusCode::PAYLOAD_TOO_LARGE, message).into_response()
+            }
+            AdminResponse::FailedToImport => StatusCode::BAD_REQUEST.into_response(),
+            AdminResponse::FailedToRead => StatusCode::BAD_REQUEST.into_response(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::storage_manager::tests::storage;
+    use axum::body::Body;
+    use axum::extract::DefaultBodyLimit;
+    use axum::http::Request;
+    use axum::Router;
+    use tower::ServiceExt;
+
+    /// A log of `count` blob adds, as a parent would export it.
+    async fn parent_log(count: usize) -> String {
+        let (_dir, parent) = storage().await;
+
+        for key in 0..count {
+            parent
+                .bytes_add(key.to_string(), b"data", "parent@example.com")
+                .await
+                .unwrap();
+        }
+
+        parent
+            .transactions()
+            .await
+            .unwrap()
+            .iter()
+            .map(|t| serde_json::to_string(t).unwrap())
+            .collect::<Vec<_>>()
+            .join("\n")
+    }
+
+    #[tokio::test]
+    async fn imports_over_the_item_limit_are_rejected() {
+        let (_dir, mut storage_manager) = storage().await;
+        storage_manager.batch_limits.max_items = 2;
+        let storage_manager = Arc::new(storage_manager);
+
+        let response = import(Extension(storage_manager.clone()), parent_log(3).await)
+            .await
+            .into_response();
+
+        assert_eq!(response.status(), StatusCode::PAYLOAD_TOO_LARGE);
+        assert!(storage_manager.transactions().await.unwrap().is_empty());
+    }
+
+    #[tokio::test]
+    async fn imports_within_the_limits_are_applied() {
+        let (_dir, mut storage_manager) = storage().await;
+        storage_manager.batch_limits.max_items = 2;
+        let storage_manager = Arc::new(storage_manager);
+
+        let response = import(Extension(storage_manager.clone()), parent_log(2).await)
+            .await
+            .into_response();
+
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(storage_manager.transactions().await.unwrap().len(), 2);
+    }
+
+    #[tokio::test]
+    async fn import_bodies_over_the_byte_limit_are_rejected() {
+        let (_dir, mut storage_manager) = storage().await;
+        let log = parent_log(2).await;
+        storage_manager.batch_limits.max_bytes = log.len() - 1;
+        let storage_manager = Arc::new(storage_manager);
+        let app = Router::new()
+            .route(
+                "/import",
+                axum::routing::post(import).layer(DefaultBodyLimit::max(
+                    storage_manager.batch_limits.max_bytes,
+                )),
+            )
+            .layer(Extension(storage_manager.clone()));
+
+        let response = app
+            .oneshot(Request::post("/import").body(Body::from(log)).unwrap())
+            .await
+            .unwrap();
+
+        assert_eq!(response.status(), StatusCode::PAYLOAD_TOO_LARGE);
+        assert!(storage_manager.transactions().await.unwrap().is_empty());
+    }
+}
@@ -1,29 +1,30 @@
-use axum::extract::{FromRequestParts, Path, Query};
+use axum::extract::{FromRequestParts, MatchedPath, Path, Query};
 use axum::http::request::Parts;
-use axum::http::{header, HeaderValue, StatusCode};
+use axum::http::{header, HeaderMap, HeaderValue, Method, StatusCode};
 use axum::response::{IntoResponse, Redirect, Response};
-use axum::{async_trait, Extension};
+use axum::{async_trait, Extension, Json};
 use axum_extra::extract::cookie::Cookie;
 use axum_extra::extract::CookieJar;
-use chrono::format::Numeric::Timestamp;
-use chrono::Utc;
 use jwt_simple::algorithms::{ECDSAP256KeyPairLike, ECDSAP256PublicKeyLike, ES256KeyPair};
-use jwt_simple::claims::{Claims, JWTClaims, NoCustomClaims};
+use jwt_simple::claims::{Claims, JWTClaims};
 use jwt_simple::common::VerificationOptions;
-use jwt_simple::prelude::{Deserialize, Duration, Serialize, UnixTimeStamp};
+use jwt_simple::prelude::{Deserialize, Serialize};
 use oauth2::basic::BasicClient;
 use oauth2::reqwest::async_http_client;
 use oauth2::{
-    AuthUrl, AuthorizationCode, ClientId, ClientSecret, CsrfToken, PkceCodeChallenge,
-    PkceCodeVerifier, RedirectUrl, Scope, TokenResponse, TokenUrl,
+    AccessToken, AuthUrl, AuthorizationCode, ClientId, ClientSecret, CsrfToken, PkceCodeChallenge,
+    PkceCodeVerifier, RedirectUrl, RefreshToken, Scope, TokenResponse, TokenUrl,
 };
 use sha256::Sha256Digest;
 use std::collections::HashMap;
+use std::sync::atomic::{AtomicBool, AtomicI64, Ordering};
 use std::sync::Arc;
-use std::time::{SystemTime, UNIX_EPOCH};
-use tokio::sync::RwLock;
+use tokio::fs::OpenOptions;
+use tokio::io::AsyncWriteExt;
+use tokio::sync::{Mutex, RwLock};
 use totp_rs::{Algorithm, TotpUrlError, TOTP};
 use tracing::{error, info, instrument, warn};
+use uuid::Uuid;
 
 /// Auth flow || https://developers.google.com/identity/openid-connect/openid-connect
 ///
@@ -78,15 +79,176 @@ pub struct GoogleUser {
     pub email: String,
     pub verified_email: bool,
     pub picture: String,
-    pub hd: String,
+    /// The Workspace domain. Personal Google accounts have none.
+    #[serde(default)]
+    pub hd: Option<String>,
+}
+
+/// Unix time, in seconds, at which the caller's validated token expires.
+/// Set on the request by the `GoogleUser` extractor.
+#[derive(Debug, Clone, Copy)]
+pub struct TokenExpiry(pub Option<i64>);
+
+#[derive(Serialize, Debug)]
+pub struct Me {
+    #[serde(flatten)]
+    pub user: GoogleUser,
+    pub expires_at: Option<i64>,
+    pub expires_in: Option<i64>,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(default)]
+pub struct SessionStoreConfig {
+    /// File the login state is saved to. Kept in memory only when unset.
+    pub path: Option<String>,
+    pub pkce_ttl_secs: i64,
+    pub jwt_cache_ttl_secs: i64,
+    /// Seconds between sweeps for expired entries.
+    pub sweep_interval_secs: u64,
+    /// Most logins waiting on Google at once. The oldest are dropped past this.
+    pub max_pending_logins: usize,
+    /// Least seconds between saves triggered by starting a login. Anything
+    /// skipped is saved by the next sweep.
+    pub login_save_interval_secs: i64,
+}
+
+impl Default for SessionStoreConfig {
+    fn default() -> Self {
+        Self {
+            path: None,
+            pkce_ttl_secs: 10 * 60,
+            jwt_cache_ttl_secs: 7 * 24 * 60 * 60,
+            sweep_interval_secs: 60,
+            max_pending_logins: 1000,
+            login_save_interval_secs: 5,
+        }
+    }
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(default)]
+pub struct TotpConfig {
+    /// Periods either side of now a code is still accepted in, to absorb
+    /// tablet clock drift. The default of 2 takes codes up to a minute off at
+    /// 30 second periods, which covers tablets that have gone a day without
+    /// network time and the time spent reading a code off one device into
+    /// another. That makes 5 of the million codes valid at once, and with the
+    /// default lockout of 5 failures a guesser gets in at most 25 in a
+    /// million times per lockout.
+    pub skew: u8,
+    /// Seconds each code is valid for. Zero is rejected when loading.
+    #[serde(deserialize_with = "nonzero_period")]
+    pub period_secs: u64,
+}
+
+impl Default for TotpConfig {
+    fn default() -> Self {
+        Self {
+            skew: 2,
+            period_secs: 30,
+        }
+    }
+}
+
+/// TOTP steps are counted by dividing by the period, so zero would panic on
+/// the first code.
+fn nonzero_period<'de, D: serde::Deserializer<'de>>(deserializer: D) -> Result<u64, D::Error> {
+    match u64::deserialize(deserializer)? {
+        0 => Err(serde::de::Error::custom(
+            "period_secs must be greater than 0",
+        )),
+        period => Ok(period),
+    }
+}
+
+/// Limits on guessing auth codes for one email.
+#[derive(Debug, Deserialize)]
+#[serde(default)]
+pub struct CodeLimitConfig {
+    /// Failed codes allowed within `window_secs` before locking out.
+    pub max_failures: u32,
+    pub window_secs: i64,
+    pub lockout_secs: i64,
+}
+
+impl Default for CodeLimitConfig {
+    fn default() -> Self {
+        Self {
+            max_failures: 5,
+            window_secs: 5 * 60,
+            lockout_secs: 15 * 60,
+        }
+    }
+}
+
+#[derive(Debug)]
+struct CodeAttempts {
+    failures: u32,
+    window_start: i64,
+    locked_until: Option<i64>,
+}
+
+#[derive(Debug)]
+enum CodeExchangeError {
+    Rejected(String),
+    /// Seconds until the email may try again.
+    LockedOut(i64),
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+struct Stamped {
+    value: String,
+    created_at: i64,
+}
+
+impl Stamped {
+    fn new(value: String) -> Self {
+        Self {
+            value,
+            created_at: chrono::Utc::now().timestamp(),
+        }
+    }
+
+    fn expired(&self, ttl_secs: i64) -> bool {
+        chrono::Utc::now().timestamp() - self.created_at >= ttl_secs
+    }
+}
+
+#[derive(Serialize, Deserialize, Default)]
+struct SessionState {
+    code_pairs: HashMap<String, Stamped>,
+    jwt_cache: HashMap<String, Stamped>,
+    #[serde(default)]
+    refresh_tokens: HashMap<String, Stamped>,
 }
 
 #[derive(Default, Deserialize)]
 pub struct GoogleAuthenticator {
     #[serde(skip)]
-    code_pairs: RwLock<HashMap<String, String>>,
+    code_pairs: RwLock<HashMap<String, Stamped>>,
+    #[serde(skip)]
+    jwt_cache: RwLock<HashMap<String, Stamped>>,
+    /// Google refresh tokens by lowercased email. They do not expire here.
+    #[serde(skip)]
+    refresh_tokens: RwLock<HashMap<String, Stamped>>,
+    #[serde(default)]
+    session_store: SessionStoreConfig,
+    #[serde(default)]
+    totp: TotpConfig,
+    #[serde(default)]
+    code_limit: CodeLimitConfig,
     #[serde(skip)]
-    jwt_cache: RwLock<HashMap<String, String>>,
+    code_attempts: RwLock<HashMap<String, CodeAttempts>>,
+    /// Held while the login state is written, so saves land one at a time.
+    #[serde(skip)]
+    save_lock: Mutex<()>,
+    /// Unix seconds of the last save.
+    #[serde(skip)]
+    last_saved: AtomicI64,
+    /// Set when a change was left for the next sweep to save.
+    #[serde(skip)]
+    unsaved: AtomicBool,
     client_id: String,
     client_secret: String,
     auth_uri: String,
@@ -106,27 +268,206 @@ impl GoogleAuthenticator {
         .set_redirect_uri(RedirectUrl::new(self.redirect_uri.clone()).unwrap())
     }
 
+    fn totp(&self, email: &str) -> Result<TOTP, TotpUrlError> {
+        totp_from_str(email, self.totp.skew, self.totp.period_secs)
+    }
+
     #[instrument(skip(self))]
     pub async fn generate_google_auth_code(&self, email: String) -> String {
-        totp_from_str(&email).unwrap().generate_current().unwrap()
+        self.totp(&email).unwrap().generate_current().unwrap()
+    }
+
+    /// Restores login state saved by a previous run, dropping expired entries.
+    #[instrument(skip(self))]
+    pub async fn load_state(&self) -> Result<(), anyhow::Error> {
+        let path = match &self.session_store.path {
+            Some(path) => path,
+            None => return Ok(()),
+        };
+
+        let state: SessionState = match tokio::fs::read(path).await {
+            Ok(bytes) => serde_json::from_slice(&bytes)?,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(()),
+            Err(e) => return Err(e.into()),
+        };
+
+        let mut code_pairs = self.code_pairs.write().await;
+        let mut jwt_cache = self.jwt_cache.write().await;
+
+        *self.refresh_tokens.write().await = state.refresh_tokens;
+        *code_pairs = state.code_pairs;
+        code_pairs.retain(|_, v| !v.expired(self.session_store.pkce_ttl_secs));
+        *jwt_cache = state.jwt_cache;
+        jwt_cache.retain(|_, v| !v.expired(self.session_store.jwt_cache_ttl_secs));
+
+        Ok(())
+    }
+
+    pub fn sweep_interval(&self) -> std::time::Duration {
+        std::time::Duration::from_secs(self.session_store.sweep_interval_secs)
+    }
+
+    /// Drops abandoned logins and stale jwt caches, returning how many went.
+    /// Code attempt counters past their window and lockout are cleared too.
+    #[instrument(skip(self))]
+    pub async fn sweep_expired(&self) -> usize {
+        let now = chrono::Utc::now().timestamp();
+        self.code_attempts.write().await.retain(|_, a| {
+            a.locked_until.is_some_and(|until| until > now)
+                || now - a.window_start <= self.code_limit.window_secs
+        });
+
+        let removed = {
+            let mut code_pairs = self.code_pairs.write().await;
+            let mut jwt_cache = self.jwt_cache.write().await;
+            let before = code_pairs.len() + jwt_cache.len();
+
+            code_pairs.retain(|_, v| !v.expired(self.session_store.pkce_ttl_secs));
+            jwt_cache.retain(|_, v| !v.expired(self.session_store.jwt_cache_ttl_secs));
+
+            before - code_pairs.len() - jwt_cache.len()
+        };
+
+        if removed > 0 || self.unsaved.load(Ordering::Relaxed) {
+            self.save_state().await;
+        }
+
+        removed
+    }
+
+    /// Writes the login state, which holds live tokens, readable by the
+    /// server's user only.
+    async fn save_state(&self) {
+        let path = match &self.session_store.path {
+            Some(path) => path,
+            None => return,
+        };
+
+        // snapshot under the lock so a later save never writes older state
+        let _saving = self.save_lock.lock().await;
+        self.unsaved.store(false, Ordering::Relaxed);
+        self.last_saved
+            .store(chrono::Utc::now().timestamp(), Ordering::Relaxed);
+
+        let state = SessionState {
+            code_pairs: self.code_pairs.read().await.clone(),
+            jwt_cache: self.jwt_cache.read().await.clone(),
+            refresh_tokens: self.refresh_tokens.read().await.clone(),
+        };
+
+        let result = async {
+            let tmp = format!("{path}.{}.tmp", Uuid::new_v4());
+            let mut file = OpenOptions::new()
+                .write(true)
+                .create_new(true)
+                .mode(0o600)
+                .open(&tmp)
+                .await?;
+            file.write_all(&serde_json::to_vec(&state)?).await?;
+            file.sync_all().await?;
+            tokio::fs::rename(&tmp, path).await?;
+            Ok::<(), anyhow::Error>(())
+        }
+        .await;
+
+        if let Err(e) = result {
+            warn!("could not save login state: {e}");
+        }
     }
 
     async fn set_jwt_cache(&self, email: String, cookie: String) {
-        self.jwt_cache.write().await.insert(email, cookie);
+        let changed = {
+            let mut jwt_cache = self.jwt_cache.write().await;
+
+            match jwt_cache.get(&email) {
+                Some(existing)
+                    if existing.value == cookie
+                        && !existing.expired(self.session_store.jwt_cache_ttl_secs) =>
+                {
+                    false
+                }
+                _ => {
+                    jwt_cache.insert(email, Stamped::new(cookie));
+                    true
+                }
+            }
+        };
+
+        if changed {
+            self.save_state().await;
+        }
     }
 
     #[instrument(skip(self))]
-    async fn get_jwt_from_code(&self, code: String, email: String) -> Result<String, String> {
-        match totp_from_str(&email)
-            .unwrap()
-            .check_current(code.trim())
-            .unwrap()
+    async fn get_jwt_from_code(
+        &self,
+        code: String,
+        email: String,
+    ) -> Result<String, CodeExchangeError> {
+        let now = chrono::Utc::now().timestamp();
+        let attempts_key = email.to_lowercase();
+
         {
-            true => match self.jwt_cache.read().await.get(&email) {
-                None => Err("User does not have jwt cache, please re-sign in with oauth".into()),
-                Some(cookies) => Ok(cookies.clone()),
-            },
-            false => Err("Invalid authentication code".into()),
+            // held from the lockout check until the outcome is counted, so
+            // concurrent guesses cannot all get in before the first failure lands
+            let mut code_attempts = self.code_attempts.write().await;
+
+            if let Some(locked_until) = code_attempts
+                .get(&attempts_key)
+                .and_then(|a| a.locked_until)
+                .filter(|until| *until > now)
+            {
+                return Err(CodeExchangeError::LockedOut(locked_until - now));
+            }
+
+            if !self.totp(&email).unwrap().check(code.trim(), now as u64) {
+                self.record_code_failure(&mut code_attempts, attempts_key, now);
+                return Err(CodeExchangeError::Rejected(
+                    "Invalid authentication code".into(),
+                ));
+            }
+
+            code_attempts.remove(&attempts_key);
+        }
+
+        match self.jwt_cache.read().await.get(&email) {
+            Some(cookies) if !cookies.expired(self.session_store.jwt_cache_ttl_secs) => {
+                Ok(cookies.value.clone())
+            }
+            _ => Err(CodeExchangeError::Rejected(
+                "User does not have jwt cache, please re-sign in with oauth".into(),
+            )),
+        }
+    }
+
+    fn record_code_failure(
+        &self,
+        code_attempts: &mut HashMap<String, CodeAttempts>,
+        key: String,
+        now: i64,
+    ) {
+        let attempts = code_attempts.entry(key).or_insert(CodeAttempts {
+            failures: 0,
+            window_start: now,
+            locked_until: None,
+        });
+
+        if now - attempts.window_start > self.code_limit.window_secs {
+            *attempts = CodeAttempts {
+                failures: 0,
+                window_start: now,
+                locked_until: None,
+            };
+        }
+
+        attempts.failures += 1;
+
+        if attempts.failures >= self.code_limit.max_failures {
+            warn!(
+                "locking out code exchange after {} failures",
+                attempts.failures
+            );
+            attempts.locked_until = Some(now + self.code_limit.lockout_secs);
         }
     }
 
@@ -138,31 +479,62 @@ impl GoogleAuthenticator {
 
         info!("{:?}", auth_response);
         let state = CsrfToken::new(auth_response.state);
-        if let Some(session_id) = self.code_pairs.read().await.get(state.secret()) {
-            let verifier = PkceCodeVerifier::new(session_id.into());
+        let session_id = self.code_pairs.write().await.remove(state.secret());
+
+        if session_id.is_some() {
+            self.save_state().await;
+        }
+
+        if let Some(session_id) = session_id
+            .filter(|pair| !pair.expired(self.session_store.pkce_ttl_secs))
+            .map(|pair| pair.value)
+        {
+            let verifier = PkceCodeVerifier::new(session_id);
             let token_response = client
                 .exchange_code(AuthorizationCode::new(auth_response.code))
                 .set_pkce_verifier(verifier)
                 .request_async(async_http_client)
                 .await
-                .expect("Failed to get token from google");
-            let access_token = token_response.access_token();
-            let user = reqwest::Client::new()
-                .get("https://www.googleapis.com/oauth2/v2/userinfo?email")
-                .bearer_auth(access_token.secret())
-                .send()
-                .await
-                .expect("Failed to request profile data")
-                .json::<GoogleUser>()
-                .await
-                .expect("Failed to deserialize profile data");
+                .map_err(|e| format!("Failed to get token from google: {e}"))?;
+            let user = fetch_user(token_response.access_token()).await?;
             info!("Body: {:?}", user);
+
+            match token_response.refresh_token() {
+                Some(refresh_token) => {
+                    self.refresh_tokens.write().await.insert(
+                        user.email.to_lowercase().trim().into(),
+                        Stamped::new(refresh_token.secret().clone()),
+                    );
+                    self.save_state().await;
+                }
+                None => info!("google did not issue a refresh token"),
+            }
+
             Ok(user)
         } else {
             Err("bad login".into())
         }
     }
 
+    /// Trades a stored refresh token for fresh profile data, so a new app
+    /// token can be minted without sending the user back through Google.
+    #[instrument(skip(self))]
+    async fn refresh_user(&self, email: &str) -> Result<GoogleUser, String> {
+        let refresh_token = match self.refresh_tokens.read().await.get(email) {
+            Some(token) => RefreshToken::new(token.value.clone()),
+            None => return Err("No refresh token for user, please sign in again".into()),
+        };
+
+        let token_response = self
+            .get_client()
+            .exchange_refresh_token(&refresh_token)
+            .request_async(async_http_client)
+            .await
+            .map_err(|e| format!("Failed to refresh token with google: {e}"))?;
+
+        fetch_user(token_response.access_token()).await
+    }
+
     async fn send_to_login(&self) -> String {
         let (pkce_challenge, pkce_verifier) = PkceCodeChallenge::new_random_sha256();
         let client = self.get_client();
@@ -173,27 +545,71 @@ impl GoogleAuthenticator {
             // Set the desired scopes.
             .add_scope(Scope::new("openid".to_string()))
             .add_scope(Scope::new("email".to_string()))
+            // Ask for a refresh token so sessions can be extended later.
+            .add_extra_param("access_type", "offline")
             // Set the PKCE code challenge.
             .set_pkce_challenge(pkce_challenge)
             .url();
 
-        self.code_pairs
-            .write()
-            .await
-            .insert(csrf_token.secret().clone(), pkce_verifier.secret().clone());
+        {
+            let mut code_pairs = self.code_pairs.write().await;
+
+            if code_pairs.len() >= self.session_store.max_pending_logins {
+                code_pairs.retain(|_, v| !v.expired(self.session_store.pkce_ttl_secs));
+            }
+
+            while code_pairs.len() >= self.session_store.max_pending_logins.max(1) {
+                let oldest = code_pairs
+                    .iter()
+                    .min_by_key(|(_, v)| v.created_at)
+                    .map(|(k, _)| k.clone());
+
+                match oldest {
+                    Some(oldest) => code_pairs.remove(&oldest),
+                    None => break,
+                };
+            }
+
+            code_pairs.insert(
+                csrf_token.secret().clone(),
+                Stamped::new(pkce_verifier.secret().clone()),
+            );
+        }
+
+        // anyone can start a login, so they must not be able to force a write each time
+        let since_save = chrono::Utc::now().timestamp() - self.last_saved.load(Ordering::Relaxed);
+
+        if since_save >= self.session_store.login_save_interval_secs {
+            self.save_state().await;
+        } else {
+            self.unsaved.store(true, Ordering::Relaxed);
+        }
+
         auth_url.to_string()
     }
 }
 
+async fn fetch_user(access_token: &AccessToken) -> Result<GoogleUser, String> {
+    reqwest::Client::new()
+        .get("https://www.googleapis.com/oauth2/v2/userinfo?email")
+        .bearer_auth(access_token.secret())
+        .send()
+        .await
+        .map_err(|e| format!("Failed to request profile data: {e}"))?
+        .json::<GoogleUser>()
+        .await
+        .map_err(|e| format!("Failed to deserialize profile data: {e}"))
+}
+
 #[instrument]
-fn totp_from_str(string: &str) -> Result<TOTP, TotpUrlError> {
+fn totp_from_str(string: &str, skew: u8, period: u64) -> Result<TOTP, TotpUrlError> {
     let string = string.digest();
 
     TOTP::new(
         Algorithm::SHA1,
         6,
-        1,
-        30,
+        skew,
+        period,
         string.into_bytes()[0..16].to_vec(),
     )
 }
@@ -203,6 +619,16 @@ pub struct JwtManagerBuilder {
     key_path: String,
     duration: u64,
     accepted_domains: Vec<String>,
+    /// Let in accounts with no hosted domain, such as personal Gmail.
+    #[serde(default)]
+    allow_personal_accounts: bool,
+    /// How long after expiry, in minutes, a token can still be refreshed.
+    #[serde(default = "default_refresh_grace")]
+    refresh_grace: u64,
+}
+
+fn default_refresh_grace() -> u64 {
+    7 * 24 * 60
 }
 
 impl JwtManagerBuilder {
@@ -211,7 +637,13 @@ impl JwtManagerBuilder {
             key_pair: ES256KeyPair::from_pem(&std::fs::read_to_string(&self.key_path).unwrap())
                 .unwrap(),
             duration: self.duration,
-            accepted_domains: self.accepted_domains,
+            accepted_domains: self
+                .accepted_domains
+                .iter()
+                .map(|d| d.trim().to_lowercase())
+                .collect(),
+            allow_personal_accounts: self.allow_personal_accounts,
+            refresh_grace: self.refresh_grace,
         }
     }
 }
@@ -220,6 +652,8 @@ pub struct JwtManager {
     key_pair: ES256KeyPair,
     duration: u64,
     accepted_domains: Vec<String>,
+    allow_personal_accounts: bool,
+    refresh_grace: u64,
 }
 
 impl JwtManager {
@@ -233,11 +667,42 @@ impl JwtManager {
         self.key_pair.sign(token).unwrap()
     }
 
-    #[instrument(skip(self, jwt))]
+    /// Matches a hosted domain against the accepted list, ignoring case. An
+    /// entry like `*.team5907.org` accepts any subdomain of `team5907.org`.
+    fn domain_accepted(&self, hd: &str) -> bool {
+        let hd = hd.trim().to_lowercase();
+
+        self.accepted_domains
+            .iter()
+            .any(|accepted| match accepted.strip_prefix("*.") {
+                Some(parent) => hd
+                    .strip_suffix(parent)
+                    .is_some_and(|sub| sub.len() > 1 && sub.ends_with('.')),
+                None => *accepted == hd,
+            })
+    }
+
     fn validate_jwt(&self, jwt: &str) -> Result<JWTClaims<GoogleUser>, String> {
+        self.validate_jwt_with_tolerance(jwt, jwt_simple::prelude::Duration::from_secs(1))
+    }
+
+    /// Validates a token that may have expired within the refresh grace period.
+    fn validate_refreshable_jwt(&self, jwt: &str) -> Result<JWTClaims<GoogleUser>, String> {
+        self.validate_jwt_with_tolerance(
+            jwt,
+            jwt_simple::prelude::Duration::from_mins(self.refresh_grace),
+        )
+    }
+
+    #[instrument(skip(self, jwt))]
+    fn validate_jwt_with_tolerance(
+        &self,
+        jwt: &str,
+        time_tolerance: jwt_simple::prelude::Duration,
+    ) -> Result<JWTClaims<GoogleUser>, String> {
         let verification_options = VerificationOptions {
             accept_future: false,
-            time_tolerance: Some(jwt_simple::prelude::Duration::from_secs(1)),
+            time_tolerance: Some(time_tolerance),
             ..Default::default()
         };
         match self
@@ -246,7 +711,12 @@ impl JwtManager {
             .verify_token::<GoogleUser>(jwt, Some(verification_options))
         {
             Ok(claims) => {
-                if self.accepted_domains.contains(&claims.custom.hd) {
+                let accepted = match claims.custom.hd.as_deref() {
+                    Some(hd) if !hd.trim().is_empty() => self.domain_accepted(hd),
+                    _ => self.allow_personal_accounts,
+                };
+
+                if accepted {
                     Ok(claims)
                 } else {
                     warn!("Oauth domain not accepted");
@@ -261,6 +731,24 @@ impl JwtManager {
     }
 }
 
+/// The app token from the `authorization` header, either `Bearer <jwt>` or
+/// the older `jwt=<jwt>`, falling back to the `jwt` cookie.
+fn jwt_from_headers(headers: &HeaderMap) -> Option<String> {
+    let jwt_header: Option<String> = headers
+        .get(header::AUTHORIZATION)
+        .and_then(|h| h.to_str().ok())
+        .map(|h| h.trim())
+        .map(|h| match h.get(..7) {
+            Some(scheme) if scheme.eq_ignore_ascii_case("bearer ") => h[7..].trim(),
+            _ => h.strip_prefix("jwt=").unwrap_or(h),
+        })
+        .filter(|h| !h.is_empty())
+        .map(String::from);
+
+    let jar = CookieJar::from_headers(headers);
+    jwt_header.or_else(|| jar.get("jwt").map(|f| f.value().to_string()))
+}
+
 #[async_trait]
 impl<S> FromRequestParts<S> for GoogleUser
 where
@@ -271,18 +759,7 @@ where
     #[instrument(skip(parts, _state))]
     async fn from_request_parts(parts: &mut Parts, _state: &S) -> Result<Self, Self::Rejection> {
         info!("in user extraction");
-        let jwt_header: Option<String> = parts
-            .headers
-            .get("authorization")
-            .and_then(|h| h.to_str().ok())
-            .and_then(|h| match h.is_empty() {
-                true => None,
-                false => Some(h),
-            })
-            .map(|s| s.replace("jwt=", ""));
-
-        let jar = CookieJar::from_headers(&parts.headers);
-        if let Some(jwt) = jar.get("jwt").map(|f| f.value().to_string()).or(jwt_header) {
+        if let Some(jwt) = jwt_from_headers(&parts.headers) {
             info!("got jwt token");
             let jwt_manager = parts
                 .extensions
@@ -291,6 +768,10 @@ where
             match jwt_manager.validate_jwt(&jwt) {
                 Ok(token) => {
                     info!("jwt accepted");
+                    parts
+                        .extensions
+                        .insert(TokenExpiry(token.expires_at.map(|t| t.as_secs() as i64)));
+
                     let google_authenticator = parts
                         .extensions
                         .get::<Arc<GoogleAuthenticator>>()
@@ -304,31 +785,110 @@ where
                 }
                 Err(error) => {
                     warn!("{:?}", error);
-                    let google_authenticator = parts
-                        .extensions
-                        .get::<Arc<GoogleAuthenticator>>()
-                        .expect("No google authenticator set up");
-
-                    let auth_url = google_authenticator.send_to_login().await;
 
-                    Err(Redirect::to(&auth_url).into_response())
+                    Err(reject(parts, error).await)
                 }
             }
         } else {
-            let google_authenticator = parts
+            warn!("no jwt found!");
+
+            Err(reject(parts, "No token provided".into()).await)
+        }
+    }
+}
+
+/// Who may change data. Reads are open to every signed-in user.
+#[derive(Debug, Default, Deserialize)]
+#[serde(default)]
+pub struct Roles {
+    /// Emails allowed to write. When empty every signed-in user may write.
+    /// Sync children need to be listed to push blobs.
+    pub writers: Vec<String>,
+}
+
+impl Roles {
+    pub fn can_write(&self, email: &str) -> bool {
+        self.writers.is_empty()
+            || self
+                .writers
+                .iter()
+                .any(|writer| writer.eq_ignore_ascii_case(email))
+    }
+}
+
+/// Routes that take a body over POST but only read.
+const READ_ONLY_POSTS: [&str; 2] = [
+    "/protected/query/:template",
+    "/protected/forms/:template/coverage",
+];
+
+/// Lets reads through and refuses writes from users `Roles` does not allow.
+#[derive(Debug)]
+pub struct WriteAccess;
+
+#[async_trait]
+impl<S> FromRequestParts<S> for WriteAccess
+where
+    S: Send + Sync + std::fmt::Debug,
+{
+    type Rejection = Response;
+
+    #[instrument(skip(parts, state))]
+    async fn from_request_parts(parts: &mut Parts, state: &S) -> Result<Self, Self::Rejection> {
+        let read = matches!(parts.method, Method::GET | Method::HEAD | Method::OPTIONS)
+            || parts
                 .extensions
-                .get::<Arc<GoogleAuthenticator>>()
-                .expect("No google authenticator set up");
+                .get::<MatchedPath>()
+                .is_some_and(|path| READ_ONLY_POSTS.contains(&path.as_str()));
 
-            warn!("no jwt found!");
+        if read {
+            return Ok(WriteAccess);
+        }
+
+        let user = GoogleUser::from_request_parts(parts, state).await?;
+        let roles = parts
+            .extensions
+            .get::<Arc<Roles>>()
+            .expect("No roles set up");
 
-            let auth_url = google_authenticator.send_to_login().await;
+        if roles.can_write(&user.email) {
+            Ok(WriteAccess)
+        } else {
+            warn!("{} may not write", user.email);
 
-            Err(Redirect::to(&auth_url).into_response())
+            Err(StatusCode::FORBIDDEN.into_response())
         }
     }
 }
 
+/// Browsers are sent to Google to sign in. Anything else, such as API clients
+/// and sync peers, gets a 401 it can act on.
+async fn reject(parts: &Parts, error: String) -> Response {
+    let wants_html = !parts.headers.contains_key(header::AUTHORIZATION)
+        && parts
+            .headers
+            .get(header::ACCEPT)
+            .and_then(|h| h.to_str().ok())
+            .is_some_and(|h| h.contains("text/html"));
+
+    if !wants_html {
+        return (
+            StatusCode::UNAUTHORIZED,
+            Json(serde_json::json!({ "error": error })),
+        )
+            .into_response();
+    }
+
+    let google_authenticator = parts
+        .extensions
+        .get::<Arc<GoogleAuthenticator>>()
+        .expect("No google authenticator set up");
+
+    let auth_url = google_authenticator.send_to_login().await;
+
+    Redirect::to(&auth_url).into_response()
+}
+
 #[instrument(ret, skip(google_authenticator))]
 pub async fn get_jwt_cache_from_code(
     Path((email, code)): Path<(String, String)>,
@@ -348,7 +908,16 @@ pub async fn get_jwt_cache_from_code(
 
             resp
         }
-        Err(msg) => {
+        Err(CodeExchangeError::LockedOut(retry_after)) => {
+            warn!("code exchange locked out for {retry_after}s");
+
+            (
+                StatusCode::TOO_MANY_REQUESTS,
+                [(header::RETRY_AFTER, retry_after.to_string())],
+            )
+                .into_response()
+        }
+        Err(CodeExchangeError::Rejected(msg)) => {
             let mut resp = Redirect::to("/protected/code").into_response();
 
             error!("[{}]!, deleting cookies and redirecting to login", msg);
@@ -364,6 +933,17 @@ pub async fn get_jwt_cache_from_code(
     }
 }
 
+#[instrument(ret)]
+pub async fn me(user: GoogleUser, Extension(expiry): Extension<TokenExpiry>) -> Json<Me> {
+    let now = chrono::Utc::now().timestamp();
+
+    Json(Me {
+        user,
+        expires_at: expiry.0,
+        expires_in: expiry.0.map(|t| (t - now).max(0)),
+    })
+}
+
 #[instrument(ret, skip(google_authenticator))]
 pub async fn auth_code(
     user: GoogleUser,
@@ -377,15 +957,30 @@ pub async fn auth_code(
 #[instrument(ret, skip(jwt_manager, google_authenticator, auth_response))]
 pub async fn login_handler(
     auth_response: Option<Query<AuthResponse>>,
+    Query(login_error): Query<LoginError>,
     google_authenticator: Extension<Arc<GoogleAuthenticator>>,
     jwt_manager: Extension<Arc<JwtManager>>,
 ) -> impl IntoResponse {
+    if let Some(error) = login_error.error {
+        return (StatusCode::UNAUTHORIZED, format!("Login failed: {error}")).into_response();
+    }
+
     let mut login_result = Redirect::to("/protected/code").into_response();
     if let Some(auth_response) = auth_response {
-        let user = google_authenticator
+        let user = match google_authenticator
             .exchange_code_for_user(auth_response.0)
             .await
-            .expect("Could not validate token with google");
+        {
+            Ok(user) => user,
+            Err(error) => {
+                error!("Could not validate token with google: {error}");
+
+                let error: String =
+                    oauth2::url::form_urlencoded::byte_serialize(error.as_bytes()).collect();
+
+                return Redirect::to(&format!("/?error={error}")).into_response();
+            }
+        };
         let email = String::clone(&user.email).to_lowercase();
         let email = email.trim();
         let token = jwt_manager.create_token_for_user(user);
@@ -403,8 +998,387 @@ pub async fn login_handler(
     login_result.into_response()
 }
 
+/// Mints a new app token for a caller whose token is expired or about to be,
+/// using the refresh token Google issued at login.
+#[instrument(skip(headers, jwt_manager, google_authenticator))]
+pub async fn refresh(
+    headers: HeaderMap,
+    google_authenticator: Extension<Arc<GoogleAuthenticator>>,
+    jwt_manager: Extension<Arc<JwtManager>>,
+) -> Response {
+    let claims = match jwt_from_headers(&headers)
+        .ok_or_else(|| "No token to refresh".to_string())
+        .and_then(|jwt| jwt_manager.validate_refreshable_jwt(&jwt))
+    {
+        Ok(claims) => claims,
+        Err(error) => return (StatusCode::UNAUTHORIZED, error).into_response(),
+    };
+
+    let email = claims.custom.email.to_lowercase();
+    let email = email.trim();
+
+    let user = match google_authenticator.refresh_user(email).await {
+        Ok(user) => user,
+        Err(error) => {
+            warn!("{error}");
+            return (StatusCode::UNAUTHORIZED, error).into_response();
+        }
+    };
+
+    let token = jwt_manager.create_token_for_user(user);
+    let cookie = Cookie::new("jwt", token.clone());
+
+    google_authenticator
+        .set_jwt_cache(email.into(), cookie.to_string())
+        .await;
+
+    let mut resp = (StatusCode::OK, token).into_response();
+    resp.headers_mut().insert(
+        header::SET_COOKIE,
+        HeaderValue::from_str(&cookie.to_string()).unwrap(),
+    );
+
+    resp
+}
+
+#[derive(serde::Deserialize, Debug)]
+pub struct LoginError {
+    error: Option<String>,
+}
+
 #[derive(serde::Deserialize, Debug, Clone)]
 pub struct AuthResponse {
     state: String,
     code: String,
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::os::unix::fs::PermissionsExt;
+
+    fn authenticator(max_failures: u32) -> GoogleAuthenticator {
+        GoogleAuthenticator {
+            code_limit: CodeLimitConfig {
+                max_failures,
+                ..Default::default()
+            },
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn codes_are_accepted_within_the_configured_skew() {
+        let authenticator = GoogleAuthenticator::default();
+        let totp = authenticator.totp("scout@team5907.org").unwrap();
+        let period = authenticator.totp.period_secs;
+        let skew = authenticator.totp.skew as u64;
+        let now = 1_700_000_000 - 1_700_000_000 % period;
+
+        for step in 1..=skew {
+            assert!(totp.check(&totp.generate(now - step * period), now));
+            assert!(totp.check(&totp.generate(now + step * period), now));
+        }
+        assert!(!totp.check(&totp.generate(now - (skew + 1) * period), now));
+        assert!(!totp.check(&totp.generate(now + (skew + 1) * period), now));
+    }
+
+    #[test]
+    fn zero_totp_periods_are_rejected_at_load() {
+        let loaded = serde_json::from_value::<TotpConfig>(serde_json::json!({
+            "period_secs": 0,
+        }));
+
+        assert!(loaded.is_err());
+        assert_eq!(
+            serde_json::from_value::<TotpConfig>(serde_json::json!({ "skew": 3 }))
+                .unwrap()
+                .period_secs,
+            30
+        );
+    }
+
+    #[tokio::test]
+    async fn code_exchange_locks_out_after_failures() {
+        let authenticator = authenticator(3);
+        let email = "scout@team5907.org";
+        authenticator
+            .set_jwt_cache(email.into(), "jwt=token".into())
+            .await;
+
+        let guesses = futures::future::join_all(
+            (0..10).map(|_| authenticator.get_jwt_from_code("nope".into(), email.into())),
+        )
+        .await;
+        let rejected = guesses
+            .iter()
+            .filter(|r| matches!(r, Err(CodeExchangeError::Rejected(_))))
+            .count();
+        assert_eq!(rejected, 3);
+        assert!(guesses[3..]
+            .iter()
+            .all(|r| matches!(r, Err(CodeExchangeError::LockedOut(_)))));
+
+        let code = authenticator.generate_google_auth_code(email.into()).await;
+        assert!(matches!(
+            authenticator.get_jwt_from_code(code, email.into()).await,
+            Err(CodeExchangeError::LockedOut(_))
+        ));
+    }
+
+    #[tokio::test]
+    async fn code_exchange_success_resets_failures() {
+        let authenticator = authenticator(3);
+        let email = "scout@team5907.org";
+        authenticator
+            .set_jwt_cache(email.into(), "jwt=token".into())
+            .await;
+
+        for _ in 0..2 {
+            assert!(authenticator
+                .get_jwt_from_code("nope".into(), email.into())
+                .await
+                .is_err());
+        }
+
+        let code = authenticator.generate_google_auth_code(email.into()).await;
+        assert_eq!(
+            authenticator
+                .get_jwt_from_code(code, email.into())
+                .await
+                .unwrap(),
+            "jwt=token"
+        );
+        assert!(authenticator.code_attempts.read().await.is_empty());
+    }
+
+    #[tokio::test]
+    async fn saved_state_is_private_and_reloads() {
+        let dir = tempfile::TempDir::new().unwrap();
+        let path = dir.path().join("sessions.json");
+        let store = || SessionStoreConfig {
+            path: Some(path.to_string_lossy().into()),
+            ..Default::default()
+        };
+
+        let authenticator = GoogleAuthenticator {
+            session_store: store(),
+            ..Default::default()
+        };
+        futures::future::join_all((0..4).map(|i| {
+            authenticator.set_jwt_cache(format!("scout{i}@team5907.org"), format!("jwt={i}"))
+        }))
+        .await;
+
+        let mode = std::fs::metadata(&path).unwrap().permissions().mode();
+        assert_eq!(mode & 0o777, 0o600);
+        assert_eq!(std::fs::read_dir(dir.path()).unwrap().count(), 1);
+
+        let restored = GoogleAuthenticator {
+            session_store: store(),
+            ..Default::default()
+        };
+        restored.load_state().await.unwrap();
+        assert_eq!(restored.jwt_cache.read().await.len(), 4);
+    }
+
+    fn login_authenticator(session_store: SessionStoreConfig) -> GoogleAuthenticator {
+        GoogleAuthenticator {
+            session_store,
+            auth_uri: "https://accounts.google.com/o/oauth2/v2/auth".into(),
+            token_uri: "https://oauth2.googleapis.com/token".into(),
+            redirect_uri: "https://localhost/login".into(),
+            ..Default::default()
+        }
+    }
+
+    #[tokio::test]
+    async fn pending_logins_are_capped() {
+        let authenticator = login_authenticator(SessionStoreConfig {
+            max_pending_logins: 3,
+            ..Default::default()
+        });
+
+        for _ in 0..5 {
+            authenticator.send_to_login().await;
+        }
+
+        assert_eq!(authenticator.code_pairs.read().await.len(), 3);
+    }
+
+    #[tokio::test]
+    async fn login_saves_are_debounced_until_sweep() {
+        let dir = tempfile::TempDir::new().unwrap();
+        let path = dir.path().join("sessions.json");
+        let authenticator = login_authenticator(SessionStoreConfig {
+            path: Some(path.to_string_lossy().into()),
+            login_save_interval_secs: 60,
+            ..Default::default()
+        });
+        let saved_logins = || {
+            let state: SessionState =
+                serde_json::from_slice(&std::fs::read(&path).unwrap()).unwrap();
+            state.code_pairs.len()
+        };
+
+        for _ in 0..3 {
+            authenticator.send_to_login().await;
+        }
+        assert_eq!(saved_logins(), 1);
+
+        authenticator.sweep_expired().await;
+        assert_eq!(saved_logins(), 3);
+    }
+
+    #[test]
+    fn accepted_domains_fold_case_and_match_subdomains() {
+        let dir = tempfile::TempDir::new().unwrap();
+        let key_path = dir.path().join("key.pem");
+        std::fs::write(&key_path, ES256KeyPair::generate().to_pem().unwrap()).unwrap();
+        let manager: JwtManagerBuilder = serde_json::from_value(serde_json::json!({
+            "key_path": key_path,
+            "duration": 60,
+            "accepted_domains": [" Team5907.ORG ", "*.ShamBots.org"],
+        }))
+        .unwrap();
+        let manager = manager.build();
+
+        for (hd, accepted) in [
+            ("team5907.org", true),
+            ("TEAM5907.org", true),
+            ("scouts.team5907.org", false),
+            ("team5907.org.example.com", false),
+            ("pit.shambots.org", true),
+            ("A.Pit.SHAMBOTS.org", true),
+            ("shambots.org", false),
+            ("fakeshambots.org", false),
+        ] {
+            assert_eq!(manager.domain_accepted(hd), accepted, "{hd}");
+        }
+    }
+
+    #[tokio::test]
+    async fn read_only_users_may_only_read() {
+        use axum::body::Body;
+        use axum::http::Request;
+        use axum::middleware::from_extractor;
+        use axum::routing::{get, post};
+        use axum::Router;
+        use tower::ServiceExt;
+
+        let manager = Arc::new(JwtManager {
+            key_pair: ES256KeyPair::generate(),
+            duration: 60,
+            accepted_domains: vec!["team5907.org".into()],
+            allow_personal_accounts: false,
+            refresh_grace: 0,
+        });
+        let token = |email: &str| {
+            manager.create_token_for_user(GoogleUser {
+                id: "1".into(),
+                email: email.into(),
+                verified_email: true,
+                picture: String::new(),
+                hd: Some("team5907.org".into()),
+            })
+        };
+        let app = Router::new()
+            .route(
+                "/protected/forms/:template",
+                get(|| async {})
+                    .post(|| async {})
+                    .put(|| async {})
+                    .delete(|| async {}),
+            )
+            .route("/protected/query/:template", post(|| async {}))
+            .layer(from_extractor::<WriteAccess>())
+            .layer(Extension(manager.clone()))
+            .layer(Extension(Arc::new(GoogleAuthenticator::default())))
+            .layer(Extension(Arc::new(Roles {
+                writers: vec!["lead@team5907.org".into()],
+            })));
+        let status = |method: Method, path: &str, email: &str| {
+            let request = Request::builder()
+                .method(method)
+                .uri(path)
+                .header(header::AUTHORIZATION, format!("Bearer {}", token(email)))
+                .body(Body::empty())
+                .unwrap();
+
+            let app = app.clone();
+            async move { app.oneshot(request).await.unwrap().status() }
+        };
+
+        let reader = "scout@team5907.org";
+        for method in [Method::POST, Method::PUT, Method::DELETE] {
+            assert_eq!(
+                status(method.clone(), "/protected/forms/scores", reader).await,
+                StatusCode::FORBIDDEN,
+                "{method}"
+            );
+        }
+        assert_eq!(
+            status(Method::GET, "/protected/forms/scores", reader).await,
+            StatusCode::OK
+        );
+        assert_eq!(
+            status(Method::POST, "/protected/query/scores", reader).await,
+            StatusCode::OK
+        );
+        assert_eq!(
+            status(Method::POST, "/protected/forms/scores", "lead@team5907.org").await,
+            StatusCode::OK
+        );
+    }
+
+    #[tokio::test]
+    async fn sweep_drops_expired_pkce_pairs() {
+        let authenticator = login_authenticator(SessionStoreConfig {
+            pkce_ttl_secs: 60,
+            ..Default::default()
+        });
+        let now = chrono::Utc::now().timestamp();
+        let stamped = |created_at: i64| Stamped {
+            value: "verifier".into(),
+            created_at,
+        };
+        authenticator.code_pairs.write().await.extend([
+            ("stale".into(), stamped(now - 61)),
+            ("fresh".into(), stamped(now)),
+        ]);
+
+        assert_eq!(authenticator.sweep_expired().await, 1);
+
+        let code_pairs = authenticator.code_pairs.read().await;
+        assert!(!code_pairs.contains_key("stale"));
+        assert!(code_pairs.contains_key("fresh"));
+    }
+
+    #[test]
+    fn jwt_from_headers_prefers_the_authorization_header() {
+        let headers = |pairs: &[(header::HeaderName, &str)]| {
+            let mut headers = HeaderMap::new();
+            for (name, value) in pairs {
+                headers.insert(name, value.parse().unwrap());
+            }
+            headers
+        };
+
+        assert_eq!(
+            jwt_from_headers(&headers(&[(header::AUTHORIZATION, "Bearer from-header")])),
+            Some("from-header".into())
+        );
+        assert_eq!(
+            jwt_from_headers(&headers(&[(header::COOKIE, "jwt=from-cookie")])),
+            Some("from-cookie".into())
+        );
+        assert_eq!(
+            jwt_from_headers(&headers(&[
+                (header::AUTHORIZATION, "bearer from-header"),
+                (header::COOKIE, "jwt=from-cookie"),
+            ])),
+            Some("from-header".into())
+        );
+        assert_eq!(jwt_from_headers(&HeaderMap::new()), None);
+    }
+}
@@ -1,54 +1,129 @@
-use crate::storage_manager::StorageManager;
-use anyhow::Error;
+use crate::auth::GoogleUser;
+use crate::storage_manager::{StorageError, StorageManager, UploadSession};
 use axum::body::Bytes;
-use axum::extract::Path;
-use axum::http::StatusCode;
+use axum::extract::{Path, Query};
+use axum::http::{header, HeaderMap, StatusCode};
 use axum::response::{IntoResponse, Response};
-use axum::Extension;
+use axum::{Extension, Json};
+use serde::Deserialize;
 use std::sync::Arc;
-use tracing::{info, instrument};
+use tracing::instrument;
 
 #[instrument(skip(storage_manager, parts))]
 pub async fn store_bytes(
     Path(blob_id): Path<String>,
+    user: GoogleUser,
     storage_manager: Extension<Arc<StorageManager>>,
     parts: Bytes,
 ) -> StoreBytesResponse {
-    let blob_id = blob_id.clone();
-
-    let id = sha256::digest(&blob_id);
-
-    match storage_manager.bytes_add(id, blob_id, parts.as_ref()).await {
+    match storage_manager
+        .bytes_add(blob_id, parts.as_ref(), &user.email)
+        .await
+    {
         Ok(_) => StoreBytesResponse::OK,
         Err(_) => StoreBytesResponse::FailedToWriteBlob,
     }
 }
 
-#[instrument(skip(storage_manager))]
+#[instrument(skip(storage_manager, parts))]
+pub async fn store_bytes_cas(
+    user: GoogleUser,
+    storage_manager: Extension<Arc<StorageManager>>,
+    parts: Bytes,
+) -> StoreBytesResponse {
+    match storage_manager
+        .bytes_add_cas(parts.as_ref(), &user.email)
+        .await
+    {
+        Ok(key) => StoreBytesResponse::Key(key),
+        Err(_) => StoreBytesResponse::FailedToWriteBlob,
+    }
+}
+
+/// Serves a blob, or with a single `Range` the requested part of it. With
+/// `?verify=true` a whole read is checked against its recorded checksum first.
+#[instrument(skip(headers, storage_manager))]
 pub async fn get_bytes(
     Path(blob_id): Path<String>,
+    Query(query): Query<VerifyQuery>,
+    headers: HeaderMap,
     storage_manager: Extension<Arc<StorageManager>>,
 ) -> StoreBytesResponse {
-    let blob_id = blob_id.clone();
+    let range = headers
+        .get(header::RANGE)
+        .and_then(|v| v.to_str().ok())
+        .and_then(parse_range);
+
+    if let Some(range) = range {
+        let total = match storage_manager.bytes_size(blob_id.clone()).await {
+            Ok(Some(total)) => total,
+            // let the full read report why the blob is missing
+            Ok(None) => return get_whole_bytes(blob_id, false, &storage_manager).await,
+            Err(_) => return StoreBytesResponse::FailedToReadBlobs,
+        };
 
-    let blob_id = sha256::digest(blob_id);
+        let Some((start, end)) = resolve_range(range, total) else {
+            return StoreBytesResponse::RangeNotSatisfiable(total);
+        };
 
-    match storage_manager.bytes_get(blob_id).await {
+        return match storage_manager.bytes_get_range(blob_id, start, end).await {
+            Ok(data) => StoreBytesResponse::Partial { data, start, total },
+            Err(_) => StoreBytesResponse::FailedToReadBlobs,
+        };
+    }
+
+    get_whole_bytes(blob_id, query.verify, &storage_manager).await
+}
+
+#[derive(Debug, Deserialize)]
+pub struct VerifyQuery {
+    #[serde(default)]
+    verify: bool,
+}
+
+async fn get_whole_bytes(
+    blob_id: String,
+    verify: bool,
+    storage_manager: &StorageManager,
+) -> StoreBytesResponse {
+    match storage_manager.bytes_get(blob_id, verify).await {
         Ok(bytes) => StoreBytesResponse::Data(bytes),
-        Err(_) => StoreBytesResponse::NotFound,
+        Err(e) => match e.downcast_ref::<StorageError>() {
+            Some(StorageError::Deleted) => StoreBytesResponse::Gone,
+            Some(StorageError::ChecksumMismatch(_) | StorageError::Truncated(_)) => {
+                StoreBytesResponse::Corrupt
+            }
+            Some(StorageError::NotFound) => StoreBytesResponse::Missing,
+            _ => StoreBytesResponse::NotFound,
+        },
     }
 }
 
 #[instrument(skip(storage_manager))]
-pub async fn delete_bytes(
+pub async fn head_bytes(
     Path(blob_id): Path<String>,
     storage_manager: Extension<Arc<StorageManager>>,
 ) -> StoreBytesResponse {
-    let blob_id = blob_id.clone();
+    match storage_manager.bytes_deleted(&blob_id).await {
+        Ok(false) => {}
+        Ok(true) => return StoreBytesResponse::Missing,
+        Err(_) => return StoreBytesResponse::FailedToReadBlobs,
+    }
 
-    let blob_id = sha256::digest(blob_id);
+    match storage_manager.bytes_size(blob_id).await {
+        Ok(Some(size)) => StoreBytesResponse::Size(size),
+        Ok(None) => StoreBytesResponse::Missing,
+        Err(_) => StoreBytesResponse::FailedToReadBlobs,
+    }
+}
 
-    let _ = storage_manager.bytes_delete(blob_id).await;
+#[instrument(skip(storage_manager))]
+pub async fn delete_bytes(
+    Path(blob_id): Path<String>,
+    user: GoogleUser,
+    storage_manager: Extension<Arc<StorageManager>>,
+) -> StoreBytesResponse {
+    let _ = storage_manager.bytes_delete(blob_id, &user.email).await;
 
     StoreBytesResponse::DeleteSuccess
 }
@@ -56,15 +131,12 @@ pub async fn delete_bytes(
 #[instrument(skip(storage_manager, parts))]
 pub async fn edit_bytes(
     Path(blob_id): Path<String>,
+    user: GoogleUser,
     storage_manager: Extension<Arc<StorageManager>>,
     parts: Bytes,
 ) -> StoreBytesResponse {
-    let blob_id = blob_id.clone();
-
-    let id = sha256::digest(&blob_id);
-
     match storage_manager
-        .bytes_edit(id, blob_id, parts.as_ref())
+        .bytes_edit(blob_id, parts.as_ref(), &user.email)
         .await
     {
         Ok(_) => StoreBytesResponse::OK,
@@ -80,33 +152,267 @@ pub async fn list_bytes(storage_manager: Extension<Arc<StorageManager>>) -> Stor
     }
 }
 
+#[instrument(skip(storage_manager))]
+pub async fn start_upload(
+    Path(blob_id): Path<String>,
+    storage_manager: Extension<Arc<StorageManager>>,
+) -> StoreBytesResponse {
+    match storage_manager.upload_start(blob_id).await {
+        Ok(session) => StoreBytesResponse::Upload(session),
+        Err(_) => StoreBytesResponse::FailedToWriteBlob,
+    }
+}
+
+#[instrument(skip(storage_manager, headers, parts))]
+pub async fn append_upload(
+    Path(blob_id): Path<String>,
+    storage_manager: Extension<Arc<StorageManager>>,
+    headers: HeaderMap,
+    parts: Bytes,
+) -> StoreBytesResponse {
+    let (start, total) = match headers
+        .get(header::CONTENT_RANGE)
+        .and_then(|v| v.to_str().ok())
+        .and_then(|v| parse_content_range(v, parts.len() as u64))
+    {
+        Some(range) => range,
+        None => return StoreBytesResponse::BadRange,
+    };
+
+    match storage_manager
+        .upload_append(blob_id, start, total, parts.as_ref())
+        .await
+    {
+        Ok(session) => StoreBytesResponse::Upload(session),
+        Err(e) => StoreBytesResponse::from_upload_error(e),
+    }
+}
+
+#[instrument(skip(storage_manager))]
+pub async fn finalize_upload(
+    Path(blob_id): Path<String>,
+    user: GoogleUser,
+    storage_manager: Extension<Arc<StorageManager>>,
+) -> StoreBytesResponse {
+    match storage_manager.upload_finalize(blob_id, &user.email).await {
+        Ok(_) => StoreBytesResponse::OK,
+        Err(e) => StoreBytesResponse::from_upload_error(e),
+    }
+}
+
+/// A single range from a `Range` header. Multiple ranges are not supported
+/// and fall back to the whole blob.
+#[derive(Debug, Clone, Copy)]
+enum ByteRange {
+    /// `bytes=start-` or `bytes=start-end`
+    From(u64, Option<u64>),
+    /// `bytes=-len`, the last `len` bytes
+    Suffix(u64),
+}
+
+fn parse_range(value: &str) -> Option<ByteRange> {
+    let (start, end) = value.strip_prefix("bytes=")?.trim().split_once('-')?;
+
+    if start.is_empty() {
+        return Some(ByteRange::Suffix(end.parse().ok()?));
+    }
+
+    let end = match end {
+        "" => None,
+        end => Some(end.parse().ok()?),
+    };
+
+    Some(ByteRange::From(start.parse().ok()?, end))
+}
+
+/// Clamps a range to a blob of `total` bytes, as inclusive offsets.
+fn resolve_range(range: ByteRange, total: u64) -> Option<(u64, u64)> {
+    let last = total.checked_sub(1)?;
+
+    match range {
+        ByteRange::From(start, end) if start <= last => {
+            let end = end.unwrap_or(last).min(last);
+            (start <= end).then_some((start, end))
+        }
+        ByteRange::From(..) => None,
+        ByteRange::Suffix(0) => None,
+        ByteRange::Suffix(len) => Some((total.saturating_sub(len), last)),
+    }
+}
+
+/// Parses `bytes start-end/total` (total may be `*`) and checks that the
+/// range length matches the chunk that was sent.
+fn parse_content_range(value: &str, len: u64) -> Option<(u64, Option<u64>)> {
+    let (range, total) = value.strip_prefix("bytes ")?.split_once('/')?;
+    let (start, end) = range.split_once('-')?;
+    let start = start.trim().parse::<u64>().ok()?;
+    let end = end.trim().parse::<u64>().ok()?;
+
+    if end < start || end - start + 1 != len {
+        return None;
+    }
+
+    let total = match total.trim() {
+        "*" => None,
+        t => Some(t.parse::<u64>().ok()?),
+    };
+
+    Some((start, total))
+}
+
 #[derive(Debug)]
 pub enum StoreBytesResponse {
     OK,
+    Key(String),
     FailedToWriteBlob,
     Data(Vec<u8>),
+    Partial {
+        data: Vec<u8>,
+        start: u64,
+        total: u64,
+    },
+    RangeNotSatisfiable(u64),
+    Size(u64),
     List(String),
     NotFound,
+    Missing,
+    Gone,
     DeleteSuccess,
     FailedToEdit,
     FailedToReadBlobs,
+    Corrupt,
+    Upload(UploadSession),
+    UploadConflict(u64),
+    UploadNotFound,
+    UploadIncomplete,
+    BadRange,
+}
+
+impl StoreBytesResponse {
+    fn from_upload_error(e: anyhow::Error) -> Self {
+        match e.downcast_ref::<StorageError>() {
+            Some(StorageError::UploadOffsetMismatch(offset)) => Self::UploadConflict(*offset),
+            Some(StorageError::UploadNotFound) => Self::UploadNotFound,
+            Some(StorageError::UploadIncomplete { .. }) => Self::UploadIncomplete,
+            _ => Self::FailedToWriteBlob,
+        }
+    }
 }
 
 impl IntoResponse for StoreBytesResponse {
     fn into_response(self) -> Response {
         match self {
             StoreBytesResponse::OK => StatusCode::OK.into_response(),
+            StoreBytesResponse::Key(key) => (StatusCode::OK, Json(key)).into_response(),
             StoreBytesResponse::FailedToWriteBlob => {
                 StatusCode::INTERNAL_SERVER_ERROR.into_response()
             }
-            StoreBytesResponse::Data(data) => (StatusCode::OK, data).into_response(),
+            StoreBytesResponse::Data(data) => {
+                (StatusCode::OK, [(header::ACCEPT_RANGES, "bytes")], data).into_response()
+            }
+            StoreBytesResponse::Partial { data, start, total } => (
+                StatusCode::PARTIAL_CONTENT,
+                [(
+                    header::CONTENT_RANGE,
+                    format!("bytes {start}-{}/{total}", start + data.len() as u64 - 1),
+                )],
+                data,
+            )
+                .into_response(),
+            StoreBytesResponse::RangeNotSatisfiable(total) => (
+                StatusCode::RANGE_NOT_SATISFIABLE,
+                [(header::CONTENT_RANGE, format!("bytes */{total}"))],
+            )
+                .into_response(),
+            StoreBytesResponse::Size(size) => {
+                (StatusCode::OK, [(header::CONTENT_LENGTH, size.to_string())]).into_response()
+            }
             StoreBytesResponse::NotFound => StatusCode::BAD_REQUEST.into_response(),
+            StoreBytesResponse::Missing => StatusCode::NOT_FOUND.into_response(),
+            StoreBytesResponse::Gone => StatusCode::GONE.into_response(),
             StoreBytesResponse::DeleteSuccess => StatusCode::OK.into_response(),
             StoreBytesResponse::FailedToEdit => StatusCode::INTERNAL_SERVER_ERROR.into_response(),
             StoreBytesResponse::List(list) => (StatusCode::OK, list).into_response(),
             StoreBytesResponse::FailedToReadBlobs => {
                 StatusCode::INTERNAL_SERVER_ERROR.into_response()
             }
+            StoreBytesResponse::Corrupt => {
+                (StatusCode::INTERNAL_SERVER_ERROR, "blob file is corrupt").into_response()
+            }
+            StoreBytesResponse::Upload(session) => (StatusCode::OK, Json(session)).into_response(),
+            StoreBytesResponse::UploadConflict(offset) => (
+                StatusCode::CONFLICT,
+                [(
+                    header::RANGE,
+                    format!("bytes=0-{}", offset.saturating_sub(1)),
+                )],
+            )
+                .into_response(),
+            StoreBytesResponse::UploadNotFound => StatusCode::NOT_FOUND.into_response(),
+            StoreBytesResponse::UploadIncomplete => StatusCode::BAD_REQUEST.into_response(),
+            StoreBytesResponse::BadRange => StatusCode::BAD_REQUEST.into_response(),
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::storage_manager::tests::storage;
+
+    #[tokio::test]
+    async fn missing_blobs_are_told_apart_from_deleted_ones() {
+        let (_dir, storage) = storage().await;
+        storage
+            .bytes_add("photo".into(), b"pixels", "lead@example.com")
+            .await
+            .unwrap();
+        storage
+            .bytes_delete("photo".into(), "lead@example.com")
+            .await
+            .unwrap();
+
+        let deleted = get_whole_bytes("photo".into(), false, &storage).await;
+        assert_eq!(deleted.into_response().status(), StatusCode::GONE);
+
+        let never_stored = get_whole_bytes("sketch".into(), false, &storage).await;
+        assert_eq!(never_stored.into_response().status(), StatusCode::NOT_FOUND);
+    }
+
+    fn resolve(header: &str, total: u64) -> Option<(u64, u64)> {
+        resolve_range(parse_range(header).unwrap(), total)
+    }
+
+    #[test]
+    fn ranges_resolve_to_inclusive_offsets() {
+        assert_eq!(resolve("bytes=2-5", 10), Some((2, 5)));
+        // open ended, and an end past the blob, run to the last byte
+        assert_eq!(resolve("bytes=4-", 10), Some((4, 9)));
+        assert_eq!(resolve("bytes=4-100", 10), Some((4, 9)));
+        // suffixes count back from the end, capped at the whole blob
+        assert_eq!(resolve("bytes=-3", 10), Some((7, 9)));
+        assert_eq!(resolve("bytes=-30", 10), Some((0, 9)));
+    }
+
+    #[test]
+    fn unsatisfiable_ranges_are_rejected() {
+        assert_eq!(resolve("bytes=10-", 10), None);
+        assert_eq!(resolve("bytes=12-20", 10), None);
+        assert_eq!(resolve("bytes=5-2", 10), None);
+        assert_eq!(resolve("bytes=-0", 10), None);
+        // nothing in an empty blob can be addressed
+        assert_eq!(resolve("bytes=0-", 0), None);
+        assert_eq!(resolve("bytes=-5", 0), None);
+
+        let response = StoreBytesResponse::RangeNotSatisfiable(10).into_response();
+        assert_eq!(response.status(), StatusCode::RANGE_NOT_SATISFIABLE);
+        assert_eq!(response.headers()[header::CONTENT_RANGE], "bytes */10");
+    }
+
+    #[test]
+    fn malformed_ranges_are_ignored() {
+        assert!(parse_range("bytes=a-5").is_none());
+        assert!(parse_range("items=0-5").is_none());
+        assert!(parse_range("bytes=5").is_none());
+    }
+}
@@ -0,0 +1,45 @@
+use crate::datatypes::Dashboard;
+use crate::storage_manager::StorageManager;
+use axum::extract::{Path, Query};
+use axum::http::StatusCode;
+use axum::response::{IntoResponse, Response};
+use axum::{Extension, Json};
+use serde::Deserialize;
+use std::sync::Arc;
+use tracing::instrument;
+
+#[instrument(skip(storage_manager))]
+pub async fn dashboard(
+    Path(template): Path<String>,
+    Query(query): Query<DashboardQuery>,
+    storage_manager: Extension<Arc<StorageManager>>,
+) -> DashboardResponse {
+    match storage_manager
+        .dashboard(template, query.event, query.latest.unwrap_or(20))
+        .await
+    {
+        Ok(d) => DashboardResponse::Dashboard(d),
+        Err(_) => DashboardResponse::FailedToRead,
+    }
+}
+
+#[derive(Debug, Deserialize)]
+pub struct DashboardQuery {
+    event: Option<String>,
+    latest: Option<usize>,
+}
+
+#[derive(Debug)]
+pub enum DashboardResponse {
+    Dashboard(Dashboard),
+    FailedToRead,
+}
+
+impl IntoResponse for DashboardResponse {
+    fn into_response(self) -> Response {
+        match self {
+            DashboardResponse::Dashboard(d) => (StatusCode::OK, Json(d)).into_response(),
+            DashboardResponse::FailedToRead => StatusCode::BAD_REQUEST.into_response(),
+        }
+    }
+}
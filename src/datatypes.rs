@@ -1,35 +1,287 @@
-use crate::auth::GoogleUser;
+use crate::storage_manager::shard;
+use crate::transactions::{Action, InternalMessage};
 use axum::async_trait;
 use axum::extract::FromRequestParts;
 use axum::http::request::Parts;
 use axum::response::Response;
-use datafusion::arrow::array::StringBuilder;
+use datafusion::arrow::array::{
+    ArrayRef, BooleanBuilder, Int64Builder, RecordBatch, StringBuilder,
+};
+use datafusion::arrow::datatypes::{DataType, Field, Schema};
+use datafusion::arrow::error::ArrowError;
 use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
 use sha256::Sha256Digest;
-use std::collections::HashMap;
-use std::ops::Add;
-use uuid::Uuid;
+use std::collections::{BTreeMap, HashMap};
+use std::sync::Arc;
 
 impl FormTemplate {
+    #[allow(dead_code)]
     pub fn new(name: &str, year: i64) -> Self {
         Self {
             fields: vec![],
             name: name.into(),
             year,
+            high_volume: false,
+            indexed_fields: vec![],
+            tba_event_keys: false,
+            team_range: TeamRange::default(),
+            version: 0,
         }
     }
 
+    #[allow(dead_code)]
     pub fn add_field(&mut self, name: &str, data_type: FieldDataType) {
         self.fields.push(FieldTemplate {
             name: name.into(),
             data_type,
+            section: None,
+            order: None,
+            optional: false,
+            default: None,
         });
     }
 
+    pub fn field_count(&self) -> usize {
+        self.fields.len()
+    }
+
+    pub fn get_field(&self, name: &str) -> Option<&FieldTemplate> {
+        self.fields.iter().find(|f| f.name == name)
+    }
+
+    /// Lays the forms out as a typed record batch: the form metadata columns
+    /// followed by one nullable column per (non-title) template field.
+    pub fn record_batch(&self, forms: &[Form]) -> Result<RecordBatch, ArrowError> {
+        let mut fields = vec![
+            Field::new("id", DataType::Utf8, true),
+            Field::new("scouter", DataType::Utf8, false),
+            Field::new("team", DataType::Int64, false),
+            Field::new("match_number", DataType::Int64, false),
+            Field::new("event_key", DataType::Utf8, false),
+        ];
+
+        let mut id = StringBuilder::new();
+        let mut scouter = StringBuilder::new();
+        let mut team = Int64Builder::new();
+        let mut match_number = Int64Builder::new();
+        let mut event_key = StringBuilder::new();
+
+        for form in forms {
+            id.append_option(form.id.as_ref());
+            scouter.append_value(&form.scouter);
+            team.append_value(form.team);
+            match_number.append_value(form.match_number);
+            event_key.append_value(&form.event_key);
+        }
+
+        let mut columns: Vec<ArrayRef> = vec![
+            Arc::new(id.finish()),
+            Arc::new(scouter.finish()),
+            Arc::new(team.finish()),
+            Arc::new(match_number.finish()),
+            Arc::new(event_key.finish()),
+        ];
+
+        for field in &self.fields {
+            let column: ArrayRef = match field.data_type {
+                FieldDataType::Title => continue,
+                FieldDataType::CheckBox => {
+                    let mut builder = BooleanBuilder::new();
+                    for form in forms {
+                        builder.append_option(match form.get_field(&field.name) {
+                            Some(FieldData::CheckBox(x)) => Some(*x),
+                            _ => None,
+                        });
+                    }
+                    fields.push(Field::new(&field.name, DataType::Boolean, true));
+                    Arc::new(builder.finish())
+                }
+                FieldDataType::Rating { .. } | FieldDataType::Number => {
+                    let mut builder = Int64Builder::new();
+                    for form in forms {
+                        builder.append_option(match form.get_field(&field.name) {
+                            Some(FieldData::Rating(x) | FieldData::Number(x)) => Some(*x),
+                            _ => None,
+                        });
+                    }
+                    fields.push(Field::new(&field.name, DataType::Int64, true));
+                    Arc::new(builder.finish())
+                }
+                FieldDataType::ShortText | FieldDataType::LongText => {
+                    let mut builder = StringBuilder::new();
+                    for form in forms {
+                        builder.append_option(match form.get_field(&field.name) {
+                            Some(FieldData::ShortText(x) | FieldData::LongText(x)) => Some(x),
+                            _ => None,
+                        });
+                    }
+                    fields.push(Field::new(&field.name, DataType::Utf8, true));
+                    Arc::new(builder.finish())
+                }
+            };
+
+            columns.push(column);
+        }
+
+        RecordBatch::try_new(Arc::new(Schema::new(fields)), columns)
+    }
+
+    /// Whether equality filters on this field can be answered from the
+    /// materialized `indexed` column rather than by inspecting each form.
+    pub fn is_indexed(&self, name: &str) -> bool {
+        self.high_volume
+            && self.indexed_fields.iter().any(|f| f == name)
+            && self.get_field(name).is_some_and(|f| f.is_numeric())
+    }
+
+    /// Copies the indexed numeric fields of a high-volume template into the
+    /// form's typed `indexed` column before it is stored.
+    pub fn materialize_indexed(&self, form: &mut Form) {
+        form.indexed = self
+            .indexed_fields
+            .iter()
+            .filter(|name| self.is_indexed(name))
+            .filter_map(|name| {
+                form.get_field(name)
+                    .and_then(|data| match data {
+                        FieldData::Rating(x) | FieldData::Number(x) => Some(*x),
+                        _ => None,
+                    })
+                    .map(|x| (name.clone(), x))
+            })
+            .collect();
+    }
+
+    /// Brings the form's metadata into the template's canonical shape before it
+    /// is hashed and validated.
+    pub fn normalize(&self, form: &mut Form) {
+        if self.tba_event_keys {
+            form.event_key = form.event_key.trim().to_lowercase();
+        }
+    }
+
+    /// Whether the form's event key is acceptable: four digits of year followed
+    /// by lowercase letters and digits when the template asks for TBA keys.
+    pub fn event_key_valid(&self, form: &Form) -> bool {
+        if !self.tba_event_keys {
+            return true;
+        }
+
+        let key = form.event_key.as_bytes();
+
+        key.len() > 4
+            && key[..4].iter().all(u8::is_ascii_digit)
+            && key[4..]
+                .iter()
+                .all(|c| c.is_ascii_lowercase() || c.is_ascii_digit())
+    }
+
+    /// A draft-07 JSON Schema for forms submitted against this template. The
+    /// `x-layout` extension lists field names by section in rendering order.
+    pub fn json_schema(&self) -> Value {
+        let mut properties = serde_json::Map::new();
+        let mut required = vec![];
+
+        for field in &self.fields {
+            let Some(schema) = field.json_schema() else {
+                continue;
+            };
+
+            properties.insert(field.name.clone(), schema);
+
+            if !field.optional {
+                required.push(field.name.clone());
+            }
+        }
+
+        let mut ordered: Vec<(usize, &FieldTemplate)> = self.fields.iter().enumerate().collect();
+        ordered.sort_by_key(|(i, f)| f.order.unwrap_or(*i as i64));
+
+        // sections in the order their first field appears
+        let mut layout: Vec<(Option<&String>, Vec<&String>)> = vec![];
+        for (_, field) in ordered {
+            let section = field.section.as_ref();
+
+            match layout.iter_mut().find(|(s, _)| *s == section) {
+                Some((_, names)) => names.push(&field.name),
+                None => layout.push((section, vec![&field.name])),
+            }
+        }
+
+        let layout: Vec<Value> = layout
+            .into_iter()
+            .map(|(section, fields)| json!({ "section": section, "fields": fields }))
+            .collect();
+
+        let mut event_key = json!({ "type": "string" });
+        if self.tba_event_keys {
+            event_key["pattern"] = json!("^\\d{4}[a-z0-9]+$");
+        }
+
+        json!({
+            "$schema": "http://json-schema.org/draft-07/schema#",
+            "title": self.name,
+            "type": "object",
+            "required": ["fields", "scouter", "team", "match_number", "event_key"],
+            "properties": {
+                "fields": {
+                    "type": "object",
+                    "properties": properties,
+                    "required": required,
+                },
+                "scouter": { "type": "string" },
+                "team": {
+                    "type": "integer",
+                    "minimum": self.team_range.min,
+                    "maximum": self.team_range.max,
+                },
+                "match_number": { "type": "integer" },
+                "event_key": event_key,
+                "id": { "type": ["string", "null"] },
+            },
+            "x-layout": layout,
+        })
+    }
+
+    /// Every field's default value, keyed by field name.
+    pub fn defaults(&self) -> HashMap<String, FieldData> {
+        self.fields
+            .iter()
+            .filter_map(|f| f.default.clone().map(|d| (f.name.clone(), d)))
+            .collect()
+    }
+
+    /// Names of fields whose default does not match their data type.
+    pub fn invalid_defaults(&self) -> Vec<String> {
+        self.fields
+            .iter()
+            .filter(|f| f.default.as_ref().is_some_and(|d| !f.data_type_match(d)))
+            .map(|f| f.name.clone())
+            .collect()
+    }
+
+    pub fn team_valid(&self, form: &Form) -> bool {
+        (self.team_range.min..=self.team_range.max).contains(&form.team)
+    }
+
+    /// Names of fields present on the form that the template does not declare.
+    pub fn unknown_fields(&self, form: &Form) -> Vec<String> {
+        let mut unknown: Vec<String> = form
+            .fields
+            .keys()
+            .filter(|name| self.get_field(name).is_none())
+            .cloned()
+            .collect();
+        unknown.sort();
+        unknown
+    }
+
     pub fn validate_form(&self, form: &Form) -> bool {
         for x in &self.fields {
             if !matches!(x.data_type, FieldDataType::Title) {
                 match form.get_field(&x.name) {
+                    None if x.optional => {}
                     None => return false,
                     Some(data) => {
                         if !x.data_type_match(data) {
@@ -45,6 +297,49 @@ impl FormTemplate {
 }
 
 impl FieldTemplate {
+    pub fn is_numeric(&self) -> bool {
+        matches!(
+            self.data_type,
+            FieldDataType::Number | FieldDataType::Rating { .. }
+        )
+    }
+
+    pub fn is_text(&self) -> bool {
+        matches!(
+            self.data_type,
+            FieldDataType::ShortText | FieldDataType::LongText
+        )
+    }
+
+    /// The schema of the field's serialized `FieldData`, or `None` for titles,
+    /// which carry no data.
+    fn json_schema(&self) -> Option<Value> {
+        let (variant, value) = match &self.data_type {
+            FieldDataType::Title => return None,
+            FieldDataType::CheckBox => ("CheckBox", json!({ "type": "boolean" })),
+            FieldDataType::Rating { min, max } => (
+                "Rating",
+                json!({ "type": "integer", "minimum": min, "maximum": max }),
+            ),
+            FieldDataType::Number => ("Number", json!({ "type": "integer" })),
+            FieldDataType::ShortText => ("ShortText", json!({ "type": "string" })),
+            FieldDataType::LongText => ("LongText", json!({ "type": "string" })),
+        };
+
+        let mut schema = json!({
+            "type": "object",
+            "properties": { variant: value },
+            "required": [variant],
+            "additionalProperties": false,
+        });
+
+        if let Some(default) = &self.default {
+            schema["default"] = json!(default);
+        }
+
+        Some(schema)
+    }
+
     fn data_type_match(&self, data: &FieldData) -> bool {
         match data {
             FieldData::CheckBox(_) => self.data_type == FieldDataType::CheckBox,
@@ -59,9 +354,21 @@ impl FieldTemplate {
 }
 
 #[derive(Serialize, Deserialize, Clone, Debug)]
-struct FieldTemplate {
+pub struct FieldTemplate {
     data_type: FieldDataType,
     name: String,
+    /// Heading the field is rendered under, e.g. "Autonomous".
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    section: Option<String>,
+    /// Rendering position. Fields without one keep their declaration position.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    order: Option<i64>,
+    /// Forms may leave the field out. It is still type checked when present.
+    #[serde(default)]
+    optional: bool,
+    /// Value a blank form is seeded with.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    default: Option<FieldData>,
 }
 
 #[derive(Serialize, Deserialize, Clone, Debug)]
@@ -69,6 +376,32 @@ pub struct FormTemplate {
     fields: Vec<FieldTemplate>,
     pub name: String,
     year: i64,
+    #[serde(default)]
+    high_volume: bool,
+    #[serde(default)]
+    indexed_fields: Vec<String>,
+    /// Require event keys in The Blue Alliance format, e.g. `2024nyro`.
+    #[serde(default)]
+    tba_event_keys: bool,
+    #[serde(default)]
+    pub team_range: TeamRange,
+    /// Bumped by the server on every edit. Templates stored before versioning are 0.
+    #[serde(default)]
+    pub version: u64,
+}
+
+/// Inclusive bounds on the team numbers a template accepts.
+#[derive(Serialize, Deserialize, Clone, Copy, Debug)]
+pub struct TeamRange {
+    pub min: i64,
+    pub max: i64,
+}
+
+impl Default for TeamRange {
+    /// FRC team numbers.
+    fn default() -> Self {
+        Self { min: 1, max: 99999 }
+    }
 }
 
 #[derive(Serialize, Deserialize, Eq, PartialEq, Clone, Debug)]
@@ -82,6 +415,7 @@ pub enum FieldDataType {
 }
 
 impl Form {
+    #[allow(dead_code)]
     pub fn add_field(&mut self, name: &str, data: FieldData) {
         self.fields.insert(name.into(), data);
     }
@@ -89,6 +423,33 @@ impl Form {
     pub fn get_field(&self, name: &str) -> Option<&FieldData> {
         self.fields.get(name)
     }
+
+    /// The form's numeric (number and rating) fields.
+    pub fn numeric_fields(&self) -> impl Iterator<Item = (&String, f64)> {
+        self.fields
+            .iter()
+            .filter_map(|(name, data)| data.as_number().map(|x| (name, x)))
+    }
+
+    /// Overwrites the given fields, leaving the rest as they are.
+    pub fn merge_fields(&mut self, partial: HashMap<String, FieldData>) {
+        self.fields.extend(partial);
+    }
+
+    /// A sha256 of the submitted content, independent of field order and of the
+    /// server-assigned id.
+    pub fn compute_content_hash(&self) -> Result<String, serde_json::Error> {
+        let fields: BTreeMap<&String, &FieldData> = self.fields.iter().collect();
+
+        serde_json::to_string(&(
+            fields,
+            &self.scouter,
+            self.team,
+            self.match_number,
+            &self.event_key,
+        ))
+        .map(|s| s.digest())
+    }
 }
 
 #[derive(Default, Debug, Serialize, Deserialize, Clone)]
@@ -99,14 +460,36 @@ pub struct Form {
     pub match_number: i64,
     pub event_key: String,
     pub id: Option<String>,
+    #[serde(default, skip_serializing_if = "HashMap::is_empty")]
+    pub indexed: HashMap<String, i64>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub content_hash: Option<String>,
+    /// Version of the template the form was validated against when written.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub template_version: Option<u64>,
 }
 
-#[derive(Debug, Deserialize, Serialize)]
+#[derive(Default, Debug, Deserialize, Serialize)]
 pub struct Filter {
     pub match_number: Option<i64>,
     pub team: Option<i64>,
     pub event: Option<String>,
     pub scouter: Option<String>,
+    pub field: Option<String>,
+    pub value: Option<i64>,
+    pub template_version: Option<u64>,
+    /// One of `match_number`, `team` or `scouter`.
+    pub sort_by: Option<String>,
+    pub sort_dir: Option<SortDir>,
+}
+
+#[derive(Default, Debug, Deserialize, Serialize, Clone, Copy, PartialEq, Eq)]
+pub enum SortDir {
+    #[default]
+    #[serde(alias = "asc")]
+    Asc,
+    #[serde(alias = "desc")]
+    Desc,
 }
 
 #[derive(Debug, Serialize, Deserialize, Clone)]
@@ -118,6 +501,216 @@ pub enum FieldData {
     LongText(String),
 }
 
+impl Dashboard {
+    pub const STATIONS: usize = 6;
+
+    pub fn new(forms: &[Form], latest_transactions: Vec<InternalMessage>) -> Self {
+        let mut per_team: BTreeMap<i64, usize> = BTreeMap::new();
+        let mut per_match: BTreeMap<i64, usize> = BTreeMap::new();
+
+        for form in forms {
+            *per_team.entry(form.team).or_default() += 1;
+            *per_match.entry(form.match_number).or_default() += 1;
+        }
+
+        let last_match = per_match.keys().last().copied().unwrap_or(0);
+
+        Self {
+            teams: per_team.keys().copied().collect(),
+            form_counts: per_team
+                .into_iter()
+                .map(|(team, forms)| TeamFormCount { team, forms })
+                .collect(),
+            coverage_gaps: (1..=last_match)
+                .map(|match_number| CoverageGap {
+                    match_number,
+                    forms: per_match.get(&match_number).copied().unwrap_or(0),
+                })
+                .filter(|gap| gap.forms < Self::STATIONS)
+                .collect(),
+            latest_transactions,
+        }
+    }
+}
+
+impl Filter {
+    /// Whether a form passes every criterion set on the filter.
+    pub fn matches(&self, form: &Form) -> bool {
+        let field_matches = match (&self.field, self.value) {
+            (Some(field), Some(value)) => {
+                form.get_field(field).and_then(|data| data.as_number()) == Some(value as f64)
+            }
+            _ => true,
+        };
+
+        field_matches
+            && self.match_number.is_none_or(|m| form.match_number == m)
+            && self.team.is_none_or(|t| form.team == t)
+            && self.event.as_ref().is_none_or(|e| &form.event_key == e)
+            && self.scouter.as_ref().is_none_or(|s| &form.scouter == s)
+            && self
+                .template_version
+                .is_none_or(|v| form.template_version == Some(v))
+    }
+}
+
+impl FieldData {
+    pub fn as_number(&self) -> Option<f64> {
+        match self {
+            FieldData::Rating(x) | FieldData::Number(x) => Some(*x as f64),
+            _ => None,
+        }
+    }
+
+    pub fn as_text(&self) -> Option<&str> {
+        match self {
+            FieldData::ShortText(x) | FieldData::LongText(x) => Some(x),
+            _ => None,
+        }
+    }
+}
+
+impl FieldStats {
+    /// Summarizes a set of values, using the population standard deviation.
+    pub fn from_values(values: &[f64]) -> Self {
+        if values.is_empty() {
+            return Self::default();
+        }
+
+        let count = values.len();
+        let mean = values.iter().sum::<f64>() / count as f64;
+        let variance = values.iter().map(|x| (x - mean).powi(2)).sum::<f64>() / count as f64;
+
+        let mut sorted = values.to_vec();
+        sorted.sort_by(f64::total_cmp);
+        let median = match count % 2 {
+            0 => (sorted[count / 2 - 1] + sorted[count / 2]) / 2.0,
+            _ => sorted[count / 2],
+        };
+
+        Self {
+            count,
+            min: sorted[0],
+            max: sorted[count - 1],
+            mean,
+            median,
+            stddev: variance.sqrt(),
+        }
+    }
+}
+
+/// A live form's id with when and how it was last written.
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct FormSummary {
+    pub id: String,
+    /// Unix micros of the latest transaction for the form.
+    pub last_modified: i64,
+    pub last_action: Action,
+}
+
+/// A template with how many live forms it has.
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct TemplateCount {
+    pub name: String,
+    pub year: i64,
+    pub form_count: usize,
+}
+
+/// How many live forms a scouter has submitted.
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct ScouterActivity {
+    pub scouter: String,
+    pub forms: usize,
+}
+
+/// A match and its teams in station order, red 1-3 then blue 1-3.
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct ExpectedMatch {
+    pub match_number: i64,
+    pub teams: Vec<i64>,
+}
+
+/// A scheduled robot with no live form.
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq, Eq, PartialOrd, Ord)]
+pub struct MissingCoverage {
+    pub match_number: i64,
+    /// Zero-based index into the match's `teams`.
+    pub station: usize,
+    pub team: i64,
+}
+
+/// Forms scouting the same team in the same match.
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct DuplicateScouting {
+    pub match_number: i64,
+    pub team: i64,
+    pub scouters: Vec<String>,
+    pub ids: Vec<String>,
+}
+
+/// How closely a scouter's numeric fields agree with the other scouters of the
+/// same robots.
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct Scouter {
+    pub scouter: String,
+    /// `1 / (1 + mean relative deviation)` from the per-match median, so 1.0 is
+    /// perfect agreement. `None` when no match could be compared.
+    pub accuracy: Option<f32>,
+    /// Matches compared, i.e. those scouted by at least one other scouter.
+    pub matches: usize,
+}
+
+#[derive(Default, Debug, Serialize, Deserialize, Clone)]
+pub struct FieldStats {
+    pub count: usize,
+    pub min: f64,
+    pub max: f64,
+    pub mean: f64,
+    pub median: f64,
+    pub stddev: f64,
+}
+
+/// A team's stats for one field at two events. Deltas are `event_b - event_a`.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct EventComparison {
+    pub event_a: FieldStats,
+    pub event_b: FieldStats,
+    pub mean_delta: f64,
+    pub median_delta: f64,
+}
+
+impl EventComparison {
+    pub fn new(event_a: FieldStats, event_b: FieldStats) -> Self {
+        Self {
+            mean_delta: event_b.mean - event_a.mean,
+            median_delta: event_b.median - event_a.median,
+            event_a,
+            event_b,
+        }
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct Dashboard {
+    pub teams: Vec<i64>,
+    pub form_counts: Vec<TeamFormCount>,
+    pub coverage_gaps: Vec<CoverageGap>,
+    pub latest_transactions: Vec<InternalMessage>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct TeamFormCount {
+    pub team: i64,
+    pub forms: usize,
+}
+
+/// A match that has fewer forms than there are alliance stations.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct CoverageGap {
+    pub match_number: i64,
+    pub forms: usize,
+}
+
 #[derive(Default, Debug, Serialize, Deserialize, Clone)]
 pub struct Schedule {
     pub event: String,
@@ -156,8 +749,14 @@ where
             Some(&"bytes") => match uri.get(1) {
                 None => Some("bytes".into()),
                 Some(name) => match uri.get(2) {
-                    None => Some(format!("bytes/{}.current", name.digest())),
-                    Some(ver_id) => Some(format!("bytes/{}.{}", name.digest(), ver_id.digest())),
+                    None => {
+                        let name = name.digest();
+                        Some(format!("bytes/{}{name}.current", shard(&name)))
+                    }
+                    Some(ver_id) => {
+                        let name = name.digest();
+                        Some(format!("bytes/{}{name}.{}", shard(&name), ver_id.digest()))
+                    }
                 },
             },
             Some(&"template" | &"templates") => match uri.get(1) {
@@ -224,3 +823,35 @@ where
         Ok(Self(path))
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn optional_fields_may_be_absent_but_not_mistyped() {
+        let template: FormTemplate = serde_json::from_value(json!({
+            "name": "scores",
+            "year": 2024,
+            "fields": [
+                { "data_type": "Number", "name": "score" },
+                { "data_type": "ShortText", "name": "notes", "optional": true },
+            ],
+        }))
+        .unwrap();
+        let form = |fields: &[(&str, FieldData)]| {
+            let mut form = Form::default();
+            for (name, data) in fields {
+                form.add_field(name, data.clone());
+            }
+            form
+        };
+
+        assert!(template.validate_form(&form(&[("score", FieldData::Number(10))])));
+        assert!(!template.validate_form(&form(&[
+            ("score", FieldData::Number(10)),
+            ("notes", FieldData::Number(3)),
+        ])));
+        assert!(!template.validate_form(&form(&[("notes", FieldData::ShortText("quick".into()))])));
+    }
+}
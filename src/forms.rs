@@ -1,34 +1,83 @@
-use crate::datatypes::{Filter, Form, Schedule};
-use crate::storage_manager::StorageManager;
-use anyhow::Error;
+use crate::auth::GoogleUser;
+use crate::datatypes::{
+    DuplicateScouting, ExpectedMatch, FieldData, Filter, Form, FormSummary, MissingCoverage,
+    Scouter, ScouterActivity,
+};
+use crate::storage_manager::{StorageError, StorageManager};
+use crate::transactions::RestoreSummary;
+use axum::body::Body;
 use axum::extract::{Path, Query};
-use axum::http::StatusCode;
+use axum::http::{header, HeaderMap, StatusCode};
 use axum::response::{IntoResponse, Response};
 use axum::{Extension, Json};
-use datafusion::arrow::compute::filter;
+use futures::StreamExt;
+use serde::Deserialize;
+use std::collections::HashMap;
 use std::sync::Arc;
 use tracing::{info, instrument};
+use uuid::Uuid;
 
-#[instrument(skip(form, storage_manager))]
+#[instrument(skip(form, headers, storage_manager))]
 pub async fn add_form(
     Path(template): Path<String>,
+    Query(query): Query<AddFormQuery>,
+    user: GoogleUser,
+    headers: HeaderMap,
     storage_manager: Extension<Arc<StorageManager>>,
     Json(form): Json<Form>,
 ) -> FormsResponse {
-    match storage_manager.forms_add(template, form).await {
+    let dedupe = query.dedupe.unwrap_or(false);
+    let key = headers
+        .get("idempotency-key")
+        .and_then(|v| v.to_str().ok())
+        .map(String::from);
+
+    let added = match key {
+        Some(key) => {
+            storage_manager
+                .forms_add_idempotent(template, form, dedupe, key, &user.email)
+                .await
+        }
+        None => {
+            storage_manager
+                .forms_add(template, form, dedupe, &user.email)
+                .await
+        }
+    };
+
+    match added {
         Ok(id) => FormsResponse::ID(id),
-        Err(_) => FormsResponse::FailedToAdd,
+        Err(e) => FormsResponse::from_error(e, FormsResponse::FailedToAdd),
     }
 }
 
 #[instrument(skip(storage_manager))]
 pub async fn list_forms(
     Path(template): Path<String>,
-    storage_manager: Extension<Arc<StorageManager>>
+    Query(query): Query<DetailedQuery>,
+    storage_manager: Extension<Arc<StorageManager>>,
 ) -> FormsResponse {
+    if query.detailed.unwrap_or(false) {
+        return match storage_manager.forms_list_detailed(template).await {
+            Ok(l) => FormsResponse::Summaries(l),
+            Err(_) => FormsResponse::FailedToRead,
+        };
+    }
+
     match storage_manager.forms_list(template).await {
         Ok(l) => FormsResponse::IDList(l),
-        Err(_) => FormsResponse::FailedToRead
+        Err(_) => FormsResponse::FailedToRead,
+    }
+}
+
+#[instrument(skip(storage_manager))]
+pub async fn list_events(
+    Path(template): Path<String>,
+    storage_manager: Extension<Arc<StorageManager>>,
+) -> FormsResponse {
+    match storage_manager.list_events(template).await {
+        Ok(l) => FormsResponse::Events(l),
+        Err(_) => FormsResponse::FailedToRead,
     }
 }
 
@@ -46,12 +95,33 @@ pub async fn get_form(
 #[instrument(skip(storage_manager, form))]
 pub async fn edit_form(
     Path((template, id)): Path<(String, String)>,
+    Query(query): Query<EditFormQuery>,
+    user: GoogleUser,
     storage_manager: Extension<Arc<StorageManager>>,
     Json(form): Json<Form>,
 ) -> FormsResponse {
-    match storage_manager.forms_edit(template, form, id).await {
-        Ok(_) => FormsResponse::OK,
-        Err(_) => FormsResponse::FailedToEdit,
+    match storage_manager
+        .forms_edit(template, form, id, query.expected_blob_id, &user.email)
+        .await
+    {
+        Ok(blob_id) => FormsResponse::EditedBlob(blob_id),
+        Err(e) => FormsResponse::from_error(e, FormsResponse::FailedToEdit),
+    }
+}
+
+#[instrument(skip(storage_manager, fields))]
+pub async fn patch_form(
+    Path((template, id)): Path<(String, String)>,
+    user: GoogleUser,
+    storage_manager: Extension<Arc<StorageManager>>,
+    Json(fields): Json<HashMap<String, FieldData>>,
+) -> FormsResponse {
+    match storage_manager
+        .forms_patch(template, id, fields, &user.email)
+        .await
+    {
+        Ok(blob_id) => FormsResponse::EditedBlob(blob_id),
+        Err(e) => FormsResponse::from_error(e, FormsResponse::FailedToEdit),
     }
 }
 
@@ -63,34 +133,275 @@ pub async fn filter_forms(
 ) -> FormsResponse {
     info!("Filter: {:?}", filter);
 
+    match storage_manager.forms_filter(template, filter).await {
+        Ok(l) => FormsResponse::Filtered(l),
+        Err(e) => FormsResponse::from_error(e, FormsResponse::FailedToRead),
+    }
+}
+
+#[instrument(skip(storage_manager))]
+pub async fn forms_by_scouter(
+    Path((template, scouter)): Path<(String, String)>,
+    Query(query): Query<EventQuery>,
+    storage_manager: Extension<Arc<StorageManager>>,
+) -> FormsResponse {
+    let filter = Filter {
+        scouter: Some(scouter),
+        event: query.event,
+        ..Default::default()
+    };
+
     match storage_manager.forms_filter(template, filter).await {
         Ok(l) => FormsResponse::Filtered(l),
         Err(_) => FormsResponse::FailedToRead,
     }
 }
 
+#[instrument(skip(storage_manager))]
+pub async fn list_scouters(
+    Path(template): Path<String>,
+    Query(query): Query<EventQuery>,
+    storage_manager: Extension<Arc<StorageManager>>,
+) -> FormsResponse {
+    match storage_manager.list_scouters(template, query.event).await {
+        Ok(l) => FormsResponse::Scouters(l),
+        Err(_) => FormsResponse::FailedToRead,
+    }
+}
+
+#[instrument(skip(storage_manager, request))]
+pub async fn coverage(
+    Path(template): Path<String>,
+    storage_manager: Extension<Arc<StorageManager>>,
+    Json(request): Json<CoverageRequest>,
+) -> FormsResponse {
+    match storage_manager
+        .coverage(template, request.event, request.matches)
+        .await
+    {
+        Ok(m) => FormsResponse::Missing(m),
+        Err(_) => FormsResponse::FailedToRead,
+    }
+}
+
+#[instrument(skip(storage_manager))]
+pub async fn find_duplicates(
+    Path(template): Path<String>,
+    Query(query): Query<EventQuery>,
+    storage_manager: Extension<Arc<StorageManager>>,
+) -> FormsResponse {
+    match storage_manager
+        .find_duplicate_scouting(template, query.event)
+        .await
+    {
+        Ok(d) => FormsResponse::Duplicates(d),
+        Err(_) => FormsResponse::FailedToRead,
+    }
+}
+
+#[instrument(skip(storage_manager))]
+pub async fn scouter_accuracy(
+    Path((template, scouter)): Path<(String, String)>,
+    storage_manager: Extension<Arc<StorageManager>>,
+) -> FormsResponse {
+    match storage_manager
+        .compute_scouter_accuracy(scouter, template)
+        .await
+    {
+        Ok(s) => FormsResponse::Accuracy(s),
+        Err(_) => FormsResponse::FailedToRead,
+    }
+}
+
+#[instrument(skip(storage_manager))]
+pub async fn search_forms(
+    Path(template): Path<String>,
+    Query(query): Query<SearchQuery>,
+    storage_manager: Extension<Arc<StorageManager>>,
+) -> FormsResponse {
+    match storage_manager
+        .forms_text_search(template, query.field, query.q)
+        .await
+    {
+        Ok(l) => FormsResponse::Filtered(l),
+        Err(e) => FormsResponse::from_error(e, FormsResponse::FailedToRead),
+    }
+}
+
+#[instrument(skip(storage_manager))]
+pub async fn arrow_forms(
+    Path(template): Path<String>,
+    Query(filter): Query<Filter>,
+    storage_manager: Extension<Arc<StorageManager>>,
+) -> FormsResponse {
+    match storage_manager.forms_arrow(template, filter).await {
+        Ok(bytes) => FormsResponse::Arrow(bytes),
+        Err(e) => FormsResponse::from_error(e, FormsResponse::FailedToRead),
+    }
+}
+
+#[instrument(skip(storage_manager))]
+pub async fn parquet_forms(
+    Path(template): Path<String>,
+    Query(filter): Query<Filter>,
+    storage_manager: Extension<Arc<StorageManager>>,
+) -> FormsResponse {
+    match storage_manager.forms_parquet(template, filter).await {
+        Ok(bytes) => FormsResponse::Parquet(bytes),
+        Err(e) => FormsResponse::from_error(e, FormsResponse::FailedToRead),
+    }
+}
+
+#[instrument(skip(storage_manager))]
+pub async fn export_forms(
+    Path(template): Path<String>,
+    storage_manager: Extension<Arc<StorageManager>>,
+) -> FormsResponse {
+    match storage_manager.forms_stream(template).await {
+        Ok(forms) => {
+            FormsResponse::Export(Body::from_stream(forms.map(|form| {
+                form.and_then(|form| Ok(format!("{}\n", serde_json::to_string(&form)?)))
+            })))
+        }
+        Err(_) => FormsResponse::FailedToRead,
+    }
+}
+
 #[instrument(skip(storage_manager))]
 pub async fn delete_form(
     Path((template, name)): Path<(String, String)>,
+    user: GoogleUser,
     storage_manager: Extension<Arc<StorageManager>>,
 ) -> FormsResponse {
-    match storage_manager.forms_delete(template, name).await {
+    match storage_manager
+        .forms_delete(template, name, &user.email)
+        .await
+    {
         Ok(_) => FormsResponse::OK,
         Err(_) => FormsResponse::FailedToDelete,
     }
 }
 
+#[instrument(skip(storage_manager))]
+pub async fn delete_forms(
+    Path(template): Path<String>,
+    Query(filter): Query<Filter>,
+    Query(query): Query<ConfirmQuery>,
+    user: GoogleUser,
+    storage_manager: Extension<Arc<StorageManager>>,
+) -> FormsResponse {
+    if !query.confirm.unwrap_or(false) {
+        return FormsResponse::ConfirmRequired;
+    }
+
+    match storage_manager
+        .forms_delete_by_filter(template, filter, &user.email)
+        .await
+    {
+        Ok(ids) => FormsResponse::IDList(ids),
+        Err(e) => FormsResponse::from_error(e, FormsResponse::FailedToDelete),
+    }
+}
+
+#[instrument(skip(storage_manager))]
+pub async fn restore_forms(
+    Path(template): Path<String>,
+    user: GoogleUser,
+    storage_manager: Extension<Arc<StorageManager>>,
+    Json(filter): Json<Filter>,
+) -> FormsResponse {
+    match storage_manager
+        .restore_by_filter(template, filter, &user.email)
+        .await
+    {
+        Ok(summary) => FormsResponse::Restored(summary),
+        Err(_) => FormsResponse::FailedToEdit,
+    }
+}
+
+#[derive(Debug, Deserialize)]
+pub struct DetailedQuery {
+    detailed: Option<bool>,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct ConfirmQuery {
+    confirm: Option<bool>,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct AddFormQuery {
+    dedupe: Option<bool>,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct EditFormQuery {
+    expected_blob_id: Option<Uuid>,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct CoverageRequest {
+    event: String,
+    matches: Vec<ExpectedMatch>,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct EventQuery {
+    event: Option<String>,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct SearchQuery {
+    field: String,
+    q: String,
+}
+
 #[derive(Debug)]
 pub enum FormsResponse {
     OK,
     ID(String),
+    EditedBlob(Uuid),
     IDList(Vec<String>),
+    Summaries(Vec<FormSummary>),
+    Events(Vec<String>),
+    Scouters(Vec<ScouterActivity>),
+    Accuracy(Scouter),
+    Duplicates(Vec<DuplicateScouting>),
+    Missing(Vec<MissingCoverage>),
+    FieldNotText(String),
+    Invalid(String),
+    ConfirmRequired,
+    TooLarge(String),
+    Restored(RestoreSummary),
     Form(Form),
     Filtered(Vec<Form>),
+    Arrow(Vec<u8>),
+    Parquet(Vec<u8>),
+    Export(Body),
     FailedToAdd,
     FailedToEdit,
     FailedToDelete,
     FailedToRead,
+    UnknownFields(Vec<String>),
+    Conflict(Option<Uuid>),
+}
+
+impl FormsResponse {
+    /// Maps typed storage errors to their own responses, using `fallback` for the rest.
+    fn from_error(error: anyhow::Error, fallback: FormsResponse) -> FormsResponse {
+        match error.downcast::<StorageError>() {
+            Ok(StorageError::UnknownFields(names)) => FormsResponse::UnknownFields(names),
+            Ok(StorageError::VersionConflict(current)) => FormsResponse::Conflict(current),
+            Ok(e @ StorageError::FieldNotText(_)) => FormsResponse::FieldNotText(e.to_string()),
+            Ok(
+                e @ (StorageError::InvalidEventKey(_)
+                | StorageError::TeamOutOfRange { .. }
+                | StorageError::UnknownSortKey(_)),
+            ) => FormsResponse::Invalid(e.to_string()),
+            Ok(e @ StorageError::BatchTooLarge(_)) => FormsResponse::TooLarge(e.to_string()),
+            _ => fallback,
+        }
+    }
 }
 
 impl IntoResponse for FormsResponse {
@@ -101,10 +412,231 @@ impl IntoResponse for FormsResponse {
             FormsResponse::FailedToAdd => StatusCode::BAD_REQUEST.into_response(),
             FormsResponse::FailedToEdit => StatusCode::BAD_REQUEST.into_response(),
             FormsResponse::FailedToDelete => StatusCode::BAD_REQUEST.into_response(),
+            FormsResponse::ConfirmRequired => {
+                (StatusCode::BAD_REQUEST, "pass confirm=true to delete").into_response()
+            }
             FormsResponse::FailedToRead => StatusCode::BAD_REQUEST.into_response(),
+            FormsResponse::TooLarge(message) => {
+                (StatusCode::PAYLOAD_TOO_LARGE, message).into_response()
+            }
+            FormsResponse::FieldNotText(message) | FormsResponse::Invalid(message) => {
+                (StatusCode::UNPROCESSABLE_ENTITY, message).into_response()
+            }
+            FormsResponse::UnknownFields(names) => {
+                (StatusCode::UNPROCESSABLE_ENTITY, Json(names)).into_response()
+            }
+            FormsResponse::Conflict(current) => {
+                (StatusCode::CONFLICT, Json(current)).into_response()
+            }
             FormsResponse::Filtered(l) => (StatusCode::OK, Json(l)).into_response(),
+            FormsResponse::Arrow(bytes) => (
+                StatusCode::OK,
+                [(header::CONTENT_TYPE, "application/vnd.apache.arrow.stream")],
+                bytes,
+            )
+                .into_response(),
+            FormsResponse::Parquet(bytes) => (
+                StatusCode::OK,
+                [(header::CONTENT_TYPE, "application/vnd.apache.parquet")],
+                bytes,
+            )
+                .into_response(),
+            FormsResponse::Export(body) => (
+                StatusCode::OK,
+                [(header::CONTENT_TYPE, "application/x-ndjson")],
+                body,
+            )
+                .into_response(),
             FormsResponse::ID(id) => (StatusCode::OK, Json(id)).into_response(),
-            FormsResponse::IDList(ids) => (StatusCode::OK, Json(ids)).into_response()
+            FormsResponse::IDList(ids) => (StatusCode::OK, Json(ids)).into_response(),
+            FormsResponse::Summaries(l) => (StatusCode::OK, Json(l)).into_response(),
+            FormsResponse::EditedBlob(id) => (StatusCode::OK, Json(id)).into_response(),
+            FormsResponse::Restored(summary) => (StatusCode::OK, Json(summary)).into_response(),
+            FormsResponse::Events(events) => (StatusCode::OK, Json(events)).into_response(),
+            FormsResponse::Scouters(scouters) => (StatusCode::OK, Json(scouters)).into_response(),
+            FormsResponse::Accuracy(scouter) => (StatusCode::OK, Json(scouter)).into_response(),
+            FormsResponse::Duplicates(groups) => (StatusCode::OK, Json(groups)).into_response(),
+            FormsResponse::Missing(missing) => (StatusCode::OK, Json(missing)).into_response(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::storage_manager::tests::{form, scores_template, storage};
+    use crate::transactions::DataType;
+    use sha256::Sha256Digest;
+
+    fn lead() -> GoogleUser {
+        GoogleUser {
+            id: "1".into(),
+            email: "lead@example.com".into(),
+            verified_email: true,
+            picture: String::new(),
+            hd: None,
+        }
+    }
+
+    #[tokio::test]
+    async fn deletes_matching_more_than_the_item_limit_are_rejected() {
+        let (_dir, mut storage_manager) = storage().await;
+        storage_manager.batch_limits.max_items = 1;
+        storage_manager
+            .templates_add(scores_template(), "lead@example.com")
+            .await
+            .unwrap();
+        for match_number in [1, 2] {
+            storage_manager
+                .forms_add(
+                    "scores".into(),
+                    form(5907, match_number, "ada", 10),
+                    false,
+                    "lead@example.com",
+                )
+                .await
+                .unwrap();
         }
+        let storage_manager = Arc::new(storage_manager);
+
+        let response = delete_forms(
+            Path("scores".into()),
+            Query(Filter::default()),
+            Query(ConfirmQuery {
+                confirm: Some(true),
+            }),
+            lead(),
+            Extension(storage_manager.clone()),
+        )
+        .await
+        .into_response();
+
+        assert_eq!(response.status(), StatusCode::PAYLOAD_TOO_LARGE);
+        assert_eq!(
+            storage_manager
+                .forms_list("scores".into())
+                .await
+                .unwrap()
+                .len(),
+            2
+        );
+    }
+
+    #[tokio::test]
+    async fn filtered_deletes_need_confirmation_and_keep_other_forms() {
+        let (_dir, storage_manager) = storage().await;
+        storage_manager
+            .templates_add(scores_template(), "lead@example.com")
+            .await
+            .unwrap();
+        let mut ids = vec![];
+        for (team, match_number) in [(5907, 1), (254, 1), (5907, 2)] {
+            ids.push(
+                storage_manager
+                    .forms_add(
+                        "scores".into(),
+                        form(team, match_number, "ada", 10),
+                        false,
+                        "lead@example.com",
+                    )
+                    .await
+                    .unwrap(),
+            );
+        }
+        let storage_manager = Extension(Arc::new(storage_manager));
+        let delete = |confirm| {
+            delete_forms(
+                Path("scores".into()),
+                Query(Filter {
+                    team: Some(5907),
+                    ..Default::default()
+                }),
+                Query(ConfirmQuery { confirm }),
+                lead(),
+                storage_manager.clone(),
+            )
+        };
+        let remaining = || async {
+            let mut ids = storage_manager.forms_list("scores".into()).await.unwrap();
+            ids.sort();
+            ids
+        };
+
+        for confirm in [None, Some(false)] {
+            assert_eq!(
+                delete(confirm).await.into_response().status(),
+                StatusCode::BAD_REQUEST
+            );
+        }
+        assert_eq!(remaining().await.len(), 3);
+
+        assert_eq!(
+            delete(Some(true)).await.into_response().status(),
+            StatusCode::OK
+        );
+        assert_eq!(remaining().await, vec![ids[1].clone()]);
+    }
+
+    #[tokio::test]
+    async fn edits_against_a_stale_blob_id_conflict() {
+        let (_dir, storage_manager) = storage().await;
+        storage_manager
+            .templates_add(scores_template(), "lead@example.com")
+            .await
+            .unwrap();
+        let id = storage_manager
+            .forms_add(
+                "scores".into(),
+                form(5907, 1, "ada", 10),
+                false,
+                "lead@example.com",
+            )
+            .await
+            .unwrap();
+        let storage_manager = Extension(Arc::new(storage_manager));
+        let edit = |expected_blob_id, score| {
+            edit_form(
+                Path(("scores".into(), id.clone())),
+                Query(EditFormQuery { expected_blob_id }),
+                lead(),
+                storage_manager.clone(),
+                Json(form(5907, 1, "ada", score)),
+            )
+        };
+        let read = storage_manager
+            .latest_blob_from_alt_key(DataType::Form("scores".into()), &(&id).digest())
+            .await
+            .unwrap();
+
+        // another lead saves first
+        assert_eq!(
+            edit(read, 20).await.into_response().status(),
+            StatusCode::OK
+        );
+
+        let stale = edit(read, 30).await.into_response();
+        assert_eq!(stale.status(), StatusCode::CONFLICT);
+        let form = storage_manager
+            .forms_get("scores".into(), id.clone())
+            .await
+            .unwrap();
+        assert!(matches!(
+            form.get_field("score"),
+            Some(FieldData::Number(20))
+        ));
+
+        // a delete moves the form on too
+        let read = storage_manager
+            .latest_blob_from_alt_key(DataType::Form("scores".into()), &(&id).digest())
+            .await
+            .unwrap();
+        storage_manager
+            .forms_delete("scores".into(), id.clone(), "lead@example.com")
+            .await
+            .unwrap();
+        assert_eq!(
+            edit(read, 40).await.into_response().status(),
+            StatusCode::CONFLICT
+        );
     }
 }
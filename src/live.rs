@@ -0,0 +1,221 @@
+use crate::storage_manager::StorageManager;
+use crate::transactions::{DataType, InternalMessage};
+use axum::extract::ws::{Message, WebSocket, WebSocketUpgrade};
+use axum::extract::{Path, Query};
+use axum::response::sse::{Event, KeepAlive, Sse};
+use axum::response::Response;
+use axum::Extension;
+use futures::{stream, Sink, SinkExt, Stream, StreamExt};
+use serde::Deserialize;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::sync::broadcast;
+use tokio::sync::broadcast::error::RecvError;
+use tracing::{instrument, warn};
+
+/// Upgrades to a websocket that receives every transaction for the template's
+/// forms as JSON, as it is written.
+#[instrument(skip(ws, storage_manager))]
+pub async fn form_updates(
+    Path(template): Path<String>,
+    ws: WebSocketUpgrade,
+    storage_manager: Extension<Arc<StorageManager>>,
+) -> Response {
+    let receiver = storage_manager.subscribe();
+
+    ws.on_upgrade(move |socket: WebSocket| {
+        let (outgoing, incoming) = socket.split();
+
+        push_form_updates(outgoing, incoming, receiver, DataType::Form(template))
+    })
+}
+
+/// Sends matching transactions until the client closes the socket or goes
+/// away. The socket is read alongside, so pings are answered and a close is
+/// noticed even while no transactions arrive.
+async fn push_form_updates(
+    mut outgoing: impl Sink<Message> + Unpin,
+    mut incoming: impl Stream<Item = Result<Message, axum::Error>> + Unpin,
+    mut receiver: broadcast::Receiver<InternalMessage>,
+    data_type: DataType,
+) {
+    loop {
+        let transaction = tokio::select! {
+            message = incoming.next() => match message {
+                Some(Ok(Message::Ping(payload))) => {
+                    if outgoing.send(Message::Pong(payload)).await.is_err() {
+                        return;
+                    }
+                    continue;
+                }
+                Some(Ok(Message::Close(_))) | Some(Err(_)) | None => return,
+                Some(Ok(_)) => continue,
+            },
+            received = receiver.recv() => match received {
+                Ok(transaction) => transaction,
+                Err(RecvError::Lagged(skipped)) => {
+                    warn!("websocket subscriber skipped {skipped} transactions");
+                    continue;
+                }
+                Err(RecvError::Closed) => return,
+            },
+        };
+
+        if transaction.data_type != data_type {
+            continue;
+        }
+
+        let Ok(json) = serde_json::to_string(&transaction) else {
+            continue;
+        };
+
+        // the client went away
+        if outgoing.send(Message::Text(json)).await.is_err() {
+            return;
+        }
+    }
+}
+
+/// Streams every transaction written from now on as server-sent events,
+/// optionally only those of one data type.
+#[instrument(skip(storage_manager))]
+pub async fn transaction_stream(
+    Query(query): Query<StreamQuery>,
+    storage_manager: Extension<Arc<StorageManager>>,
+) -> Sse<impl Stream<Item = Result<Event, axum::Error>>> {
+    let receiver = storage_manager.subscribe();
+
+    let events = stream::unfold(receiver, move |mut receiver| {
+        let query = query.clone();
+
+        async move {
+            loop {
+                let transaction = match receiver.recv().await {
+                    Ok(transaction) => transaction,
+                    Err(RecvError::Lagged(skipped)) => {
+                        warn!("event stream subscriber skipped {skipped} transactions");
+                        continue;
+                    }
+                    Err(RecvError::Closed) => return None,
+                };
+
+                if query.matches(&transaction.data_type) {
+                    return Some((Event::default().json_data(&transaction), receiver));
+                }
+            }
+        }
+    });
+
+    Sse::new(events).keep_alive(
+        KeepAlive::new()
+            .interval(Duration::from_secs(15))
+            .text("keep-alive"),
+    )
+}
+
+#[derive(Debug, Deserialize, Clone)]
+pub struct StreamQuery {
+    /// One of `Bytes`, `Form`, `Schedule` or `Template`.
+    data_type: Option<String>,
+    /// Narrows `Form` to a single template.
+    template: Option<String>,
+}
+
+impl StreamQuery {
+    fn matches(&self, data_type: &DataType) -> bool {
+        let kind = match data_type {
+            DataType::Bytes => "Bytes",
+            DataType::Form(_) => "Form",
+            DataType::Schedule => "Schedule",
+            DataType::Template => "Template",
+        };
+
+        let kind_matches = self
+            .data_type
+            .as_ref()
+            .is_none_or(|wanted| wanted.eq_ignore_ascii_case(kind));
+
+        let template_matches = match (&self.template, data_type) {
+            (Some(wanted), DataType::Form(template)) => wanted == template,
+            (Some(_), _) => false,
+            (None, _) => true,
+        };
+
+        kind_matches && template_matches
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::transactions::Action;
+    use futures::channel::mpsc;
+
+    struct Client {
+        to_server: mpsc::UnboundedSender<Result<Message, axum::Error>>,
+        from_server: mpsc::UnboundedReceiver<Message>,
+        transactions: broadcast::Sender<InternalMessage>,
+        server: tokio::task::JoinHandle<()>,
+    }
+
+    fn connect() -> Client {
+        let (to_server, incoming) = mpsc::unbounded();
+        let (outgoing, from_server) = mpsc::unbounded();
+        let (transactions, receiver) = broadcast::channel(16);
+        let server = tokio::spawn(push_form_updates(
+            outgoing,
+            incoming,
+            receiver,
+            DataType::Form("scores".into()),
+        ));
+
+        Client {
+            to_server,
+            from_server,
+            transactions,
+            server,
+        }
+    }
+
+    #[tokio::test]
+    async fn pings_are_answered_between_updates() {
+        let mut client = connect();
+
+        client
+            .to_server
+            .unbounded_send(Ok(Message::Ping(b"are you there".to_vec())))
+            .unwrap();
+        assert!(matches!(
+            client.from_server.next().await,
+            Some(Message::Pong(payload)) if payload == b"are you there"
+        ));
+
+        let transaction = InternalMessage::new(
+            DataType::Form("scores".into()),
+            Action::Add,
+            "form.current".into(),
+            "a@example.com",
+        );
+        client.transactions.send(transaction.clone()).unwrap();
+        assert!(matches!(
+            client.from_server.next().await,
+            Some(Message::Text(json)) if json.contains(&transaction.id.to_string())
+        ));
+    }
+
+    #[tokio::test]
+    async fn closing_the_socket_ends_the_updates() {
+        let client = connect();
+
+        client
+            .to_server
+            .unbounded_send(Ok(Message::Close(None)))
+            .unwrap();
+
+        // returns without a transaction ever being sent
+        tokio::time::timeout(Duration::from_secs(5), client.server)
+            .await
+            .unwrap()
+            .unwrap();
+    }
+}
@@ -1,12 +1,14 @@
 use crate::datatypes::ItemPath;
 use crate::storage_manager::StorageManager;
-use auth::{GoogleAuthenticator, GoogleUser, JwtManagerBuilder};
+use auth::{GoogleAuthenticator, GoogleUser, JwtManagerBuilder, Roles, WriteAccess};
 use axum::body::Body;
-use axum::http::Method;
+use axum::extract::DefaultBodyLimit;
+use axum::http::{header, HeaderName, HeaderValue, Method};
 use axum::middleware::from_extractor;
 use axum::response::{IntoResponse, Response};
 use axum::Extension;
 use axum_server::tls_rustls::RustlsConfig;
+use axum_server::Handle;
 use jwt_simple::prelude::*;
 use opentelemetry::KeyValue;
 use opentelemetry_otlp::WithExportConfig;
@@ -14,27 +16,35 @@ use opentelemetry_sdk::trace::{RandomIdGenerator, Sampler};
 use opentelemetry_sdk::{trace, Resource};
 use std::sync::Arc;
 use std::time::Duration;
-use axum::extract::DefaultBodyLimit;
 use tower::ServiceBuilder;
 use tower_http::compression::CompressionLayer;
-use tower_http::cors::{Any, CorsLayer};
+use tower_http::cors::CorsLayer;
 use tower_http::trace::TraceLayer;
-use tracing::{info, instrument};
+use tracing::{info, instrument, warn};
 use tracing_subscriber::layer::SubscriberExt;
 use tracing_subscriber::util::SubscriberInitExt;
 
+mod admin;
 mod auth;
 mod bytes;
+mod dashboard;
 mod datatypes;
 mod forms;
+mod live;
 mod misc;
+mod patch;
+mod query;
 mod schedules;
+mod stats;
 mod storage_manager;
 mod sync;
 mod templates;
 mod transactions;
 
+const KILOBYTE: usize = 1024;
 const GIGABYTE: usize = 1024 * 1024 * 1024;
+/// How long in-flight requests get to finish once shutdown starts.
+const SHUTDOWN_GRACE: Duration = Duration::from_secs(30);
 
 #[instrument(ret)]
 async fn handler(user_info: GoogleUser) -> Result<ApiResponse, ApiError> {
@@ -71,6 +81,79 @@ struct TlsConfig {
     application_bind: String,
 }
 
+#[derive(Deserialize)]
+#[serde(default)]
+struct TelemetryConfig {
+    /// OTLP collector to export traces to. Traces are only logged when unset.
+    otlp_endpoint: Option<String>,
+    timeout_secs: u64,
+    /// Fraction of traces to sample, from 0.0 to 1.0.
+    sampler_ratio: f64,
+    service_name: String,
+}
+
+impl Default for TelemetryConfig {
+    fn default() -> Self {
+        Self {
+            otlp_endpoint: None,
+            timeout_secs: 3,
+            sampler_ratio: 1.0,
+            service_name: "scouting-api".into(),
+        }
+    }
+}
+
+#[derive(Deserialize, Default)]
+#[serde(default)]
+struct CorsConfig {
+    /// Origins allowed to make credentialed requests, e.g. `https://scout.example.com`.
+    allowed_origins: Vec<String>,
+    /// Allow any origin when `allowed_origins` is empty. Only for development.
+    dev: bool,
+}
+
+impl CorsConfig {
+    fn layer(&self) -> CorsLayer {
+        if self.allowed_origins.is_empty() && self.dev {
+            warn!("no CORS origins configured, allowing any origin");
+            return CorsLayer::very_permissive();
+        }
+
+        let origins: Vec<HeaderValue> = self
+            .allowed_origins
+            .iter()
+            .filter_map(|origin| match origin.parse() {
+                Ok(origin) => Some(origin),
+                Err(_) => {
+                    warn!("ignoring invalid CORS origin {origin}");
+                    None
+                }
+            })
+            .collect();
+
+        CorsLayer::new()
+            .allow_origin(origins)
+            .allow_credentials(true)
+            .allow_methods([
+                Method::GET,
+                Method::HEAD,
+                Method::POST,
+                Method::PUT,
+                Method::PATCH,
+                Method::DELETE,
+            ])
+            .allow_headers([
+                header::AUTHORIZATION,
+                header::CONTENT_TYPE,
+                header::ACCEPT,
+                header::ACCEPT_ENCODING,
+                header::IF_MODIFIED_SINCE,
+                header::RANGE,
+                HeaderName::from_static("idempotency-key"),
+            ])
+    }
+}
+
 #[tokio::main]
 async fn main() {
     let settings = config::Config::builder()
@@ -80,34 +163,117 @@ async fn main() {
 
     let tls_config = settings.get::<TlsConfig>("tls_config").unwrap();
 
-    let storage_manager = settings.get::<StorageManager>("storage_manager").unwrap();
+    let telemetry = settings
+        .get::<TelemetryConfig>("telemetry")
+        .unwrap_or_default();
+
+    // before anything that logs, so startup messages are not dropped
+    setup_tracing(&telemetry);
+
+    // set up metrics for adding into the application. This installs the global
+    // meter provider, which the storage manager's counters are built from.
+    let metrics = axum_otel_metrics::HttpMetricsLayerBuilder::new().build();
+    // get the /metrics endpoint for publishing
+    let metrics_routes = metrics.routes();
+
+    let storage_manager = Arc::new(settings.get::<StorageManager>("storage_manager").unwrap());
+
+    storage_manager
+        .migrate()
+        .await
+        .expect("Could not migrate storage");
+
+    let imported = storage_manager
+        .import_templates()
+        .await
+        .expect("Could not import templates");
+
+    if imported > 0 {
+        info!("imported {imported} templates");
+    }
 
     let google_authenticator = settings
         .get::<GoogleAuthenticator>("authenticator")
         .unwrap();
 
+    google_authenticator
+        .load_state()
+        .await
+        .expect("Could not load login state");
+    let google_authenticator = Arc::new(google_authenticator);
+
     let jwt_manager = settings
         .get::<JwtManagerBuilder>("jwt_manager")
         .unwrap()
         .build();
 
-    let max_bytes = settings
-        .get::<usize>("max_upload")
-        .unwrap_or(GIGABYTE * 5);
+    let max_bytes = settings.get::<usize>("max_upload").unwrap_or(GIGABYTE * 5);
+    let max_form_bytes = settings
+        .get::<usize>("max_form_upload")
+        .unwrap_or(KILOBYTE * 256);
 
-    setup_tracing();
-    // set up metrics for adding into the application
-    let metrics = axum_otel_metrics::HttpMetricsLayerBuilder::new().build();
-    // get the /metrics endpoint for publishing
-    let metrics_routes = metrics.routes();
+    let cors = settings.get::<CorsConfig>("cors").unwrap_or_default();
+    let roles = settings.get::<Roles>("roles").unwrap_or_default();
+
+    let gc_storage_manager = storage_manager.clone();
+    tokio::spawn(async move {
+        let mut interval = tokio::time::interval(Duration::from_secs(
+            gc_storage_manager.retention.interval_secs,
+        ));
+
+        loop {
+            interval.tick().await;
+
+            match gc_storage_manager.gc_blobs().await {
+                Ok(removed) => info!("gc removed {removed} expired items"),
+                Err(e) => warn!("gc failed: {e}"),
+            }
+        }
+    });
+
+    let upload_storage_manager = storage_manager.clone();
+    tokio::spawn(async move {
+        let mut interval = tokio::time::interval(Duration::from_secs(
+            upload_storage_manager.uploads.prune_interval_secs,
+        ));
+
+        loop {
+            interval.tick().await;
+
+            match upload_storage_manager.prune_uploads().await {
+                Ok(removed) => info!("pruned {removed} expired uploads"),
+                Err(e) => warn!("upload pruning failed: {e}"),
+            }
+        }
+    });
+
+    let sweep_authenticator = google_authenticator.clone();
+    tokio::spawn(async move {
+        let mut interval = tokio::time::interval(sweep_authenticator.sweep_interval());
+
+        loop {
+            interval.tick().await;
+
+            let removed = sweep_authenticator.sweep_expired().await;
+
+            if removed > 0 {
+                info!("swept {removed} expired login entries");
+            }
+        }
+    });
 
     // set up the routes and middleware
     let router = axum::Router::new()
         .route("/protected/age/*path", axum::routing::get(misc::age))
         .route("/protected", axum::routing::get(handler))
         .route("/protected/code", axum::routing::get(auth::auth_code))
+        .route("/protected/me", axum::routing::get(auth::me))
         //bytes
         .route("/protected/bytes/", axum::routing::get(bytes::list_bytes))
+        .route(
+            "/protected/bytes/",
+            axum::routing::post(bytes::store_bytes_cas),
+        )
         .route(
             "/protected/bytes/:blob_id",
             axum::routing::post(bytes::store_bytes),
@@ -116,6 +282,10 @@ async fn main() {
             "/protected/bytes/:blob_id",
             axum::routing::get(bytes::get_bytes),
         )
+        .route(
+            "/protected/bytes/:blob_id",
+            axum::routing::head(bytes::head_bytes),
+        )
         .route(
             "/protected/bytes/:blob_id",
             axum::routing::delete(bytes::delete_bytes),
@@ -124,6 +294,18 @@ async fn main() {
             "/protected/bytes/:blob_id",
             axum::routing::patch(bytes::edit_bytes),
         )
+        .route(
+            "/protected/bytes/:blob_id/upload",
+            axum::routing::post(bytes::start_upload),
+        )
+        .route(
+            "/protected/bytes/:blob_id/upload",
+            axum::routing::patch(bytes::append_upload),
+        )
+        .route(
+            "/protected/bytes/:blob_id/upload/finalize",
+            axum::routing::post(bytes::finalize_upload),
+        )
         //templates
         .route(
             "/protected/templates/",
@@ -133,10 +315,22 @@ async fn main() {
             "/protected/template/:template",
             axum::routing::get(templates::get_template),
         )
+        .route(
+            "/protected/template/:template/schema",
+            axum::routing::get(templates::get_template_schema),
+        )
+        .route(
+            "/protected/template/:template/defaults",
+            axum::routing::get(templates::get_template_defaults),
+        )
         .route(
             "/protected/template/",
             axum::routing::patch(templates::edit_template),
         )
+        .route(
+            "/protected/template/:template",
+            axum::routing::patch(templates::patch_template),
+        )
         .route(
             "/protected/template/:template",
             axum::routing::delete(templates::delete_template),
@@ -171,17 +365,77 @@ async fn main() {
             "/protected/forms/:template/ids",
             axum::routing::get(forms::list_forms),
         )
+        .route(
+            "/protected/forms/:template/search",
+            axum::routing::get(forms::search_forms),
+        )
+        .route(
+            "/protected/forms/:template/duplicates",
+            axum::routing::get(forms::find_duplicates),
+        )
+        .route(
+            "/protected/forms/:template/coverage",
+            axum::routing::post(forms::coverage),
+        )
+        .route(
+            "/protected/events/:template",
+            axum::routing::get(forms::list_events),
+        )
+        .route(
+            "/protected/ws/:template",
+            axum::routing::get(live::form_updates),
+        )
+        .route(
+            "/protected/events/stream",
+            axum::routing::get(live::transaction_stream),
+        )
+        .route(
+            "/protected/scouters/:template",
+            axum::routing::get(forms::list_scouters),
+        )
+        .route(
+            "/protected/scouters/:template/:scouter/accuracy",
+            axum::routing::get(forms::scouter_accuracy),
+        )
         .route(
             "/protected/forms/:template/",
             axum::routing::get(forms::filter_forms),
         )
+        .route(
+            "/protected/forms/:template/",
+            axum::routing::delete(forms::delete_forms),
+        )
+        .route(
+            "/protected/forms/:template/restore",
+            axum::routing::post(forms::restore_forms),
+        )
+        .route(
+            "/protected/forms/:template/by-scouter/:scouter",
+            axum::routing::get(forms::forms_by_scouter),
+        )
+        .route(
+            "/protected/forms/:template/arrow",
+            axum::routing::get(forms::arrow_forms),
+        )
+        .route(
+            "/protected/forms/:template/export.parquet",
+            axum::routing::get(forms::parquet_forms),
+        )
+        .route(
+            "/protected/forms/:template/export.ndjson",
+            axum::routing::get(forms::export_forms),
+        )
         .route(
             "/protected/form/:template/:id",
             axum::routing::get(forms::get_form),
         )
         .route(
             "/protected/form/:template/:id",
-            axum::routing::patch(forms::edit_form),
+            axum::routing::patch(forms::edit_form).layer(DefaultBodyLimit::max(max_form_bytes)),
+        )
+        .route(
+            "/protected/form/:template/:id/fields",
+            axum::routing::patch(forms::patch_form).layer(DefaultBodyLimit::max(max_form_bytes)),
         )
         .route(
             "/protected/form/:template/:id",
@@ -189,24 +443,81 @@ async fn main() {
         )
         .route(
             "/protected/form/:template",
-            axum::routing::post(forms::add_form),
+            axum::routing::post(forms::add_form).layer(DefaultBodyLimit::max(max_form_bytes)),
         )
+        //dashboard
+        .route(
+            "/protected/dashboard/:template",
+            axum::routing::get(dashboard::dashboard),
+        )
+        //stats
+        .route(
+            "/protected/stats/:template/team/:team/:field",
+            axum::routing::get(stats::team_field_stats),
+        )
+        .route(
+            "/protected/compare/:template/:team",
+            axum::routing::get(stats::compare_events),
+        )
+        .route(
+            "/protected/query/:template",
+            axum::routing::post(query::query_forms),
+        )
+        //admin
+        .route(
+            "/protected/admin/import",
+            axum::routing::post(admin::import).layer(DefaultBodyLimit::max(
+                storage_manager.batch_limits.max_bytes,
+            )),
+        )
+        .route("/protected/audit", axum::routing::get(admin::audit))
+        .route(
+            "/protected/audit/:alt_key",
+            axum::routing::get(admin::history),
+        )
+        .route("/protected/admin/verify", axum::routing::get(admin::verify))
+        .route("/protected/admin/stats", axum::routing::get(admin::stats))
         //sync
+        .route("/protected/sync/log", axum::routing::get(sync::log))
+        .route(
+            "/protected/sync/needed",
+            axum::routing::get(sync::needed_blobs),
+        )
+        .route(
+            "/protected/sync/blob/:id",
+            axum::routing::get(sync::pull_blob).post(sync::push_blob),
+        )
+        .route(
+            "/protected/sync/since/:micros",
+            axum::routing::get(sync::since),
+        )
+        .route(
+            "/protected/sync/conflicts",
+            axum::routing::get(sync::conflicts),
+        )
+        .route(
+            "/protected/sync/diff-summary",
+            axum::routing::get(sync::diff_summary),
+        )
         .route("/protected/sync/:last_id", axum::routing::get(sync::sync))
         .layer(from_extractor::<GoogleUser>())
+        .layer(from_extractor::<WriteAccess>())
         .layer(from_extractor::<ItemPath>())
         .route("/", axum::routing::get(auth::login_handler))
+        .route("/health", axum::routing::get(misc::health))
         .route(
             "/auth/:code/:email",
             axum::routing::get(auth::get_jwt_cache_from_code),
         )
-        .layer(CorsLayer::very_permissive())
+        .route("/auth/refresh", axum::routing::post(auth::refresh))
+        .layer(cors.layer())
         .layer(DefaultBodyLimit::max(max_bytes))
         .layer(
             ServiceBuilder::new()
-                .layer(Extension(Arc::new(google_authenticator)))
-                .layer(Extension(Arc::new(storage_manager)))
+                .layer(Extension(google_authenticator))
+                .layer(Extension(storage_manager))
                 .layer(Extension(Arc::new(jwt_manager)))
+                .layer(Extension(Arc::new(roles)))
                 .layer(metrics)
                 .layer(CompressionLayer::new())
                 .layer(TraceLayer::new_for_http()),
@@ -216,47 +527,67 @@ async fn main() {
     let ssl_config = RustlsConfig::from_pem_file(tls_config.cert_path, tls_config.key_path)
         .await
         .expect("Could not get ssl cert");
+    let application_handle = Handle::new();
+    let metrics_handle = Handle::new();
+
+    // On ctrl-c stop accepting connections on both servers and let in-flight requests drain
+    let (app_shutdown, metrics_shutdown) = (application_handle.clone(), metrics_handle.clone());
     tokio::spawn(async move {
-        axum_server::bind_rustls(tls_config.application_bind.parse().unwrap(), ssl_config)
-            .serve(router.into_make_service())
+        tokio::signal::ctrl_c()
             .await
-            .unwrap()
+            .expect("Could not listen for shutdown signal");
+        info!("shutting down");
+        app_shutdown.graceful_shutdown(Some(SHUTDOWN_GRACE));
+        metrics_shutdown.graceful_shutdown(Some(SHUTDOWN_GRACE));
     });
 
+    let application =
+        axum_server::bind_rustls(tls_config.application_bind.parse().unwrap(), ssl_config)
+            .handle(application_handle)
+            .serve(router.into_make_service());
+
     // Metrics endpoint should be published on a non-TLS port separately
-    axum_server::bind(tls_config.metrics_bind.parse().unwrap())
-        .serve(metrics_routes.into_make_service())
-        .await
-        .unwrap();
+    let metrics = axum_server::bind(tls_config.metrics_bind.parse().unwrap())
+        .handle(metrics_handle)
+        .serve(metrics_routes.into_make_service());
+
+    let (application, metrics) = tokio::join!(application, metrics);
+    application.unwrap();
+    metrics.unwrap();
+
+    // flush any spans still buffered in the batch exporter
+    opentelemetry::global::shutdown_tracer_provider();
 }
 
-fn setup_tracing() {
-    let tracer = opentelemetry_otlp::new_pipeline()
-        .tracing()
-        .with_exporter(
-            opentelemetry_otlp::new_exporter()
-                .tonic()
-                .with_endpoint("http://localhost:4317") // grafana agent endpoint
-                .with_timeout(Duration::from_secs(3)),
-        )
-        .with_trace_config(
-            trace::config()
-                .with_sampler(Sampler::AlwaysOn) // this should be changed in high throughput settings
-                .with_id_generator(RandomIdGenerator::default())
-                .with_max_events_per_span(64)
-                .with_max_attributes_per_span(16)
-                .with_max_events_per_span(16)
-                .with_resource(Resource::new(vec![KeyValue::new(
-                    "service.name",
-                    "example", // what the service name the metrics and traces are attached to
-                )])),
-        )
-        .install_batch(opentelemetry_sdk::runtime::Tokio)
-        .unwrap();
+fn setup_tracing(telemetry: &TelemetryConfig) {
+    let tracer = telemetry.otlp_endpoint.as_ref().map(|endpoint| {
+        opentelemetry_otlp::new_pipeline()
+            .tracing()
+            .with_exporter(
+                opentelemetry_otlp::new_exporter()
+                    .tonic()
+                    .with_endpoint(endpoint)
+                    .with_timeout(Duration::from_secs(telemetry.timeout_secs)),
+            )
+            .with_trace_config(
+                trace::config()
+                    .with_sampler(Sampler::TraceIdRatioBased(telemetry.sampler_ratio))
+                    .with_id_generator(RandomIdGenerator::default())
+                    .with_max_events_per_span(64)
+                    .with_max_attributes_per_span(16)
+                    .with_max_events_per_span(16)
+                    .with_resource(Resource::new(vec![KeyValue::new(
+                        "service.name",
+                        telemetry.service_name.clone(),
+                    )])),
+            )
+            .install_batch(opentelemetry_sdk::runtime::Tokio)
+            .unwrap()
+    });
     tracing_subscriber::registry()
         .with(tracing_subscriber::EnvFilter::new("tower_http=trace,info")) // logging levels
         .with(tracing_subscriber::fmt::layer())
-        //.with(tracing_opentelemetry::layer().with_tracer(tracer))
+        .with(tracer.map(|tracer| tracing_opentelemetry::layer().with_tracer(tracer)))
         .try_init()
         .unwrap();
 }
@@ -8,9 +8,7 @@ use chrono::{Duration, Utc};
 use serde::{Deserialize, Serialize};
 use std::sync::Arc;
 use std::time::UNIX_EPOCH;
-use tokio::fs;
 use tokio::fs::metadata;
-use tokio::time::Instant;
 use tracing::instrument;
 
 #[instrument(ret, skip(storage_manager))]
@@ -45,6 +43,17 @@ pub async fn age(
     }
 }
 
+#[instrument(skip(storage_manager))]
+pub async fn health(storage_manager: Extension<Arc<StorageManager>>) -> impl IntoResponse {
+    match storage_manager.health_check().await {
+        Ok(_) => (StatusCode::OK, Json(serde_json::json!({ "status": "ok" }))),
+        Err(e) => (
+            StatusCode::SERVICE_UNAVAILABLE,
+            Json(serde_json::json!({ "status": "unavailable", "reason": e.to_string() })),
+        ),
+    }
+}
+
 #[derive(Debug, Serialize, Deserialize)]
 pub struct AgeQuery {
     #[serde(alias = "type")]
@@ -58,3 +67,53 @@ pub enum Format {
     #[serde(alias = "timestamp")]
     Timestamp,
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::storage_manager::tests::storage;
+
+    async fn health_status(storage_manager: StorageManager) -> StatusCode {
+        health(Extension(Arc::new(storage_manager)))
+            .await
+            .into_response()
+            .status()
+    }
+
+    #[tokio::test]
+    async fn health_is_ok_on_working_storage() {
+        let (_dir, storage_manager) = storage().await;
+        storage_manager
+            .bytes_add("key".into(), b"data", "a@example.com")
+            .await
+            .unwrap();
+
+        assert_eq!(health_status(storage_manager).await, StatusCode::OK);
+    }
+
+    #[tokio::test]
+    async fn health_is_unavailable_when_the_log_cannot_be_opened() {
+        let (dir, storage_manager) = storage().await;
+        let log = dir.path().join("transactions.log");
+        let _ = tokio::fs::remove_file(&log).await;
+        tokio::fs::create_dir(&log).await.unwrap();
+
+        assert_eq!(
+            health_status(storage_manager).await,
+            StatusCode::SERVICE_UNAVAILABLE
+        );
+    }
+
+    #[tokio::test]
+    async fn health_is_unavailable_when_bytes_cannot_be_written() {
+        let (_dir, storage_manager) = storage().await;
+        tokio::fs::remove_dir_all(format!("{}bytes", storage_manager.get_path()))
+            .await
+            .unwrap();
+
+        assert_eq!(
+            health_status(storage_manager).await,
+            StatusCode::SERVICE_UNAVAILABLE
+        );
+    }
+}
@@ -0,0 +1,179 @@
+use serde::Deserialize;
+use serde_json::Value;
+
+/// One RFC 6902 JSON Patch operation. Paths are RFC 6901 JSON Pointers.
+#[derive(Debug, Deserialize, Clone)]
+#[serde(tag = "op", rename_all = "lowercase")]
+pub enum PatchOperation {
+    Add { path: String, value: Value },
+    Remove { path: String },
+    Replace { path: String, value: Value },
+    Move { from: String, path: String },
+    Copy { from: String, path: String },
+    Test { path: String, value: Value },
+}
+
+/// Applies the operations in order. The document is left untouched unless
+/// every operation succeeds.
+pub fn apply(doc: &mut Value, operations: &[PatchOperation]) -> Result<(), String> {
+    let mut patched = doc.clone();
+
+    for operation in operations {
+        match operation {
+            PatchOperation::Add { path, value } => add(&mut patched, path, value.clone())?,
+            PatchOperation::Remove { path } => {
+                remove(&mut patched, path)?;
+            }
+            PatchOperation::Replace { path, value } => {
+                *patched
+                    .pointer_mut(path)
+                    .ok_or_else(|| format!("nothing at {path}"))? = value.clone();
+            }
+            PatchOperation::Move { from, path } => {
+                let value = remove(&mut patched, from)?;
+                add(&mut patched, path, value)?;
+            }
+            PatchOperation::Copy { from, path } => {
+                let value = patched
+                    .pointer(from)
+                    .ok_or_else(|| format!("nothing at {from}"))?
+                    .clone();
+                add(&mut patched, path, value)?;
+            }
+            PatchOperation::Test { path, value } => {
+                if patched.pointer(path) != Some(value) {
+                    return Err(format!("test failed at {path}"));
+                }
+            }
+        }
+    }
+
+    *doc = patched;
+
+    Ok(())
+}
+
+/// Splits a pointer into its parent pointer and unescaped last token.
+fn split(path: &str) -> Result<(&str, String), String> {
+    let (parent, last) = path
+        .rsplit_once('/')
+        .ok_or_else(|| format!("invalid pointer {path}"))?;
+
+    Ok((parent, last.replace("~1", "/").replace("~0", "~")))
+}
+
+fn add(doc: &mut Value, path: &str, value: Value) -> Result<(), String> {
+    if path.is_empty() {
+        *doc = value;
+        return Ok(());
+    }
+
+    let (parent, token) = split(path)?;
+
+    match doc.pointer_mut(parent) {
+        Some(Value::Object(map)) => {
+            map.insert(token, value);
+        }
+        Some(Value::Array(array)) if token == "-" => array.push(value),
+        Some(Value::Array(array)) => {
+            let index = token
+                .parse::<usize>()
+                .ok()
+                .filter(|i| *i <= array.len())
+                .ok_or_else(|| format!("invalid index in {path}"))?;
+            array.insert(index, value);
+        }
+        _ => return Err(format!("no container at {parent}")),
+    }
+
+    Ok(())
+}
+
+fn remove(doc: &mut Value, path: &str) -> Result<Value, String> {
+    let (parent, token) = split(path)?;
+
+    match doc.pointer_mut(parent) {
+        Some(Value::Object(map)) => map.remove(&token),
+        Some(Value::Array(array)) => token
+            .parse::<usize>()
+            .ok()
+            .filter(|i| *i < array.len())
+            .map(|i| array.remove(i)),
+        _ => None,
+    }
+    .ok_or_else(|| format!("nothing at {path}"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    fn patch(doc: Value, operations: Value) -> Result<Value, String> {
+        let operations: Vec<PatchOperation> = serde_json::from_value(operations).unwrap();
+        let mut doc = doc;
+        apply(&mut doc, &operations).map(|_| doc)
+    }
+
+    #[test]
+    fn operations_apply_in_order() {
+        let doc = json!({"team": 5907, "notes": ["fast"], "auto": {"leave": true}});
+
+        let patched = patch(
+            doc,
+            json!([
+                {"op": "add", "path": "/notes/0", "value": "defended"},
+                {"op": "remove", "path": "/auto/leave"},
+                {"op": "replace", "path": "/team", "value": 254},
+                {"op": "move", "from": "/notes/1", "path": "/auto/note"},
+                {"op": "copy", "from": "/team", "path": "/partner"},
+                {"op": "test", "path": "/partner", "value": 254}
+            ]),
+        )
+        .unwrap();
+
+        assert_eq!(
+            patched,
+            json!({"team": 254, "partner": 254, "notes": ["defended"], "auto": {"note": "fast"}})
+        );
+    }
+
+    #[test]
+    fn escaped_tokens_and_the_end_index_are_understood() {
+        let patched = patch(
+            json!({"a/b": 1, "m~n": 2, "list": [1]}),
+            json!([
+                {"op": "replace", "path": "/a~1b", "value": 10},
+                {"op": "remove", "path": "/m~0n"},
+                {"op": "add", "path": "/c~1d~0", "value": 3},
+                {"op": "add", "path": "/list/-", "value": 2}
+            ]),
+        )
+        .unwrap();
+
+        assert_eq!(patched, json!({"a/b": 10, "c/d~": 3, "list": [1, 2]}));
+    }
+
+    #[test]
+    fn failures_leave_the_document_untouched() {
+        let original = json!({"team": 5907, "notes": []});
+
+        for failing in [
+            json!({"op": "test", "path": "/team", "value": 254}),
+            json!({"op": "remove", "path": "/missing"}),
+            json!({"op": "replace", "path": "/missing", "value": 1}),
+            json!({"op": "add", "path": "/notes/3", "value": 1}),
+            json!({"op": "add", "path": "/missing/child", "value": 1}),
+        ] {
+            let operations: Vec<PatchOperation> = serde_json::from_value(json!([
+                {"op": "replace", "path": "/team", "value": 1},
+                failing
+            ]))
+            .unwrap();
+            let mut doc = original.clone();
+
+            assert!(apply(&mut doc, &operations).is_err());
+            assert_eq!(doc, original);
+        }
+    }
+}
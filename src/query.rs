@@ -0,0 +1,99 @@
+use crate::storage_manager::{StorageError, StorageManager};
+use axum::extract::Path;
+use axum::http::StatusCode;
+use axum::response::{IntoResponse, Response};
+use axum::{Extension, Json};
+use serde::Deserialize;
+use serde_json::{Map, Value};
+use std::sync::Arc;
+use tracing::instrument;
+
+#[instrument(skip(storage_manager))]
+pub async fn query_forms(
+    Path(template): Path<String>,
+    storage_manager: Extension<Arc<StorageManager>>,
+    Json(request): Json<QueryRequest>,
+) -> QueryResponse {
+    match storage_manager.forms_query(template, request.sql).await {
+        Ok(rows) => QueryResponse::Rows(rows),
+        Err(e) => QueryResponse::from_error(e),
+    }
+}
+
+#[derive(Debug, Deserialize)]
+pub struct QueryRequest {
+    sql: String,
+}
+
+#[derive(Debug)]
+pub enum QueryResponse {
+    Rows(Vec<Map<String, Value>>),
+    Invalid(String),
+    TimedOut(String),
+    FailedToRead,
+}
+
+impl QueryResponse {
+    fn from_error(e: anyhow::Error) -> Self {
+        match e.downcast::<StorageError>() {
+            Ok(e @ StorageError::InvalidQuery(_)) => Self::Invalid(e.to_string()),
+            Ok(e @ StorageError::QueryTimedOut) => Self::TimedOut(e.to_string()),
+            _ => Self::FailedToRead,
+        }
+    }
+}
+
+impl IntoResponse for QueryResponse {
+    fn into_response(self) -> Response {
+        match self {
+            QueryResponse::Rows(rows) => (StatusCode::OK, Json(rows)).into_response(),
+            QueryResponse::Invalid(message) => {
+                (StatusCode::UNPROCESSABLE_ENTITY, message).into_response()
+            }
+            // the server ran out of time, not the client, so 408 would invite
+            // the client to resend the same query straight away
+            QueryResponse::TimedOut(message) => {
+                (StatusCode::SERVICE_UNAVAILABLE, message).into_response()
+            }
+            QueryResponse::FailedToRead => StatusCode::BAD_REQUEST.into_response(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::storage_manager::tests::storage;
+
+    async fn status(sql: &str) -> StatusCode {
+        let (_dir, storage_manager) = storage().await;
+
+        query_forms(
+            Path("scores".into()),
+            Extension(Arc::new(storage_manager)),
+            Json(QueryRequest { sql: sql.into() }),
+        )
+        .await
+        .into_response()
+        .status()
+    }
+
+    #[tokio::test]
+    async fn only_single_selects_are_run() {
+        for sql in [
+            "INSERT INTO forms (team) VALUES (5907)",
+            "DROP TABLE forms",
+            "SELECT team FROM forms; SELECT scouter FROM forms",
+        ] {
+            assert_eq!(status(sql).await, StatusCode::UNPROCESSABLE_ENTITY, "{sql}");
+        }
+    }
+
+    #[test]
+    fn timeouts_are_service_unavailable() {
+        let response =
+            QueryResponse::from_error(StorageError::QueryTimedOut.into()).into_response();
+
+        assert_eq!(response.status(), StatusCode::SERVICE_UNAVAILABLE);
+    }
+}
@@ -1,21 +1,27 @@
+use crate::auth::GoogleUser;
 use crate::datatypes::Schedule;
-use crate::storage_manager::StorageManager;
-use anyhow::Error;
-use axum::extract::Path;
+use crate::storage_manager::{StorageError, StorageManager};
+use crate::transactions::DeletePreview;
+use axum::extract::{Path, Query};
 use axum::http::StatusCode;
 use axum::response::{IntoResponse, Response};
 use axum::{Extension, Json};
+use serde::Deserialize;
 use std::sync::Arc;
 use tracing::instrument;
 
 #[instrument(skip(schedule, storage_manager))]
 pub async fn add_schedule(
+    user: GoogleUser,
     storage_manager: Extension<Arc<StorageManager>>,
     Json(schedule): Json<Schedule>,
 ) -> SchedulesResponse {
-    match storage_manager.schedules_add(schedule).await {
+    match storage_manager.schedules_add(schedule, &user.email).await {
         Ok(_) => SchedulesResponse::OK,
-        Err(_) => SchedulesResponse::FailedToAdd,
+        Err(e) => match e.downcast::<StorageError>() {
+            Ok(e @ StorageError::AlreadyExists { .. }) => SchedulesResponse::Exists(e.to_string()),
+            _ => SchedulesResponse::FailedToAdd,
+        },
     }
 }
 
@@ -32,10 +38,11 @@ pub async fn get_schedule(
 
 #[instrument(skip(storage_manager, schedule))]
 pub async fn edit_schedule(
+    user: GoogleUser,
     storage_manager: Extension<Arc<StorageManager>>,
     Json(schedule): Json<Schedule>,
 ) -> SchedulesResponse {
-    match storage_manager.schedules_edit(schedule).await {
+    match storage_manager.schedules_edit(schedule, &user.email).await {
         Ok(_) => SchedulesResponse::OK,
         Err(_) => SchedulesResponse::FailedToEdit,
     }
@@ -52,14 +59,32 @@ pub async fn list_schedules(storage_manager: Extension<Arc<StorageManager>>) ->
 #[instrument(skip(storage_manager))]
 pub async fn delete_schedule(
     Path(name): Path<String>,
+    Query(query): Query<DryRunQuery>,
+    user: GoogleUser,
     storage_manager: Extension<Arc<StorageManager>>,
 ) -> SchedulesResponse {
-    match storage_manager.schedules_delete(name).await {
+    if query.dry_run.unwrap_or(false) {
+        return match storage_manager
+            .schedules_delete_preview(name, &user.email)
+            .await
+        {
+            Ok(preview) => SchedulesResponse::Preview(preview),
+            Err(_) => SchedulesResponse::FailedToRead,
+        };
+    }
+
+    match storage_manager.schedules_delete(name, &user.email).await {
         Ok(_) => SchedulesResponse::OK,
         Err(_) => SchedulesResponse::FailedToDelete,
     }
 }
 
+#[derive(Debug, Deserialize)]
+pub struct DryRunQuery {
+    /// Report what would be deleted instead of deleting.
+    dry_run: Option<bool>,
+}
+
 #[derive(Debug)]
 pub enum SchedulesResponse {
     OK,
@@ -69,6 +94,8 @@ pub enum SchedulesResponse {
     FailedToEdit,
     FailedToDelete,
     FailedToRead,
+    Exists(String),
+    Preview(DeletePreview),
 }
 
 impl IntoResponse for SchedulesResponse {
@@ -81,6 +108,47 @@ impl IntoResponse for SchedulesResponse {
             SchedulesResponse::FailedToDelete => StatusCode::BAD_REQUEST.into_response(),
             SchedulesResponse::FailedToRead => StatusCode::BAD_REQUEST.into_response(),
             SchedulesResponse::List(l) => (StatusCode::OK, Json(l)).into_response(),
+            SchedulesResponse::Preview(p) => (StatusCode::OK, Json(p)).into_response(),
+            SchedulesResponse::Exists(message) => (StatusCode::CONFLICT, message).into_response(),
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::storage_manager::tests::storage;
+
+    #[tokio::test]
+    async fn adding_an_existing_schedule_conflicts() {
+        let (_dir, storage_manager) = storage().await;
+        let storage_manager = Extension(Arc::new(storage_manager));
+        let add = || async {
+            add_schedule(
+                GoogleUser {
+                    id: "1".into(),
+                    email: "lead@example.com".into(),
+                    verified_email: true,
+                    picture: String::new(),
+                    hd: None,
+                },
+                storage_manager.clone(),
+                Json(Schedule {
+                    event: "2024nyro".into(),
+                    shifts: vec![],
+                }),
+            )
+            .await
+            .into_response()
+        };
+
+        assert_eq!(add().await.status(), StatusCode::OK);
+
+        let duplicate = add().await;
+        assert_eq!(duplicate.status(), StatusCode::CONFLICT);
+        let body = axum::body::to_bytes(duplicate.into_body(), usize::MAX)
+            .await
+            .unwrap();
+        assert_eq!(body, "Schedule 2024nyro already exists");
+    }
+}
@@ -0,0 +1,73 @@
+use crate::datatypes::{EventComparison, FieldStats};
+use crate::storage_manager::{StorageError, StorageManager};
+use axum::extract::{Path, Query};
+use axum::http::StatusCode;
+use axum::response::{IntoResponse, Response};
+use axum::{Extension, Json};
+use serde::Deserialize;
+use std::sync::Arc;
+use tracing::instrument;
+
+#[instrument(skip(storage_manager))]
+pub async fn team_field_stats(
+    Path((template, team, field)): Path<(String, i64, String)>,
+    storage_manager: Extension<Arc<StorageManager>>,
+) -> StatsResponse {
+    match storage_manager
+        .team_field_stats(template, team, field, None)
+        .await
+    {
+        Ok(stats) => StatsResponse::Stats(stats),
+        Err(e) => StatsResponse::from_error(e),
+    }
+}
+
+#[instrument(skip(storage_manager))]
+pub async fn compare_events(
+    Path((template, team)): Path<(String, i64)>,
+    Query(query): Query<CompareQuery>,
+    storage_manager: Extension<Arc<StorageManager>>,
+) -> StatsResponse {
+    match storage_manager
+        .team_event_comparison(template, team, query.field, query.event_a, query.event_b)
+        .await
+    {
+        Ok(comparison) => StatsResponse::Comparison(comparison),
+        Err(e) => StatsResponse::from_error(e),
+    }
+}
+
+#[derive(Debug, Deserialize)]
+pub struct CompareQuery {
+    event_a: String,
+    event_b: String,
+    field: String,
+}
+
+#[derive(Debug)]
+pub enum StatsResponse {
+    Stats(FieldStats),
+    Comparison(EventComparison),
+    FieldNotNumeric,
+    FailedToRead,
+}
+
+impl StatsResponse {
+    fn from_error(e: anyhow::Error) -> Self {
+        match e.downcast_ref::<StorageError>() {
+            Some(StorageError::FieldNotNumeric(_)) => Self::FieldNotNumeric,
+            _ => Self::FailedToRead,
+        }
+    }
+}
+
+impl IntoResponse for StatsResponse {
+    fn into_response(self) -> Response {
+        match self {
+            StatsResponse::Stats(s) => (StatusCode::OK, Json(s)).into_response(),
+            StatsResponse::Comparison(c) => (StatusCode::OK, Json(c)).into_response(),
+            StatsResponse::FieldNotNumeric => StatusCode::UNPROCESSABLE_ENTITY.into_response(),
+            StatsResponse::FailedToRead => StatusCode::BAD_REQUEST.into_response(),
+        }
+    }
+}
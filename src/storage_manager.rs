@@ -1,35 +1,434 @@
-use crate::datatypes::{Filter, Form, FormTemplate, Schedule};
-use crate::transactions::{Action, DataType, InternalMessage};
+use crate::datatypes::{
+    Dashboard, DuplicateScouting, EventComparison, ExpectedMatch, FieldData, FieldStats, Filter,
+    Form, FormSummary, FormTemplate, MissingCoverage, Schedule, Scouter, ScouterActivity, SortDir,
+    TemplateCount,
+};
+use crate::patch;
+use crate::patch::PatchOperation;
+use crate::transactions::{
+    Action, DataType, DeletePreview, ImportSummary, InternalMessage, MergePolicy, Resolution,
+    RestoreSummary, StorageOverview, SyncConflict, SyncDiff, SYSTEM_USER,
+};
 use anyhow::anyhow;
-use datafusion::arrow::array::RecordBatch;
-use datafusion::arrow::array::{Array, AsArray};
-use datafusion::arrow::datatypes;
-use datafusion::arrow::datatypes::{Field, FieldRef, Schema, SchemaRef};
+use chrono::Utc;
+use datafusion::arrow::array::{RecordBatch, StringArray, UInt64Array};
+use datafusion::arrow::datatypes::{DataType as ArrowType, Field, Schema, SchemaRef};
+use datafusion::arrow::ipc::writer::StreamWriter;
 use datafusion::arrow::json::writer::record_batches_to_json_rows;
-use datafusion::arrow::util::pretty::pretty_format_batches;
 use datafusion::datasource::file_format::json::JsonFormat;
 use datafusion::datasource::listing::{
     ListingOptions, ListingTable, ListingTableConfig, ListingTableUrl,
 };
+use datafusion::datasource::MemTable;
+use datafusion::parquet::arrow::ArrowWriter;
 use datafusion::prelude::{col, lit, SessionContext};
+use datafusion::sql::sqlparser::ast::Statement;
+use datafusion::sql::sqlparser::dialect::GenericDialect;
+use datafusion::sql::sqlparser::parser::Parser;
+use futures::{stream, Stream, StreamExt};
 use glob::glob;
-use serde::Deserialize;
-use serde_json::Value;
+use opentelemetry::metrics::{Counter, Unit};
+use opentelemetry::{global, KeyValue};
+use serde::{Deserialize, Serialize};
+use serde_json::{Map, Value};
+use sha2::{Digest, Sha256};
 use sha256::Sha256Digest;
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::io::SeekFrom;
 use std::path::Path;
 use std::sync::Arc;
+use std::time::{Duration, Instant};
+use tokio::fs;
 use tokio::fs::{File, OpenOptions};
-use tokio::io::{AsyncBufReadExt, AsyncReadExt, AsyncWriteExt, BufReader};
-use tokio::{fs, io};
-use tracing::{info, instrument, warn};
+use tokio::io::{AsyncBufReadExt, AsyncReadExt, AsyncSeekExt, AsyncWriteExt, BufReader};
+use tokio::sync::broadcast::error::TryRecvError;
+use tokio::sync::{broadcast, Mutex, MutexGuard, OnceCell};
+use tracing::{debug, info, instrument, warn};
 use uuid::Uuid;
 
+const TEMPLATES_TABLE: &str = "templates";
+
+/// Transactions a slow live subscriber can fall behind by before it skips ahead.
+const EVENT_CAPACITY: usize = 256;
+
+/// Form columns filtered forms can be ordered by.
+const SORT_KEYS: [&str; 3] = ["match_number", "team", "scouter"];
+const SCHEDULES_TABLE: &str = "schedules";
+
+/// Most rows an ad-hoc SQL query returns.
+const QUERY_ROW_LIMIT: usize = 10_000;
+/// How long an ad-hoc SQL query may run before it is abandoned.
+const QUERY_TIMEOUT: Duration = Duration::from_secs(10);
+/// How long the size of the bytes directory is reused before it is walked again.
+const BLOB_BYTES_TTL: Duration = Duration::from_secs(60);
+
+/// Most recent transactions a dashboard lists.
+const DASHBOARD_LATEST_LIMIT: usize = 100;
+
+/// Names of storage directories and query tables a template may not shadow.
+const RESERVED_TEMPLATE_NAMES: [&str; 6] = [
+    TEMPLATES_TABLE,
+    SCHEDULES_TABLE,
+    "forms",
+    "bytes",
+    "uploads",
+    "transactions",
+];
+
+#[derive(Debug)]
+pub enum StorageError {
+    FieldNotNumeric(String),
+    FieldNotText(String),
+    InvalidEventKey(String),
+    UnknownSortKey(String),
+    TeamOutOfRange {
+        team: i64,
+        min: i64,
+        max: i64,
+    },
+    UnknownFields(Vec<String>),
+    VersionConflict(Option<Uuid>),
+    UploadNotFound,
+    UploadOffsetMismatch(u64),
+    /// `total` is `None` when no chunk declared the upload's length.
+    UploadIncomplete {
+        received: u64,
+        total: Option<u64>,
+    },
+    TemplateHasForms(usize),
+    FormsWouldBreak(Vec<String>),
+    TemplateNameReserved(String),
+    InvalidDefaults(Vec<String>),
+    InvalidPatch(String),
+    NotFound,
+    Deleted,
+    AlreadyExists {
+        data_type: DataType,
+        key: String,
+    },
+    DigestMismatch,
+    InvalidQuery(String),
+    QueryTimedOut,
+    TemplateTooLarge(String),
+    ChecksumMismatch(String),
+    /// The blob file is shorter than its header says it should be.
+    Truncated(String),
+    BatchTooLarge(String),
+}
+
+impl std::fmt::Display for StorageError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            StorageError::FieldNotNumeric(name) => write!(f, "field {name} is not numeric"),
+            StorageError::FieldNotText(name) => write!(f, "field {name} is not text"),
+            StorageError::TeamOutOfRange { team, min, max } => {
+                write!(f, "team {team} is outside {min}..={max}")
+            }
+            StorageError::UnknownSortKey(key) => {
+                write!(
+                    f,
+                    "cannot sort by {key}, expected one of {}",
+                    SORT_KEYS.join(", ")
+                )
+            }
+            StorageError::InvalidEventKey(key) => {
+                write!(f, "event key {key:?} is not a TBA event key")
+            }
+            StorageError::UnknownFields(names) => {
+                write!(f, "fields not in template: {}", names.join(", "))
+            }
+            StorageError::VersionConflict(current) => {
+                write!(f, "item was modified, current version is {current:?}")
+            }
+            StorageError::UploadNotFound => write!(f, "no upload in progress"),
+            StorageError::UploadOffsetMismatch(offset) => {
+                write!(f, "upload is at offset {offset}")
+            }
+            StorageError::UploadIncomplete {
+                received,
+                total: Some(total),
+            } => write!(f, "upload has {received} of {total} bytes"),
+            StorageError::UploadIncomplete {
+                received,
+                total: None,
+            } => write!(f, "upload has {received} bytes of an undeclared total"),
+            StorageError::TemplateHasForms(count) => {
+                write!(f, "template has {count} forms stored against it")
+            }
+            StorageError::FormsWouldBreak(ids) => {
+                write!(f, "edit would invalidate forms: {}", ids.join(", "))
+            }
+            StorageError::TemplateNameReserved(name) => {
+                write!(f, "template name {name} is reserved")
+            }
+            StorageError::InvalidPatch(reason) => write!(f, "patch failed: {reason}"),
+            StorageError::InvalidDefaults(names) => {
+                write!(
+                    f,
+                    "defaults do not match their field type: {}",
+                    names.join(", ")
+                )
+            }
+            StorageError::NotFound => write!(f, "item was never stored"),
+            StorageError::Deleted => write!(f, "item was deleted"),
+            StorageError::AlreadyExists { data_type, key } => {
+                write!(f, "{data_type:?} {key} already exists")
+            }
+            StorageError::DigestMismatch => write!(f, "content does not match its digest"),
+            StorageError::ChecksumMismatch(key) => {
+                write!(f, "{key} does not match the checksum it was written with")
+            }
+            StorageError::Truncated(key) => write!(f, "{key} is shorter than its header"),
+            StorageError::TemplateTooLarge(reason) => write!(f, "template too large: {reason}"),
+            StorageError::BatchTooLarge(reason) => write!(f, "batch too large: {reason}"),
+            StorageError::InvalidQuery(reason) => write!(f, "invalid query: {reason}"),
+            StorageError::QueryTimedOut => {
+                write!(f, "query ran longer than {}s", QUERY_TIMEOUT.as_secs())
+            }
+        }
+    }
+}
+
+impl std::error::Error for StorageError {}
+
+/// How many days a deleted item of each data type is kept before `gc_blobs`
+/// removes it for good. A missing window keeps deleted items forever.
+#[derive(Debug, Deserialize)]
+#[serde(default)]
+pub struct Retention {
+    pub form_days: Option<u64>,
+    pub template_days: Option<u64>,
+    pub schedule_days: Option<u64>,
+    pub bytes_days: Option<u64>,
+    pub interval_secs: u64,
+}
+
+impl Default for Retention {
+    fn default() -> Self {
+        Self {
+            form_days: None,
+            template_days: None,
+            schedule_days: None,
+            bytes_days: None,
+            interval_secs: 60 * 60,
+        }
+    }
+}
+
+impl Retention {
+    fn days_for(&self, data_type: &DataType) -> Option<u64> {
+        match data_type {
+            DataType::Form(_) => self.form_days,
+            DataType::Template => self.template_days,
+            DataType::Schedule => self.schedule_days,
+            DataType::Bytes => self.bytes_days,
+        }
+    }
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(default)]
+pub struct UploadConfig {
+    /// Seconds an upload session may sit idle before it expires.
+    pub ttl_secs: u64,
+    /// Seconds between sweeps for expired upload sessions.
+    pub prune_interval_secs: u64,
+}
+
+impl Default for UploadConfig {
+    fn default() -> Self {
+        Self {
+            ttl_secs: 24 * 60 * 60,
+            prune_interval_secs: 15 * 60,
+        }
+    }
+}
+
+/// Caps on what a template may contain, checked on add and edit.
+#[derive(Debug, Deserialize)]
+#[serde(default)]
+pub struct TemplateLimits {
+    pub max_fields: usize,
+    /// Bytes of the template once serialized as JSON.
+    pub max_bytes: usize,
+}
+
+impl Default for TemplateLimits {
+    fn default() -> Self {
+        Self {
+            max_fields: 500,
+            max_bytes: 256 * 1024,
+        }
+    }
+}
+
+/// Caps on requests that act on many items at once: transaction imports and
+/// deletes by filter.
+#[derive(Debug, Deserialize)]
+#[serde(default)]
+pub struct BatchLimits {
+    pub max_items: usize,
+    /// Bytes of a batch request body, enforced before it is read in full.
+    pub max_bytes: usize,
+}
+
+impl Default for BatchLimits {
+    fn default() -> Self {
+        Self {
+            max_items: 10_000,
+            max_bytes: 16 * 1024 * 1024,
+        }
+    }
+}
+
+/// The node this one syncs from.
+#[derive(Debug, Deserialize)]
+pub struct SyncParent {
+    pub url: String,
+    /// Sent as the `authorization` header on requests to the parent.
+    pub jwt: String,
+}
+
+/// A resumable upload that is being assembled in a temporary file.
+#[derive(Debug, Clone, Serialize)]
+pub struct UploadSession {
+    pub offset: u64,
+    pub total: Option<u64>,
+    pub expires_at: i64,
+    /// Set while the upload is being committed, which takes no more chunks.
+    #[serde(skip)]
+    finalizing: bool,
+    /// Set while a chunk is being written, which takes no other chunk.
+    #[serde(skip)]
+    appending: bool,
+}
+
+/// Application-level counters, published on `/metrics` next to the HTTP metrics.
+/// Built from the global meter provider, so that has to be installed before the
+/// storage manager is loaded.
+pub struct StorageMetrics {
+    forms_added: Counter<u64>,
+    forms_edited: Counter<u64>,
+    forms_deleted: Counter<u64>,
+    blob_bytes_written: Counter<u64>,
+    query_cache_hits: Counter<u64>,
+    query_cache_misses: Counter<u64>,
+}
+
+impl Default for StorageMetrics {
+    fn default() -> Self {
+        let meter = global::meter("scouting-api");
+
+        Self {
+            forms_added: meter.u64_counter("forms_added_total").init(),
+            forms_edited: meter.u64_counter("forms_edited_total").init(),
+            forms_deleted: meter.u64_counter("forms_deleted_total").init(),
+            blob_bytes_written: meter
+                .u64_counter("blob_bytes_written")
+                .with_unit(Unit::new("By"))
+                .init(),
+            query_cache_hits: meter.u64_counter("query_cache_hits_total").init(),
+            query_cache_misses: meter.u64_counter("query_cache_misses_total").init(),
+        }
+    }
+}
+
 #[derive(Default, Deserialize)]
 pub struct StorageManager {
     transaction_log: TransactionLog,
     path: String,
+    #[serde(default)]
+    pub retention: Retention,
+    /// Reject forms carrying fields their template does not declare.
+    #[serde(default)]
+    strict_forms: bool,
+    #[serde(default)]
+    pub uploads: UploadConfig,
+    #[serde(default)]
+    pub template_limits: TemplateLimits,
+    #[serde(default)]
+    pub batch_limits: BatchLimits,
+    /// Directory of `*.json` templates added on startup if not already present.
+    #[serde(default)]
+    template_directory: Option<String>,
+    /// How imported transactions that conflict with local changes are resolved.
+    #[serde(default)]
+    pub merge_policy: MergePolicy,
+    #[serde(default)]
+    pub sync_parent: Option<SyncParent>,
+    /// Emails of the child nodes allowed to push and pull blobs.
+    #[serde(default)]
+    pub sync_children: Vec<String>,
+    /// Where bytes blobs are kept, relative to `path` unless absolute.
+    #[serde(default = "default_bytes_dir")]
+    bytes_dir: String,
+    /// Seconds a form submission's idempotency key is remembered for.
+    #[serde(default = "default_idempotency_ttl")]
+    idempotency_ttl_secs: u64,
+    /// Form ids created under an idempotency key, by user, template and key,
+    /// with when the key expires. A key is reserved before its form is written.
+    #[serde(skip)]
+    idempotency_keys: Mutex<HashMap<(String, String, String), IdempotencyEntry>>,
     #[serde(skip)]
     df_ctx: SessionContext,
+    #[serde(skip)]
+    write_lock: Mutex<()>,
+    #[serde(skip)]
+    upload_sessions: Mutex<HashMap<String, UploadSession>>,
+    #[serde(skip)]
+    metrics: StorageMetrics,
+    #[serde(skip)]
+    query_cache: Mutex<QueryCache>,
+    /// Size of the bytes directory and when it was measured.
+    #[serde(skip)]
+    blob_bytes: Mutex<Option<(u64, Instant)>>,
+}
+
+/// The form id a key resolves to once written, and when the key expires.
+type IdempotencyEntry = (Arc<OnceCell<String>>, i64);
+
+/// Record batches built for SQL queries, by template. Entries are dropped as
+/// transactions for their template come through `events`.
+#[derive(Default)]
+struct QueryCache {
+    /// Subscribed on first use, so nothing before then needs replaying.
+    events: Option<broadcast::Receiver<InternalMessage>>,
+    batches: HashMap<String, RecordBatch>,
+}
+
+impl QueryCache {
+    /// Drops the batches of templates with transactions since the last drain,
+    /// returning whether `template` was one of them.
+    fn drain(&mut self, template: &str) -> bool {
+        let Some(events) = self.events.as_mut() else {
+            return false;
+        };
+        let mut touched = false;
+
+        loop {
+            match events.try_recv() {
+                Ok(transaction) => match transaction.data_type {
+                    DataType::Form(name) => {
+                        touched |= name == template;
+                        self.batches.remove(&name);
+                    }
+                    DataType::Template => {
+                        touched = true;
+                        self.batches.clear();
+                    }
+                    _ => {}
+                },
+                // can't tell which templates were missed
+                Err(TryRecvError::Lagged(_)) => {
+                    touched = true;
+                    self.batches.clear();
+                }
+                Err(TryRecvError::Empty | TryRecvError::Closed) => break,
+            }
+        }
+
+        touched
+    }
 }
 
 impl StorageManager {
@@ -58,39 +457,47 @@ impl StorageManager {
         }
     }
 
+    /// Writes `data` as a new version of an item, under the file named by its
+    /// transaction, then makes it the item's current file and logs the
+    /// transaction, whose `new_path` and checksum both describe that version.
+    /// An add fails if the item already has a current file, an edit if it has
+    /// none.
     #[instrument(skip(self, data))]
-    pub async fn raw_edit(
+    async fn write_version(
         &self,
-        name: &str,
-        old_name: &str,
-        sub_path: &str,
-        data: impl AsRef<[u8]>,
-    ) -> Result<(), anyhow::Error> {
-        info!("Edit from {sub_path}{name} to {sub_path}{old_name}");
+        data_type: DataType,
+        action: Action,
+        alt_key: &str,
+        data: &[u8],
+        user: &str,
+    ) -> Result<InternalMessage, anyhow::Error> {
+        let replace = action == Action::Edit;
+        let mut transaction = InternalMessage::new(data_type, action, alt_key.into(), user);
+        transaction.new_path = Self::version_path(&transaction);
 
-        fs::rename(
-            format!("{}{sub_path}{name}", &self.path),
-            format!("{}{sub_path}{old_name}", &self.path),
-        )
-        .await?;
+        let dir = self.dir(&self.sub_path(&transaction.data_type, alt_key));
+        let version = format!("{dir}{}", transaction.new_path);
+        let current = format!("{dir}{alt_key}.current");
 
-        write_non_create(format!("{}{sub_path}{name}", &self.path), data)
-            .await
-            .map_err(Into::into)
-    }
+        info!("Write {version}");
 
-    #[instrument(skip(self, data))]
-    pub async fn raw_add(
-        &self,
-        name: &str,
-        sub_path: &str,
-        data: &[u8],
-    ) -> Result<(), anyhow::Error> {
-        info!("Add at {sub_path}{name}");
+        if replace {
+            fs::metadata(&current).await?;
+        }
 
-        write_non_create(format!("{}{sub_path}{name}", &self.path), data)
-            .await
-            .map_err(Into::into)
+        write_non_create(&version, data).await?;
+
+        if let Err(e) = make_current(&version, &current, replace).await {
+            let _ = fs::remove_file(&version).await;
+            return Err(e);
+        }
+
+        let transaction = transaction.with_checksum(data.digest());
+        self.transaction_log
+            .log_transaction(transaction.clone())
+            .await?;
+
+        Ok(transaction)
     }
 
     #[instrument(skip(self))]
@@ -103,8 +510,8 @@ impl StorageManager {
         info!("Delete from {sub_path}{name} to {sub_path}{old_name}");
 
         fs::rename(
-            format!("{}{sub_path}{name}", &self.path),
-            format!("{}{sub_path}{old_name}", &self.path),
+            format!("{}{name}", self.dir(sub_path)),
+            format!("{}{old_name}", self.dir(sub_path)),
         )
         .await
         .map_err(Into::into)
@@ -114,286 +521,712 @@ impl StorageManager {
     pub async fn raw_get(&self, name: &str, sub_path: &str) -> Result<Vec<u8>, anyhow::Error> {
         info!("Get at {sub_path}{name}");
 
-        fs::read(format!("{}{sub_path}{name}", &self.path))
+        fs::read(format!("{}{name}", self.dir(sub_path)))
             .await
             .map_err(Into::into)
     }
 
-    #[instrument(skip(self, form))]
-    pub async fn forms_add(&self, template: String, form: Form) -> Result<String, anyhow::Error> {
-        let pre = Uuid::new_v4().to_string();
-        let mut form = form;
-        form.id = Some(pre.clone());
-        let ser = serde_json::to_string(&form)?;
-        let digested = format!("{}.current", (&pre).digest());
-        let template = self.templates_get(template).await?;
+    /// Default values to seed a blank form of the template with.
+    #[instrument(skip(self))]
+    pub async fn template_defaults(
+        &self,
+        template: String,
+    ) -> Result<HashMap<String, FieldData>, anyhow::Error> {
+        Ok(self.templates_get(template).await?.defaults())
+    }
 
-        if !template.validate_form(&form) {
-            return Err(anyhow!("form does not follow template"));
+    fn check_template_name(name: &str) -> Result<(), anyhow::Error> {
+        if RESERVED_TEMPLATE_NAMES
+            .iter()
+            .any(|reserved| reserved.eq_ignore_ascii_case(name))
+        {
+            return Err(StorageError::TemplateNameReserved(name.to_string()).into());
         }
 
-        self.raw_add(
-            &digested,
-            &format!("forms/{}.current/", (&template.name).digest()),
-            ser.as_bytes(),
-        )
-        .await?;
+        Ok(())
+    }
 
-        self.transaction_log
-            .log_transaction(InternalMessage::new(
-                DataType::Form(template.name),
-                Action::Add,
-                digested,
+    fn check_template_defaults(template: &FormTemplate) -> Result<(), anyhow::Error> {
+        let invalid = template.invalid_defaults();
+
+        if !invalid.is_empty() {
+            return Err(StorageError::InvalidDefaults(invalid).into());
+        }
+
+        Ok(())
+    }
+
+    fn check_template_size(&self, template: &FormTemplate) -> Result<(), anyhow::Error> {
+        let limits = &self.template_limits;
+        let fields = template.field_count();
+
+        if fields > limits.max_fields {
+            return Err(StorageError::TemplateTooLarge(format!(
+                "{fields} fields, at most {} allowed",
+                limits.max_fields
             ))
-            .await?;
+            .into());
+        }
 
-        Ok(pre)
+        let bytes = serde_json::to_vec(template)?.len();
+
+        if bytes > limits.max_bytes {
+            return Err(StorageError::TemplateTooLarge(format!(
+                "{bytes} bytes, at most {} allowed",
+                limits.max_bytes
+            ))
+            .into());
+        }
+
+        Ok(())
+    }
+
+    fn check_form(&self, template: &FormTemplate, form: &Form) -> Result<(), anyhow::Error> {
+        if !template.validate_form(form) {
+            return Err(anyhow!("form does not follow template"));
+        }
+
+        if !template.team_valid(form) {
+            return Err(StorageError::TeamOutOfRange {
+                team: form.team,
+                min: template.team_range.min,
+                max: template.team_range.max,
+            }
+            .into());
+        }
+
+        if !template.event_key_valid(form) {
+            return Err(StorageError::InvalidEventKey(form.event_key.clone()).into());
+        }
+
+        if self.strict_forms {
+            let unknown = template.unknown_fields(form);
+
+            if !unknown.is_empty() {
+                return Err(StorageError::UnknownFields(unknown).into());
+            }
+        }
+
+        Ok(())
     }
 
+    /// Adds a form, or with `dedupe` returns the id of an identical live form
+    /// from the same scouter for the same team and match instead.
     #[instrument(skip(self, form))]
-    pub async fn forms_edit(
+    pub async fn forms_add(
         &self,
         template: String,
         form: Form,
-        id: String,
-    ) -> Result<(), anyhow::Error> {
-        let pre = id.to_string();
+        dedupe: bool,
+        user: &str,
+    ) -> Result<String, anyhow::Error> {
+        // held until the form is written, so a template edit cannot slip in
+        // after the form is validated, and two identical submissions cannot
+        // both miss each other
+        let _guard = self.write_lock.lock().await;
+
         let mut form = form;
-        form.id = Some(pre.clone());
-        let ser = serde_json::to_string(&form)?;
-        let digested = (&pre).digest();
-        let old = format!("{}.{}", digested, Uuid::new_v4());
-        let digested = format!("{}.current", digested);
-        let template = self.templates_get(template).await?;
+        let form_template = self.templates_get(template.clone()).await?;
+        form_template.normalize(&mut form);
+        let hash = form.compute_content_hash()?;
 
-        if !template.validate_form(&form) {
-            return Err(anyhow!("form does not follow template"));
+        if dedupe {
+            let existing = self
+                .forms_filter(
+                    template.clone(),
+                    Filter {
+                        team: Some(form.team),
+                        match_number: Some(form.match_number),
+                        scouter: Some(form.scouter.clone()),
+                        ..Default::default()
+                    },
+                )
+                .await?
+                .into_iter()
+                .find(|f| f.content_hash.as_ref() == Some(&hash))
+                .and_then(|f| f.id);
+
+            if let Some(id) = existing {
+                info!("Duplicate of {id}");
+
+                return Ok(id);
+            }
         }
 
-        self.raw_edit(
-            &digested,
-            &old,
-            &format!("forms/{}.current/", (&template.name).digest()),
+        let pre = Uuid::new_v4().to_string();
+        form.id = Some(pre.clone());
+        form.content_hash = Some(hash);
+        let template = form_template;
+
+        self.check_form(&template, &form)?;
+
+        template.materialize_indexed(&mut form);
+        form.template_version = Some(template.version);
+        let ser = serde_json::to_string(&form)?;
+
+        self.write_version(
+            DataType::Form(template.name.clone()),
+            Action::Add,
+            &(&pre).digest(),
             ser.as_bytes(),
+            user,
         )
         .await?;
 
-        self.transaction_log
-            .log_transaction(InternalMessage::new(
-                DataType::Form(template.name),
-                Action::Edit,
-                digested,
-            ))
-            .await
-            .map_err(Into::into)
+        self.metrics
+            .forms_added
+            .add(1, &[KeyValue::new("template", template.name)]);
+
+        Ok(pre)
     }
 
-    #[instrument(skip(self))]
-    pub async fn forms_delete(&self, template: String, id: String) -> Result<(), anyhow::Error> {
-        let dig = id.digest();
-        let old = format!("{}.{}", &dig, Uuid::new_v4());
-        let digested = format!("{}.current", &dig);
+    /// Adds a form once per idempotency key. Repeating a key within the
+    /// configured window returns the id the first request created. Keys are
+    /// scoped to the user, so two scouters picking the same key do not collide.
+    #[instrument(skip(self, form))]
+    pub async fn forms_add_idempotent(
+        &self,
+        template: String,
+        form: Form,
+        dedupe: bool,
+        key: String,
+        user: &str,
+    ) -> Result<String, anyhow::Error> {
+        let now = Utc::now().timestamp_micros();
+        let expires_at = now + self.idempotency_ttl_secs as i64 * 1_000_000;
 
-        self.raw_delete(
-            &digested,
-            &old,
-            &format!("forms/{}.current/", (&template).digest()),
-        )
-        .await?;
+        // only the reservation is made under the map's lock, so submissions
+        // under other keys are not held up by this one's write
+        let reserved = {
+            let mut keys = self.idempotency_keys.lock().await;
+            keys.retain(|_, (_, expires_at)| *expires_at > now);
 
-        self.transaction_log
-            .log_transaction(InternalMessage::new(
-                DataType::Form(template),
-                Action::Delete,
-                digested,
-            ))
+            keys.entry((user.to_string(), template.clone(), key))
+                .or_insert_with(|| (Arc::new(OnceCell::new()), expires_at))
+                .0
+                .clone()
+        };
+
+        if let Some(id) = reserved.get() {
+            info!("Repeat of idempotency key for {id}");
+            return Ok(id.clone());
+        }
+
+        // a repeat arriving mid-write waits for it, and retries if it fails
+        reserved
+            .get_or_try_init(|| self.forms_add(template, form, dedupe, user))
             .await
-            .map_err(Into::into)
+            .cloned()
     }
 
-    pub fn get_path(&self) -> &str {
-        &self.path
-    }
+    /// Edits a form and returns the id of the new version. When `expected_blob_id`
+    /// is given the edit only goes through if it still names the transaction
+    /// that wrote the form's current version.
+    #[instrument(skip(self, form))]
+    pub async fn forms_edit(
+        &self,
+        template: String,
+        form: Form,
+        id: String,
+        expected_blob_id: Option<Uuid>,
+        user: &str,
+    ) -> Result<Uuid, anyhow::Error> {
+        let _guard = self.write_lock.lock().await;
 
-    #[instrument(skip(self))]
-    pub async fn forms_get(&self, template: String, id: String) -> Result<Form, anyhow::Error> {
-        let digested = format!("{}.current", id.digest());
+        if let Some(expected) = expected_blob_id {
+            let current = self
+                .latest_blob_from_alt_key(DataType::Form(template.clone()), &(&id).digest())
+                .await?;
 
-        let bytes = self
-            .raw_get(
-                &digested,
-                &format!("forms/{}.current/", (&template).digest()),
+            if current != Some(expected) {
+                return Err(StorageError::VersionConflict(current).into());
+            }
+        }
+
+        let pre = id.to_string();
+        let template = self.templates_get(template).await?;
+        let mut form = form;
+        template.normalize(&mut form);
+        form.id = Some(pre.clone());
+        form.content_hash = Some(form.compute_content_hash()?);
+
+        self.check_form(&template, &form)?;
+
+        template.materialize_indexed(&mut form);
+        form.template_version = Some(template.version);
+        let ser = serde_json::to_string(&form)?;
+
+        let blob_id = self
+            .write_version(
+                DataType::Form(template.name.clone()),
+                Action::Edit,
+                &(&pre).digest(),
+                ser.as_bytes(),
+                user,
             )
-            .await?;
+            .await?
+            .id;
 
-        serde_json::from_slice(bytes.as_slice()).map_err(Into::into)
+        self.metrics
+            .forms_edited
+            .add(1, &[KeyValue::new("template", template.name)]);
+
+        Ok(blob_id)
     }
 
+    /// Deletes every live form matching `filter` and returns their ids. Edits
+    /// are held off until the whole batch is written.
     #[instrument(skip(self))]
-    pub async fn forms_list(&self, template: String) -> Result<Vec<String>, anyhow::Error> {
-        let mut files =
-            fs::read_dir(format!("{}forms/{}.current", self.path, template.digest())).await?;
+    pub async fn forms_delete_by_filter(
+        &self,
+        template: String,
+        filter: Filter,
+        user: &str,
+    ) -> Result<Vec<String>, anyhow::Error> {
+        let _guard = self.write_lock.lock().await;
 
-        let mut names: Vec<String> = vec![];
+        let ids: Vec<String> = self
+            .forms_filter(template.clone(), filter)
+            .await?
+            .into_iter()
+            .filter_map(|form| form.id)
+            .collect();
 
-        while let Some(entry) = files.next_entry().await? {
-            if entry
-                .file_name()
-                .to_string_lossy()
-                .to_string()
-                .ends_with(".current")
-            {
-                let de: Form = serde_json::from_slice(fs::read(entry.path()).await?.as_ref())?;
-                if let Some(id) = de.id {
-                    names.push(id);
-                }
-            }
+        self.check_batch_items(ids.len())?;
+
+        for id in &ids {
+            self.forms_delete_locked(template.clone(), id.clone(), user)
+                .await?;
         }
-        Ok(names)
+
+        Ok(ids)
     }
 
+    /// Brings back deleted forms matching `filter` whose files have not been
+    /// collected yet, logging each as a fresh add.
     #[instrument(skip(self))]
-    pub async fn forms_filter(
+    pub async fn restore_by_filter(
         &self,
         template: String,
         filter: Filter,
-    ) -> Result<Vec<Form>, anyhow::Error> {
-        let path = format!("{}forms/{}.current/", self.path, template.digest());
+        user: &str,
+    ) -> Result<RestoreSummary, anyhow::Error> {
+        let _guard = self.write_lock.lock().await;
+        let data_type = DataType::Form(template.clone());
+        let sub_path = self.sub_path(&data_type, "");
+        let mut summary = RestoreSummary::default();
 
-        if fs::metadata(&path).await.is_err() {
-            return Ok(vec![]);
-        }
+        for transaction in self.transaction_log.latest_deletes(&data_type).await? {
+            let deleted = match self.raw_get(&transaction.new_path, &sub_path).await {
+                Ok(bytes) => bytes,
+                Err(_) => continue,
+            };
+            let form: Form = serde_json::from_slice(&deleted)?;
 
-        if std::fs::read_dir(&path)?.count() < 1 {
-            return Ok(vec![]);
-        }
+            if !filter.matches(&form) {
+                continue;
+            }
 
-        let path = ListingTableUrl::parse(path)?;
-        let state = self.df_ctx.state();
-        let file_format = JsonFormat::default();
-        let listing_options =
-            ListingOptions::new(Arc::new(file_format)).with_file_extension(".current");
-        let schema = listing_options.infer_schema(&state, &path).await?;
-        let config = ListingTableConfig::new(path)
-            .with_listing_options(listing_options)
-            .with_schema(schema);
-        let provider = Arc::new(ListingTable::try_new(config)?);
+            self.write_version(
+                data_type.clone(),
+                Action::Add,
+                transaction.alt_key(),
+                &deleted,
+                user,
+            )
+            .await?;
+            fs::remove_file(format!("{}{}", self.dir(&sub_path), transaction.new_path)).await?;
 
-        let df = self.df_ctx.read_table(provider)?;
+            summary.restored += 1;
+            summary.ids.extend(form.id);
+        }
 
-        let mut df_filter = col("fields").is_not_null();
+        Ok(summary)
+    }
 
-        if let Some(f) = filter.event {
-            df_filter = df_filter.and(col("event_key").eq(lit(f)));
-        }
-        if let Some(f) = filter.scouter {
-            df_filter = df_filter.and(col("scouter").eq(lit(f)));
-        }
-        if let Some(f) = filter.match_number {
-            df_filter = df_filter.and(col("match_number").eq(lit(f)));
-        }
-        if let Some(f) = filter.team {
-            df_filter = df_filter.and(col("team").eq(lit(f)));
-        }
+    /// Merges `partial` over the latest version of a form and writes the result
+    /// as an edit. Fails with a version conflict if the form changes in between.
+    #[instrument(skip(self, partial))]
+    pub async fn forms_patch(
+        &self,
+        template: String,
+        id: String,
+        partial: HashMap<String, FieldData>,
+        user: &str,
+    ) -> Result<Uuid, anyhow::Error> {
+        let current = self
+            .latest_blob_from_alt_key(DataType::Form(template.clone()), &(&id).digest())
+            .await?;
+        let mut form = self.forms_get(template.clone(), id.clone()).await?;
 
-        let res = df.filter(df_filter)?.collect().await?;
+        form.merge_fields(partial);
 
-        let res: Vec<&RecordBatch> = res.iter().collect();
-        let res = record_batches_to_json_rows(res.as_slice())?;
-        let ser = serde_json::to_string(&res)?;
+        self.forms_edit(template, form, id, current, user).await
+    }
+
+    /// Deletes a form. Runs under the write lock, so it cannot land between an
+    /// edit's version check and its write.
+    #[instrument(skip(self))]
+    pub async fn forms_delete(
+        &self,
+        template: String,
+        id: String,
+        user: &str,
+    ) -> Result<(), anyhow::Error> {
+        let _guard = self.write_lock.lock().await;
 
-        serde_json::from_str(&ser).map_err(Into::into)
+        self.forms_delete_locked(template, id, user).await
     }
 
-    #[instrument(skip(self, schedule))]
-    pub async fn schedules_add(&self, schedule: Schedule) -> Result<(), anyhow::Error> {
-        let digested_name = (&schedule.event).digest();
-        let digested_name = format!("{}.current", digested_name);
+    /// [`Self::forms_delete`] for callers already holding the write lock.
+    async fn forms_delete_locked(
+        &self,
+        template: String,
+        id: String,
+        user: &str,
+    ) -> Result<(), anyhow::Error> {
+        let dig = id.digest();
+        let old = format!("{}.{}", &dig, Uuid::new_v4());
+        let digested = format!("{}.current", &dig);
 
-        self.raw_add(
-            &digested_name,
-            "schedules/",
-            serde_json::to_string(&schedule)?.as_bytes(),
+        self.raw_delete(
+            &digested,
+            &old,
+            &format!("forms/{}.current/", (&template).digest()),
         )
         .await?;
 
         self.transaction_log
             .log_transaction(InternalMessage::new(
-                DataType::Schedule,
-                Action::Add,
-                digested_name,
+                DataType::Form(template.clone()),
+                Action::Delete,
+                old,
+                user,
             ))
-            .await
+            .await?;
+
+        self.metrics
+            .forms_deleted
+            .add(1, &[KeyValue::new("template", template)]);
+
+        Ok(())
     }
 
-    #[instrument(skip(self, schedule))]
-    pub async fn schedules_edit(&self, schedule: Schedule) -> Result<(), anyhow::Error> {
-        let digested_name = (&schedule.event).digest();
-        let old = format!("{}.{}", &digested_name, Uuid::new_v4());
-        let digested_name = format!("{}.current", digested_name);
+    /// Resolves a directory against the storage root. Absolute directories are
+    /// used as they are.
+    fn dir(&self, sub_path: &str) -> String {
+        Path::new(&self.path)
+            .join(sub_path)
+            .to_string_lossy()
+            .to_string()
+    }
 
-        self.raw_edit(
-            &digested_name,
-            &old,
-            "schedules/",
-            serde_json::to_string(&schedule)?.as_bytes(),
-        )
-        .await?;
+    fn bytes_dir(&self) -> String {
+        match self.bytes_dir.as_str() {
+            "" => default_bytes_dir(),
+            dir if dir.ends_with('/') => dir.to_string(),
+            dir => format!("{dir}/"),
+        }
+    }
 
-        self.transaction_log
-            .log_transaction(InternalMessage::new(DataType::Schedule, Action::Edit, old))
-            .await
+    /// The directory, relative to the storage root, that an item lives in.
+    fn sub_path(&self, data_type: &DataType, name: &str) -> String {
+        match data_type {
+            DataType::Form(template) => format!("forms/{}.current/", template.digest()),
+            DataType::Template => "templates/".into(),
+            DataType::Schedule => "schedules/".into(),
+            DataType::Bytes => self.bytes_shard_dir(name),
+        }
+    }
+
+    /// The sharded directory under the bytes directory that a blob lives in.
+    fn bytes_shard_dir(&self, name: &str) -> String {
+        format!("{}{}", self.bytes_dir(), shard(name))
     }
 
+    /// Permanently removes deleted items whose retention window has passed.
+    /// Deletes logged before transactions carried a timestamp are never collected.
     #[instrument(skip(self))]
-    pub async fn schedules_delete(&self, name: String) -> Result<(), anyhow::Error> {
-        let digested_name = (&name).digest();
-        let old = format!("{}.{}", &digested_name, Uuid::new_v4());
-        let digested_name = format!("{}.current", digested_name);
+    pub async fn gc_blobs(&self) -> Result<usize, anyhow::Error> {
+        let now = Utc::now().timestamp_micros();
+        let mut removed = 0;
+        let transactions = self.transaction_log.read_all().await?;
 
-        self.raw_delete(&digested_name, &old, "schedules/").await?;
+        for transaction in transactions.iter().cloned() {
+            if transaction.action != Action::Delete || transaction.timestamp == 0 {
+                continue;
+            }
 
-        self.transaction_log
-            .log_transaction(InternalMessage::new(
-                DataType::Schedule,
-                Action::Delete,
-                old,
-            ))
-            .await
+            let days = match self.retention.days_for(&transaction.data_type) {
+                None => continue,
+                Some(days) => days as i64,
+            };
+
+            if now - transaction.timestamp < days * 24 * 60 * 60 * 1_000_000 {
+                continue;
+            }
+
+            let path = format!(
+                "{}{}",
+                self.dir(&self.sub_path(&transaction.data_type, &transaction.new_path)),
+                transaction.new_path
+            );
+
+            if fs::metadata(&path).await.is_ok() {
+                info!("Collecting {path}");
+                fs::remove_file(&path).await?;
+                removed += 1;
+            }
+
+            // the versions written before the delete go with it, unless the
+            // item has been written again since
+            let latest = self
+                .transaction_log
+                .latest_for(&transaction.data_type, transaction.alt_key())
+                .await?;
+            if latest.is_some_and(|t| t.id == transaction.id) {
+                for version in transactions.iter().filter(|t| {
+                    t.data_type == transaction.data_type
+                        && t.alt_key() == transaction.alt_key()
+                        && matches!(t.action, Action::Add | Action::Edit | Action::Superseded)
+                        && !t.new_path.ends_with(".current")
+                }) {
+                    let path = format!(
+                        "{}{}",
+                        self.dir(&self.sub_path(&version.data_type, &version.new_path)),
+                        version.new_path
+                    );
+
+                    if fs::metadata(&path).await.is_ok() {
+                        info!("Collecting {path}");
+                        fs::remove_file(&path).await?;
+                    }
+                }
+            }
+
+            if transaction.data_type == DataType::Template {
+                let forms = format!("{}forms/{}", self.path, transaction.new_path);
+
+                if fs::metadata(&forms).await.is_ok() {
+                    fs::remove_dir_all(&forms).await?;
+                }
+            }
+        }
+
+        Ok(removed)
     }
 
+    /// The id of the latest transaction for an item, which identifies the
+    /// version of the item currently on disk.
     #[instrument(skip(self))]
-    pub async fn schedules_get(&self, name: String) -> Result<Schedule, anyhow::Error> {
-        let digested_name = (&name).digest();
-        let digested_name = format!("{}.current", digested_name);
+    pub async fn latest_blob_from_alt_key(
+        &self,
+        data_type: DataType,
+        alt_key: &str,
+    ) -> Result<Option<Uuid>, anyhow::Error> {
+        Ok(self
+            .transaction_log
+            .latest_for(&data_type, alt_key)
+            .await?
+            .map(|t| t.id))
+    }
 
-        let bytes = self.raw_get(&digested_name, "schedules/").await?;
+    /// Unix seconds of the latest transaction for a template, or `None` once
+    /// the template is deleted, so a cached copy is never confirmed as fresh.
+    #[instrument(skip(self))]
+    pub async fn template_last_modified(&self, name: &str) -> Result<Option<i64>, anyhow::Error> {
+        Ok(self
+            .transaction_log
+            .latest_for(&DataType::Template, &name.digest())
+            .await?
+            .filter(|t| t.action != Action::Delete)
+            .map(|t| t.timestamp / 1_000_000))
+    }
+
+    /// Whether the latest transaction for an item is a delete. Versions are
+    /// matched on alt key, and log order decides ties between equal timestamps.
+    #[instrument(skip(self))]
+    pub async fn blob_deleted(
+        &self,
+        data_type: DataType,
+        alt_key: &str,
+    ) -> Result<bool, anyhow::Error> {
+        Ok(self
+            .transaction_log
+            .latest_for(&data_type, alt_key)
+            .await?
+            .is_some_and(|t| t.action == Action::Delete))
+    }
+
+    pub fn get_path(&self) -> &str {
+        &self.path
+    }
+
+    #[instrument(skip(self))]
+    pub async fn forms_get(&self, template: String, id: String) -> Result<Form, anyhow::Error> {
+        let digested = format!("{}.current", id.digest());
+
+        let bytes = self
+            .raw_get(
+                &digested,
+                &format!("forms/{}.current/", (&template).digest()),
+            )
+            .await?;
 
         serde_json::from_slice(bytes.as_slice()).map_err(Into::into)
     }
 
+    /// Builds a listing table over the live (`.current`) forms of a template,
+    /// returning `None` when the template has no forms to infer a schema from.
     #[instrument(skip(self))]
-    pub async fn schedules_list(&self) -> Result<Vec<String>, anyhow::Error> {
-        if !self.df_ctx.table_exist("schedules")? {
-            let path = ListingTableUrl::parse(format!("{}schedules", self.path))?;
-            let file_format = JsonFormat::default();
-            let listing_options =
-                ListingOptions::new(Arc::new(file_format)).with_file_extension(".current");
-            let schema = SchemaRef::new(Schema::new(vec![Field::new(
-                "event",
-                datafusion::arrow::datatypes::DataType::Utf8,
-                false,
-            )]));
-            let config = ListingTableConfig::new(path)
-                .with_listing_options(listing_options)
-                .with_schema(schema);
-            let provider = Arc::new(ListingTable::try_new(config)?);
+    async fn forms_table(
+        &self,
+        template: &str,
+    ) -> Result<Option<Arc<ListingTable>>, anyhow::Error> {
+        let path = format!("{}forms/{}.current/", self.path, template.digest());
 
-            self.df_ctx.register_table("schedules", provider)?;
+        if fs::metadata(&path).await.is_err() {
+            return Ok(None);
         }
 
-        let df = self.df_ctx.table("schedules").await?;
-        let res = df.select(vec![col("event")])?.collect().await?;
+        if std::fs::read_dir(&path)?.count() < 1 {
+            return Ok(None);
+        }
+
+        let path = ListingTableUrl::parse(path)?;
+        let state = self.df_ctx.state();
+        let file_format = JsonFormat::default();
+        let listing_options =
+            ListingOptions::new(Arc::new(file_format)).with_file_extension(".current");
+        let schema = listing_options.infer_schema(&state, &path).await?;
+        let config = ListingTableConfig::new(path)
+            .with_listing_options(listing_options)
+            .with_schema(schema);
+
+        Ok(Some(Arc::new(ListingTable::try_new(config)?)))
+    }
+
+    /// Ids of a template's live forms. The latest transaction the log index
+    /// holds for each form goes into a `transactions` table, and a form is live
+    /// unless the last action by `seq` is a delete. Its id is read from the file
+    /// that transaction left current.
+    #[instrument(skip(self))]
+    pub async fn forms_list(&self, template: String) -> Result<Vec<String>, anyhow::Error> {
+        let sub_path = format!("forms/{}.current/", (&template).digest());
+        let transactions = self
+            .transaction_log
+            .latest_of(&DataType::Form(template))
+            .await?;
+
+        if transactions.is_empty() {
+            return Ok(vec![]);
+        }
+
+        let batch = RecordBatch::try_new(
+            Arc::new(Schema::new(vec![
+                Field::new("alt_key", ArrowType::Utf8, false),
+                Field::new("action", ArrowType::Utf8, false),
+                Field::new("seq", ArrowType::UInt64, false),
+            ])),
+            vec![
+                Arc::new(StringArray::from_iter_values(
+                    transactions.iter().map(|t| t.alt_key()),
+                )),
+                Arc::new(StringArray::from_iter_values(
+                    transactions.iter().map(|t| format!("{:?}", t.action)),
+                )),
+                Arc::new(UInt64Array::from_iter_values(
+                    transactions.iter().map(|t| t.seq),
+                )),
+            ],
+        )?;
+
+        // df_ctx is shared between requests, so the table is registered on a
+        // context of this call's own
+        let ctx = SessionContext::new_with_config(self.df_ctx.copied_config());
+        ctx.register_table(
+            "transactions",
+            Arc::new(MemTable::try_new(batch.schema(), vec![vec![batch]])?),
+        )?;
+
+        let live = ctx
+            .sql(
+                "select alt_key from transactions
+                group by alt_key
+                having last_value(action order by seq) != 'Delete'",
+            )
+            .await?
+            .collect()
+            .await?;
+
+        let live: Vec<&RecordBatch> = live.iter().collect();
+        let mut res = vec![];
+
+        for alt_key in record_batches_to_json_rows(live.as_slice())?
+            .iter()
+            .filter_map(|row| row.get("alt_key").and_then(Value::as_str))
+        {
+            let bytes = match self.raw_get(&format!("{alt_key}.current"), &sub_path).await {
+                Ok(bytes) => bytes,
+                // forms of a deleted template are moved out with its directory
+                Err(e)
+                    if e.downcast_ref::<std::io::Error>()
+                        .is_some_and(|e| e.kind() == std::io::ErrorKind::NotFound) =>
+                {
+                    continue
+                }
+                Err(e) => return Err(e),
+            };
+
+            if let Some(id) = serde_json::from_slice::<Form>(&bytes)?.id {
+                res.push(id);
+            }
+        }
+
+        Ok(res)
+    }
+
+    /// Live form ids of a template along with their latest transaction.
+    #[instrument(skip(self))]
+    pub async fn forms_list_detailed(
+        &self,
+        template: String,
+    ) -> Result<Vec<FormSummary>, anyhow::Error> {
+        let data_type = DataType::Form(template.clone());
+        let mut res = vec![];
+
+        for id in self.forms_list(template).await? {
+            if let Some(latest) = self
+                .transaction_log
+                .latest_for(&data_type, &(&id).digest())
+                .await?
+            {
+                res.push(FormSummary {
+                    id,
+                    last_modified: latest.timestamp,
+                    last_action: latest.action,
+                });
+            }
+        }
+
+        Ok(res)
+    }
+
+    /// Sorted distinct event keys across the live forms of a template.
+    #[instrument(skip(self))]
+    pub async fn list_events(&self, template: String) -> Result<Vec<String>, anyhow::Error> {
+        let provider = match self.forms_table(&template).await? {
+            None => return Ok(vec![]),
+            Some(provider) => provider,
+        };
+
+        let df = self.df_ctx.read_table(provider)?;
+        let res = df
+            .select(vec![col("event_key")])?
+            .distinct()?
+            .sort(vec![col("event_key").sort(true, false)])?
+            .collect()
+            .await?;
 
         let res: Vec<&RecordBatch> = res.iter().collect();
 
@@ -401,7 +1234,7 @@ impl StorageManager {
 
         let res = res
             .iter()
-            .filter_map(|m| m.get("event"))
+            .filter_map(|m| m.get("event_key"))
             .filter_map(|thing| match thing {
                 Value::String(s) => Some(s.clone()),
                 _ => None,
@@ -411,316 +1244,4414 @@ impl StorageManager {
         Ok(res)
     }
 
-    #[instrument(skip(self, template))]
-    pub async fn templates_add(&self, template: FormTemplate) -> Result<(), anyhow::Error> {
-        let digested_name = (&template.name).digest();
-        let digested_name = format!("{}.current", digested_name);
+    /// Every scouter with live forms for a template, optionally scoped to an
+    /// event, most active first.
+    #[instrument(skip(self))]
+    pub async fn list_scouters(
+        &self,
+        template: String,
+        event: Option<String>,
+    ) -> Result<Vec<ScouterActivity>, anyhow::Error> {
+        let forms = self
+            .forms_filter(
+                template,
+                Filter {
+                    event,
+                    ..Default::default()
+                },
+            )
+            .await?;
 
-        self.raw_add(
-            &digested_name,
-            "templates/",
-            serde_json::to_string(&template)?.as_bytes(),
-        )
-        .await?;
+        let mut counts: HashMap<String, usize> = HashMap::new();
 
-        self.template_dir(&digested_name, None).await?;
+        for form in forms {
+            *counts.entry(form.scouter).or_default() += 1;
+        }
 
-        self.transaction_log
-            .log_transaction(InternalMessage::new(
-                DataType::Template,
-                Action::Add,
-                digested_name,
-            ))
-            .await
+        let mut res: Vec<ScouterActivity> = counts
+            .into_iter()
+            .map(|(scouter, forms)| ScouterActivity { scouter, forms })
+            .collect();
+
+        res.sort_by(|a, b| {
+            b.forms
+                .cmp(&a.forms)
+                .then_with(|| a.scouter.cmp(&b.scouter))
+        });
+
+        Ok(res)
+    }
+
+    /// Returns the expected `(match_number, team)` pairs of an event that have no
+    /// live form, ordered by match then station.
+    #[instrument(skip(self, matches))]
+    pub async fn coverage(
+        &self,
+        template: String,
+        event: String,
+        matches: Vec<ExpectedMatch>,
+    ) -> Result<Vec<MissingCoverage>, anyhow::Error> {
+        let scouted: HashSet<(i64, i64)> = self
+            .forms_filter(
+                template,
+                Filter {
+                    event: Some(event),
+                    ..Default::default()
+                },
+            )
+            .await?
+            .into_iter()
+            .map(|f| (f.match_number, f.team))
+            .collect();
+
+        let mut missing: Vec<MissingCoverage> = matches
+            .into_iter()
+            .flat_map(|m| {
+                m.teams
+                    .into_iter()
+                    .enumerate()
+                    .map(move |(station, team)| MissingCoverage {
+                        match_number: m.match_number,
+                        station,
+                        team,
+                    })
+            })
+            .filter(|m| !scouted.contains(&(m.match_number, m.team)))
+            .collect();
+
+        missing.sort();
+
+        Ok(missing)
+    }
+
+    /// Groups the live forms by match and team, returning the groups with more
+    /// than one form, ordered by match then team.
+    #[instrument(skip(self))]
+    pub async fn find_duplicate_scouting(
+        &self,
+        template: String,
+        event: Option<String>,
+    ) -> Result<Vec<DuplicateScouting>, anyhow::Error> {
+        let forms = self
+            .forms_filter(
+                template,
+                Filter {
+                    event,
+                    ..Default::default()
+                },
+            )
+            .await?;
+
+        let mut groups: BTreeMap<(i64, i64), Vec<Form>> = BTreeMap::new();
+
+        for form in forms {
+            groups
+                .entry((form.match_number, form.team))
+                .or_default()
+                .push(form);
+        }
+
+        Ok(groups
+            .into_iter()
+            .filter(|(_, forms)| forms.len() > 1)
+            .map(|((match_number, team), forms)| DuplicateScouting {
+                match_number,
+                team,
+                scouters: forms.iter().map(|f| f.scouter.clone()).collect(),
+                ids: forms.into_iter().filter_map(|f| f.id).collect(),
+            })
+            .collect())
+    }
+
+    /// Scores a scouter against everyone else who scouted the same team in the
+    /// same match. Each numeric field's deviation from the median of all
+    /// scouters' values is taken relative to that median (or 1, if smaller), and
+    /// the accuracy is `1 / (1 + mean deviation)`. Matches the scouter was
+    /// alone on are excluded, as there is nothing to compare them to.
+    #[instrument(skip(self))]
+    pub async fn compute_scouter_accuracy(
+        &self,
+        scouter: String,
+        template: String,
+    ) -> Result<Scouter, anyhow::Error> {
+        let forms = self.forms_filter(template, Filter::default()).await?;
+
+        let mut by_match: HashMap<(&str, i64, i64), Vec<&Form>> = HashMap::new();
+
+        for form in &forms {
+            by_match
+                .entry((&form.event_key, form.match_number, form.team))
+                .or_default()
+                .push(form);
+        }
+
+        let mut deviations = vec![];
+        let mut matches = 0;
+
+        for group in by_match.values() {
+            let Some(own) = group.iter().find(|f| f.scouter == scouter) else {
+                continue;
+            };
+
+            if group.iter().all(|f| f.scouter == scouter) {
+                continue;
+            }
+
+            matches += 1;
+
+            for (name, value) in own.numeric_fields() {
+                let values: Vec<f64> = group
+                    .iter()
+                    .filter_map(|f| f.get_field(name).and_then(FieldData::as_number))
+                    .collect();
+
+                let median = FieldStats::from_values(&values).median;
+                deviations.push((value - median).abs() / median.abs().max(1.0));
+            }
+        }
+
+        let accuracy = (!deviations.is_empty()).then(|| {
+            let mean = deviations.iter().sum::<f64>() / deviations.len() as f64;
+            (1.0 / (1.0 + mean)) as f32
+        });
+
+        Ok(Scouter {
+            scouter,
+            accuracy,
+            matches,
+        })
+    }
+
+    /// Lazily reads every live form of a template, one file at a time.
+    #[instrument(skip(self))]
+    pub async fn forms_stream(
+        &self,
+        template: String,
+    ) -> Result<impl Stream<Item = Result<Form, anyhow::Error>>, anyhow::Error> {
+        let entries =
+            fs::read_dir(format!("{}forms/{}.current", self.path, template.digest())).await?;
+
+        Ok(stream::unfold(entries, |mut entries| async move {
+            loop {
+                match entries.next_entry().await {
+                    Err(e) => return Some((Err(e.into()), entries)),
+                    Ok(None) => return None,
+                    Ok(Some(entry)) => {
+                        if !entry.file_name().to_string_lossy().ends_with(".current") {
+                            continue;
+                        }
+
+                        let form = match fs::read(entry.path()).await {
+                            Ok(bytes) => serde_json::from_slice(&bytes).map_err(Into::into),
+                            Err(e) => Err(e.into()),
+                        };
+
+                        return Some((form, entries));
+                    }
+                }
+            }
+        }))
+    }
+
+    /// Live forms whose text field `field_name` contains `needle`, ignoring case.
+    #[instrument(skip(self))]
+    pub async fn forms_text_search(
+        &self,
+        template: String,
+        field_name: String,
+        needle: String,
+    ) -> Result<Vec<Form>, anyhow::Error> {
+        let form_template = self.templates_get(template.clone()).await?;
+
+        if !form_template
+            .get_field(&field_name)
+            .is_some_and(|f| f.is_text())
+        {
+            return Err(StorageError::FieldNotText(field_name).into());
+        }
+
+        let needle = needle.to_lowercase();
+        let mut forms = Box::pin(self.forms_stream(template).await?);
+        let mut res = vec![];
+
+        while let Some(form) = forms.next().await {
+            let form = form?;
+
+            if form
+                .get_field(&field_name)
+                .and_then(|data| data.as_text())
+                .is_some_and(|text| text.to_lowercase().contains(&needle))
+            {
+                res.push(form);
+            }
+        }
+
+        Ok(res)
+    }
+
+    #[instrument(skip(self))]
+    pub async fn forms_filter(
+        &self,
+        template: String,
+        filter: Filter,
+    ) -> Result<Vec<Form>, anyhow::Error> {
+        if let Some(key) = &filter.sort_by {
+            if !SORT_KEYS.contains(&key.as_str()) {
+                return Err(StorageError::UnknownSortKey(key.clone()).into());
+            }
+        }
+
+        let provider = match self.forms_table(&template).await? {
+            None => return Ok(vec![]),
+            Some(provider) => provider,
+        };
+
+        let df = self.df_ctx.read_table(provider)?;
+
+        let mut df_filter = col("fields").is_not_null();
+
+        // When the template materializes a filtered field into the indexed column
+        // the query matches on it. Forms written before the field was indexed
+        // have no entry, so they, and every form when the field is not indexed,
+        // are matched on their deserialized fields instead.
+        let mut post_filter = None;
+
+        if let (Some(field), Some(value)) = (filter.field, filter.value) {
+            let form_template = self.templates_get(template).await?;
+            let stored_indexed = df
+                .schema()
+                .field_with_unqualified_name("indexed")
+                .ok()
+                .is_some_and(|indexed| match indexed.data_type() {
+                    datafusion::arrow::datatypes::DataType::Struct(fields) => {
+                        fields.iter().any(|f| f.name() == &field)
+                    }
+                    _ => false,
+                });
+
+            let in_query = form_template.is_indexed(&field) && stored_indexed;
+
+            if in_query {
+                let indexed = col("indexed").field(field.as_str());
+                df_filter = df_filter.and(indexed.clone().eq(lit(value)).or(indexed.is_null()));
+            }
+
+            post_filter = Some((field, value, in_query));
+        }
+
+        if let Some(f) = filter.event {
+            df_filter = df_filter.and(col("event_key").eq(lit(f)));
+        }
+        if let Some(f) = filter.scouter {
+            df_filter = df_filter.and(col("scouter").eq(lit(f)));
+        }
+        if let Some(f) = filter.match_number {
+            df_filter = df_filter.and(col("match_number").eq(lit(f)));
+        }
+        if let Some(f) = filter.team {
+            df_filter = df_filter.and(col("team").eq(lit(f)));
+        }
+        if let Some(f) = filter.template_version {
+            // no stored form carries a version yet
+            if !df
+                .schema()
+                .has_column_with_unqualified_name("template_version")
+            {
+                return Ok(vec![]);
+            }
+
+            df_filter = df_filter.and(col("template_version").eq(lit(f)));
+        }
+
+        let mut df = df.filter(df_filter)?;
+
+        if let Some(key) = filter.sort_by {
+            let ascending = filter.sort_dir.unwrap_or_default() == SortDir::Asc;
+            df = df.sort(vec![col(key).sort(ascending, false)])?;
+        }
+
+        let res = df.collect().await?;
+
+        let res: Vec<&RecordBatch> = res.iter().collect();
+        let res = record_batches_to_json_rows(res.as_slice())?;
+        let ser = serde_json::to_string(&res)?;
+
+        let forms: Vec<Form> = serde_json::from_str(&ser)?;
+
+        Ok(match post_filter {
+            None => forms,
+            Some((field, value, in_query)) => forms
+                .into_iter()
+                .filter(|form| {
+                    (in_query && form.indexed.contains_key(&field))
+                        || form.get_field(&field).and_then(|data| data.as_number())
+                            == Some(value as f64)
+                })
+                .collect(),
+        })
+    }
+
+    /// Serializes the filtered forms as an Arrow IPC stream with typed columns
+    /// derived from the template.
+    #[instrument(skip(self))]
+    pub async fn forms_arrow(
+        &self,
+        template: String,
+        filter: Filter,
+    ) -> Result<Vec<u8>, anyhow::Error> {
+        let form_template = self.templates_get(template.clone()).await?;
+        let forms = self.forms_filter(template, filter).await?;
+        let batch = form_template.record_batch(&forms)?;
+
+        let mut buf = vec![];
+        let mut writer = StreamWriter::try_new(&mut buf, &batch.schema())?;
+        writer.write(&batch)?;
+        writer.finish()?;
+        drop(writer);
+
+        Ok(buf)
+    }
+
+    /// Writes the filtered forms as a Parquet file, with the same columns as
+    /// [`Self::forms_arrow`].
+    #[instrument(skip(self))]
+    pub async fn forms_parquet(
+        &self,
+        template: String,
+        filter: Filter,
+    ) -> Result<Vec<u8>, anyhow::Error> {
+        let form_template = self.templates_get(template.clone()).await?;
+        let forms = self.forms_filter(template, filter).await?;
+        let batch = form_template.record_batch(&forms)?;
+
+        let mut buf = vec![];
+        let mut writer = ArrowWriter::try_new(&mut buf, batch.schema(), None)?;
+        writer.write(&batch)?;
+        writer.close()?;
+
+        Ok(buf)
+    }
+
+    /// Runs a single read-only `SELECT` against the template's live forms,
+    /// registered as the table `forms` with the columns of [`Self::forms_arrow`].
+    /// Results are capped at [`QUERY_ROW_LIMIT`] rows and [`QUERY_TIMEOUT`].
+    #[instrument(skip(self))]
+    pub async fn forms_query(
+        &self,
+        template: String,
+        sql: String,
+    ) -> Result<Vec<Map<String, Value>>, anyhow::Error> {
+        let statements = Parser::parse_sql(&GenericDialect {}, &sql)
+            .map_err(|e| StorageError::InvalidQuery(e.to_string()))?;
+
+        match statements.as_slice() {
+            [Statement::Query(_)] => {}
+            [_] => Err(StorageError::InvalidQuery("only SELECT is allowed".into()))?,
+            _ => Err(StorageError::InvalidQuery(
+                "expected exactly one statement".into(),
+            ))?,
+        }
+
+        let batch = self.forms_batch(template).await?;
+
+        let ctx = SessionContext::new();
+        let table = MemTable::try_new(batch.schema(), vec![vec![batch]])?;
+        ctx.register_table("forms", Arc::new(table))?;
+
+        let df = ctx
+            .sql(&sql)
+            .await
+            .map_err(|e| StorageError::InvalidQuery(e.to_string()))?
+            .limit(0, Some(QUERY_ROW_LIMIT))?;
+
+        let res = tokio::time::timeout(QUERY_TIMEOUT, df.collect())
+            .await
+            .map_err(|_| StorageError::QueryTimedOut)?
+            .map_err(|e| StorageError::InvalidQuery(e.to_string()))?;
+
+        let res: Vec<&RecordBatch> = res.iter().collect();
+
+        Ok(record_batches_to_json_rows(res.as_slice())?)
+    }
+
+    /// The template's live forms as a record batch, only rebuilt after a
+    /// transaction for one of its forms or for any template. The cache is only
+    /// locked around lookups, so a rebuild does not hold up other queries.
+    #[instrument(skip(self))]
+    async fn forms_batch(&self, template: String) -> Result<RecordBatch, anyhow::Error> {
+        let attributes = [KeyValue::new("template", template.clone())];
+
+        {
+            let mut cache = self.query_cache.lock().await;
+            if cache.events.is_none() {
+                cache.events = Some(self.subscribe());
+            }
+            cache.drain(&template);
+
+            if let Some(batch) = cache.batches.get(&template) {
+                self.metrics.query_cache_hits.add(1, &attributes);
+                return Ok(batch.clone());
+            }
+        }
+
+        self.metrics.query_cache_misses.add(1, &attributes);
+
+        let form_template = self.templates_get(template.clone()).await?;
+        let forms = self
+            .forms_filter(template.clone(), Filter::default())
+            .await?;
+        let batch = form_template.record_batch(&forms)?;
+
+        // a transaction logged during the rebuild may not be in the batch
+        let mut cache = self.query_cache.lock().await;
+        if !cache.drain(&template) {
+            cache.batches.insert(template, batch.clone());
+        }
+
+        Ok(batch)
+    }
+
+    /// Gathers everything a match-day UI needs on load for one event. At most
+    /// [`DASHBOARD_LATEST_LIMIT`] of the template's latest form transactions
+    /// are included.
+    #[instrument(skip(self))]
+    pub async fn dashboard(
+        &self,
+        template: String,
+        event: Option<String>,
+        latest: usize,
+    ) -> Result<Dashboard, anyhow::Error> {
+        let data_type = DataType::Form(template.clone());
+        let forms = self
+            .forms_filter(
+                template,
+                Filter {
+                    event,
+                    ..Default::default()
+                },
+            )
+            .await?;
+
+        Ok(Dashboard::new(
+            &forms,
+            self.transaction_log
+                .latest(&data_type, latest.min(DASHBOARD_LATEST_LIMIT))
+                .await?,
+        ))
+    }
+
+    #[instrument(skip(self))]
+    pub async fn team_field_stats(
+        &self,
+        template: String,
+        team: i64,
+        field_name: String,
+        event: Option<String>,
+    ) -> Result<FieldStats, anyhow::Error> {
+        let form_template = self.templates_get(template.clone()).await?;
+
+        if !form_template
+            .get_field(&field_name)
+            .is_some_and(|f| f.is_numeric())
+        {
+            return Err(StorageError::FieldNotNumeric(field_name).into());
+        }
+
+        let forms = self
+            .forms_filter(
+                template,
+                Filter {
+                    team: Some(team),
+                    event,
+                    ..Default::default()
+                },
+            )
+            .await?;
+
+        let values: Vec<f64> = forms
+            .iter()
+            .filter_map(|form| form.get_field(&field_name))
+            .filter_map(|data| data.as_number())
+            .collect();
+
+        Ok(FieldStats::from_values(&values))
+    }
+
+    #[instrument(skip(self))]
+    pub async fn team_event_comparison(
+        &self,
+        template: String,
+        team: i64,
+        field_name: String,
+        event_a: String,
+        event_b: String,
+    ) -> Result<EventComparison, anyhow::Error> {
+        let a = self
+            .team_field_stats(template.clone(), team, field_name.clone(), Some(event_a))
+            .await?;
+        let b = self
+            .team_field_stats(template, team, field_name, Some(event_b))
+            .await?;
+
+        Ok(EventComparison::new(a, b))
+    }
+
+    #[instrument(skip(self, schedule))]
+    pub async fn schedules_add(&self, schedule: Schedule, user: &str) -> Result<(), anyhow::Error> {
+        self.write_version(
+            DataType::Schedule,
+            Action::Add,
+            &(&schedule.event).digest(),
+            serde_json::to_string(&schedule)?.as_bytes(),
+            user,
+        )
+        .await
+        .map_err(|e| already_exists(e, DataType::Schedule, &schedule.event))?;
+
+        Ok(())
+    }
+
+    #[instrument(skip(self, schedule))]
+    pub async fn schedules_edit(
+        &self,
+        schedule: Schedule,
+        user: &str,
+    ) -> Result<(), anyhow::Error> {
+        self.write_version(
+            DataType::Schedule,
+            Action::Edit,
+            &(&schedule.event).digest(),
+            serde_json::to_string(&schedule)?.as_bytes(),
+            user,
+        )
+        .await?;
+
+        Ok(())
+    }
+
+    #[instrument(skip(self))]
+    pub async fn schedules_delete(&self, name: String, user: &str) -> Result<(), anyhow::Error> {
+        let digested_name = (&name).digest();
+        let old = format!("{}.{}", &digested_name, Uuid::new_v4());
+        let digested_name = format!("{}.current", digested_name);
+
+        self.raw_delete(&digested_name, &old, "schedules/").await?;
+
+        self.transaction_log
+            .log_transaction(InternalMessage::new(
+                DataType::Schedule,
+                Action::Delete,
+                old,
+                user,
+            ))
+            .await
+    }
+
+    /// What `schedules_delete` would do, without writing anything.
+    #[instrument(skip(self))]
+    pub async fn schedules_delete_preview(
+        &self,
+        name: String,
+        user: &str,
+    ) -> Result<DeletePreview, anyhow::Error> {
+        let schedule = self.schedules_get(name.clone()).await?;
+        let old = format!("{}.{}", (&name).digest(), Uuid::new_v4());
+
+        Ok(DeletePreview {
+            forms: None,
+            shifts: Some(schedule.shifts.len()),
+            transaction: InternalMessage::new(DataType::Schedule, Action::Delete, old, user),
+        })
+    }
+
+    #[instrument(skip(self))]
+    pub async fn schedules_get(&self, name: String) -> Result<Schedule, anyhow::Error> {
+        let digested_name = (&name).digest();
+        let digested_name = format!("{}.current", digested_name);
+
+        let bytes = self.raw_get(&digested_name, "schedules/").await?;
+
+        serde_json::from_slice(bytes.as_slice()).map_err(Into::into)
+    }
+
+    #[instrument(skip(self))]
+    pub async fn schedules_list(&self) -> Result<Vec<String>, anyhow::Error> {
+        if !self.df_ctx.table_exist(SCHEDULES_TABLE)? {
+            let path = ListingTableUrl::parse(format!("{}schedules", self.path))?;
+            let file_format = JsonFormat::default();
+            let listing_options =
+                ListingOptions::new(Arc::new(file_format)).with_file_extension(".current");
+            let schema = SchemaRef::new(Schema::new(vec![Field::new(
+                "event",
+                datafusion::arrow::datatypes::DataType::Utf8,
+                false,
+            )]));
+            let config = ListingTableConfig::new(path)
+                .with_listing_options(listing_options)
+                .with_schema(schema);
+            let provider = Arc::new(ListingTable::try_new(config)?);
+
+            self.df_ctx.register_table(SCHEDULES_TABLE, provider)?;
+        }
+
+        let df = self.df_ctx.table("schedules").await?;
+        let res = df.select(vec![col("event")])?.collect().await?;
+
+        let res: Vec<&RecordBatch> = res.iter().collect();
+
+        let res = record_batches_to_json_rows(res.as_slice())?;
+
+        let res = res
+            .iter()
+            .filter_map(|m| m.get("event"))
+            .filter_map(|thing| match thing {
+                Value::String(s) => Some(s.clone()),
+                _ => None,
+            })
+            .collect();
+
+        Ok(res)
+    }
+
+    #[instrument(skip(self, template))]
+    pub async fn templates_add(
+        &self,
+        template: FormTemplate,
+        user: &str,
+    ) -> Result<(), anyhow::Error> {
+        Self::check_template_name(&template.name)?;
+        Self::check_template_defaults(&template)?;
+        self.check_template_size(&template)?;
+
+        let mut template = template;
+        template.version = 1;
+
+        let digested_name = (&template.name).digest();
+
+        // the forms directory exists before the add is logged, so forms can be
+        // stored as soon as the template is visible
+        let forms_dir = format!("{digested_name}.current");
+        self.template_dir(&forms_dir, None)
+            .await
+            .map_err(|e| already_exists(e, DataType::Template, &template.name))?;
+
+        let written = self
+            .write_version(
+                DataType::Template,
+                Action::Add,
+                &digested_name,
+                serde_json::to_string(&template)?.as_bytes(),
+                user,
+            )
+            .await;
+
+        if let Err(e) = written {
+            let _ = fs::remove_dir(format!("{}forms/{forms_dir}", self.path)).await;
+            return Err(already_exists(e, DataType::Template, &template.name));
+        }
+
+        Ok(())
+    }
+
+    /// Applies a JSON Patch to a stored template and saves the result as an
+    /// edit. The patch may not rename the template.
+    #[instrument(skip(self, operations))]
+    pub async fn templates_patch(
+        &self,
+        name: String,
+        operations: &[PatchOperation],
+        force: bool,
+        user: &str,
+    ) -> Result<(), anyhow::Error> {
+        // held from reading the template through writing the patched one, so
+        // a concurrent edit or patch cannot be overwritten
+        let _guard = self.write_lock.lock().await;
+
+        let mut doc = serde_json::to_value(self.templates_get(name.clone()).await?)?;
+
+        patch::apply(&mut doc, operations).map_err(StorageError::InvalidPatch)?;
+
+        let template: FormTemplate =
+            serde_json::from_value(doc).map_err(|e| StorageError::InvalidPatch(e.to_string()))?;
+
+        if template.name != name {
+            return Err(StorageError::InvalidPatch("templates cannot be renamed".into()).into());
+        }
+
+        self.write_template_edit(template, force, user).await
+    }
+
+    /// Replaces a template. Unless `force` is set, the edit is refused if any
+    /// stored form would no longer validate against the new template.
+    #[instrument(skip(self, template))]
+    pub async fn templates_edit(
+        &self,
+        template: FormTemplate,
+        force: bool,
+        user: &str,
+    ) -> Result<(), anyhow::Error> {
+        // held from the form check and the version read through the write, so
+        // no form is added in between and concurrent edits get distinct versions
+        let _guard = self.write_lock.lock().await;
+
+        self.write_template_edit(template, force, user).await
+    }
+
+    /// The body of `templates_edit`, for callers already holding `write_lock`.
+    async fn write_template_edit(
+        &self,
+        template: FormTemplate,
+        force: bool,
+        user: &str,
+    ) -> Result<(), anyhow::Error> {
+        Self::check_template_name(&template.name)?;
+        Self::check_template_defaults(&template)?;
+        self.check_template_size(&template)?;
+
+        if !force {
+            let broken: Vec<String> = self
+                .forms_filter(template.name.clone(), Filter::default())
+                .await?
+                .iter()
+                .filter(|form| self.check_form(&template, form).is_err())
+                .map(|form| form.id.clone().unwrap_or_default())
+                .collect();
+
+            if !broken.is_empty() {
+                return Err(StorageError::FormsWouldBreak(broken).into());
+            }
+        }
+
+        let mut template = template;
+        template.version = self.templates_get(template.name.clone()).await?.version + 1;
+
+        self.write_version(
+            DataType::Template,
+            Action::Edit,
+            &(&template.name).digest(),
+            serde_json::to_string(&template)?.as_bytes(),
+            user,
+        )
+        .await?;
+
+        Ok(())
+    }
+
+    /// Deletes a template. Unless `force` is set, a template that still has
+    /// live forms is left alone.
+    #[instrument(skip(self))]
+    pub async fn templates_delete(
+        &self,
+        name: String,
+        force: bool,
+        user: &str,
+    ) -> Result<(), anyhow::Error> {
+        // held from the form count through the log, so no form is added to a
+        // template that is about to be moved out
+        let _guard = self.write_lock.lock().await;
+
+        if !force {
+            let count = self.forms_list(name.clone()).await?.len();
+
+            if count > 0 {
+                return Err(StorageError::TemplateHasForms(count).into());
+            }
+        }
+
+        let digested_name = name.digest();
+        let old = format!("{}.{}", &digested_name, Uuid::new_v4());
+        let digested_name = format!("{}.current", digested_name);
+
+        self.raw_delete(&digested_name, &old, "templates/").await?;
+
+        self.template_dir(&digested_name, Some(&old)).await?;
+
+        self.transaction_log
+            .log_transaction(InternalMessage::new(
+                DataType::Template,
+                Action::Delete,
+                old,
+                user,
+            ))
+            .await
+    }
+
+    /// What `templates_delete` would do, without writing anything. The form
+    /// count is what refuses the delete unless forced.
+    #[instrument(skip(self))]
+    pub async fn templates_delete_preview(
+        &self,
+        name: String,
+        user: &str,
+    ) -> Result<DeletePreview, anyhow::Error> {
+        self.templates_get(name.clone()).await?;
+        let forms = self.forms_list(name.clone()).await?.len();
+        let old = format!("{}.{}", name.digest(), Uuid::new_v4());
+
+        Ok(DeletePreview {
+            forms: Some(forms),
+            shifts: None,
+            transaction: InternalMessage::new(DataType::Template, Action::Delete, old, user),
+        })
+    }
+
+    #[instrument(skip(self))]
+    pub async fn templates_get(&self, name: String) -> Result<FormTemplate, anyhow::Error> {
+        let digested_name = name.digest();
+        let digested_name = format!("{}.current", digested_name);
+        let bytes = self.raw_get(&digested_name, "templates/").await?;
+
+        serde_json::from_slice(bytes.as_slice()).map_err(Into::into)
+    }
+
+    #[instrument(skip(self), ret)]
+    pub async fn templates_list(&self, year: Option<i64>) -> Result<Vec<String>, anyhow::Error> {
+        let res = self
+            .templates_rows(year)
+            .await?
+            .iter()
+            .filter_map(|m| m.get("name"))
+            .filter_map(|thing| match thing {
+                Value::String(s) => Some(s.clone()),
+                _ => None,
+            })
+            .collect();
+
+        Ok(res)
+    }
+
+    /// Templates with the number of live forms each has, including those with none.
+    #[instrument(skip(self))]
+    pub async fn templates_with_counts(
+        &self,
+        year: Option<i64>,
+    ) -> Result<Vec<TemplateCount>, anyhow::Error> {
+        let counts = self.transaction_log.live_forms_by_template().await?;
+
+        let res = self
+            .templates_rows(year)
+            .await?
+            .iter()
+            .filter_map(|m| match (m.get("name"), m.get("year")) {
+                (Some(Value::String(name)), Some(year)) => Some(TemplateCount {
+                    name: name.clone(),
+                    year: year.as_i64().unwrap_or_default(),
+                    form_count: counts.get(name).copied().unwrap_or(0),
+                }),
+                _ => None,
+            })
+            .collect();
+
+        Ok(res)
+    }
+
+    /// Name and year of every template, optionally only those of one year.
+    async fn templates_rows(
+        &self,
+        year: Option<i64>,
+    ) -> Result<Vec<Map<String, Value>>, anyhow::Error> {
+        if !self.df_ctx.table_exist(TEMPLATES_TABLE)? {
+            let path = ListingTableUrl::parse(format!("{}templates", self.path))?;
+            let file_format = JsonFormat::default();
+            let listing_options =
+                ListingOptions::new(Arc::new(file_format)).with_file_extension(".current");
+            let schema = SchemaRef::new(Schema::new(vec![
+                Field::new("name", datafusion::arrow::datatypes::DataType::Utf8, false),
+                Field::new("year", datafusion::arrow::datatypes::DataType::Int64, false),
+            ]));
+            let config = ListingTableConfig::new(path)
+                .with_listing_options(listing_options)
+                .with_schema(schema);
+            let provider = Arc::new(ListingTable::try_new(config)?);
+
+            self.df_ctx.register_table(TEMPLATES_TABLE, provider)?;
+        }
+
+        let mut df = self.df_ctx.table("templates").await?;
+
+        if let Some(year) = year {
+            df = df.filter(col("year").eq(lit(year)))?;
+        }
+
+        let res = df.select(vec![col("name"), col("year")])?.collect().await?;
+
+        let res: Vec<&RecordBatch> = res.iter().collect();
+
+        Ok(record_batches_to_json_rows(res.as_slice())?)
+    }
+
+    #[instrument(skip(self, data))]
+    pub async fn bytes_add(
+        &self,
+        key: String,
+        data: &[u8],
+        user: &str,
+    ) -> Result<(), anyhow::Error> {
+        let name = (&key).digest();
+        let desired_key = key;
+
+        fs::create_dir_all(self.dir(&self.bytes_shard_dir(&name))).await?;
+
+        self.write_version(
+            DataType::Bytes,
+            Action::Add,
+            &name,
+            &[
+                &(desired_key.len() as u64).to_be_bytes(),
+                desired_key.as_bytes(),
+                data,
+            ]
+            .concat(),
+            user,
+        )
+        .await?;
+
+        self.metrics.blob_bytes_written.add(data.len() as u64, &[]);
+
+        Ok(())
+    }
+
+    /// Stores a blob under the sha256 of its contents and returns that key.
+    /// Content that is already stored is not written again.
+    #[instrument(skip(self, data))]
+    pub async fn bytes_add_cas(&self, data: &[u8], user: &str) -> Result<String, anyhow::Error> {
+        let key = data.digest();
+        let _guard = self.write_lock.lock().await;
+
+        if self.bytes_size(key.clone()).await?.is_none() {
+            self.bytes_add(key.clone(), data, user).await?;
+        }
+
+        Ok(key)
+    }
+
+    fn upload_path(&self, name: &str) -> String {
+        format!("{}uploads/{name}.part", self.path)
+    }
+
+    fn upload_expiry(&self) -> i64 {
+        Utc::now().timestamp_micros() + self.uploads.ttl_secs as i64 * 1_000_000
+    }
+
+    /// Starts a resumable upload, or reports how far an existing one got.
+    /// A partial file left behind by a restart is picked up where it stopped.
+    #[instrument(skip(self))]
+    pub async fn upload_start(&self, key: String) -> Result<UploadSession, anyhow::Error> {
+        let name = key.digest();
+        let mut sessions = self.upload_sessions.lock().await;
+        let now = Utc::now().timestamp_micros();
+
+        if let Some(session) = sessions.get_mut(&name) {
+            if session.expires_at > now {
+                session.expires_at = self.upload_expiry();
+                return Ok(session.clone());
+            }
+        }
+
+        let path = self.upload_path(&name);
+        fs::create_dir_all(format!("{}uploads", self.path)).await?;
+
+        let offset = match fs::metadata(&path).await {
+            Ok(metadata) => metadata.len(),
+            Err(_) => {
+                File::create(&path).await?;
+                0
+            }
+        };
+
+        let session = UploadSession {
+            offset,
+            total: None,
+            expires_at: self.upload_expiry(),
+            finalizing: false,
+            appending: false,
+        };
+        sessions.insert(name, session.clone());
+
+        Ok(session)
+    }
+
+    /// Appends a chunk to an upload. The chunk must start exactly where the
+    /// previous one ended. Whatever a failed chunk left past that point is cut
+    /// off before the chunk is written again.
+    #[instrument(skip(self, data))]
+    pub async fn upload_append(
+        &self,
+        key: String,
+        start: u64,
+        total: Option<u64>,
+        data: &[u8],
+    ) -> Result<UploadSession, anyhow::Error> {
+        let name = key.digest();
+
+        {
+            let mut sessions = self.upload_sessions.lock().await;
+            let now = Utc::now().timestamp_micros();
+
+            let session = match sessions.get_mut(&name) {
+                Some(session) if session.expires_at > now && !session.finalizing => session,
+                _ => return Err(StorageError::UploadNotFound.into()),
+            };
+
+            if session.offset != start || session.appending {
+                return Err(StorageError::UploadOffsetMismatch(session.offset).into());
+            }
+
+            // the write runs without the lock, so other uploads carry on
+            session.appending = true;
+        }
+
+        let written = async {
+            let mut part = OpenOptions::new()
+                .append(true)
+                .open(self.upload_path(&name))
+                .await?;
+            part.set_len(start).await?;
+            part.write_all(data).await?;
+            // tokio finishes writes in the background, so without this the chunk
+            // could still be landing when the next one or a prune looks at the file
+            part.flush().await?;
+
+            Ok::<_, anyhow::Error>(())
+        }
+        .await;
+
+        let mut sessions = self.upload_sessions.lock().await;
+        let session = sessions
+            .get_mut(&name)
+            .ok_or(StorageError::UploadNotFound)?;
+        session.appending = false;
+        written?;
+
+        session.offset += data.len() as u64;
+        self.metrics.blob_bytes_written.add(data.len() as u64, &[]);
+        session.total = total.or(session.total);
+        session.expires_at = self.upload_expiry();
+
+        Ok(session.clone())
+    }
+
+    /// Drops expired upload sessions along with their temp files. Temp files
+    /// with no session, left over from a restart, expire by modification time.
+    #[instrument(skip(self))]
+    pub async fn prune_uploads(&self) -> Result<usize, anyhow::Error> {
+        let mut sessions = self.upload_sessions.lock().await;
+        let now = Utc::now().timestamp_micros();
+        let mut removed = 0;
+
+        sessions.retain(|_, session| session.expires_at > now || session.appending);
+
+        let mut entries = match fs::read_dir(format!("{}uploads", self.path)).await {
+            Ok(entries) => entries,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(0),
+            Err(e) => return Err(e.into()),
+        };
+
+        while let Some(entry) = entries.next_entry().await? {
+            let file_name = entry.file_name().to_string_lossy().to_string();
+            let name = match file_name.strip_suffix(".part") {
+                Some(name) => name,
+                None => continue,
+            };
+
+            if sessions.contains_key(name) {
+                continue;
+            }
+
+            let idle = entry
+                .metadata()
+                .await?
+                .modified()?
+                .elapsed()
+                .unwrap_or_default();
+
+            if idle.as_secs() < self.uploads.ttl_secs {
+                continue;
+            }
+
+            info!("Pruning upload {file_name}");
+            fs::remove_file(entry.path()).await?;
+            removed += 1;
+        }
+
+        Ok(removed)
+    }
+
+    /// Commits a completed upload as a bytes blob and records the transaction.
+    /// The upload must have declared its total and received all of it. Should
+    /// the commit fail, the session is left as it was to finalize again.
+    #[instrument(skip(self))]
+    pub async fn upload_finalize(&self, key: String, user: &str) -> Result<(), anyhow::Error> {
+        let name = (&key).digest();
+
+        {
+            let mut sessions = self.upload_sessions.lock().await;
+            let now = Utc::now().timestamp_micros();
+
+            let session = match sessions.get_mut(&name) {
+                Some(session) if session.expires_at > now && !session.finalizing => session,
+                _ => return Err(StorageError::UploadNotFound.into()),
+            };
+
+            if session.appending || session.total != Some(session.offset) {
+                return Err(StorageError::UploadIncomplete {
+                    received: session.offset,
+                    total: session.total,
+                }
+                .into());
+            }
+
+            // the copy runs without the lock, so other uploads carry on
+            session.finalizing = true;
+        }
+
+        let committed = self.commit_upload(&name, key, user).await;
+        let mut sessions = self.upload_sessions.lock().await;
+
+        match committed {
+            Ok(()) => {
+                sessions.remove(&name);
+            }
+            Err(_) => {
+                if let Some(session) = sessions.get_mut(&name) {
+                    session.finalizing = false;
+                }
+            }
+        }
+
+        committed
+    }
+
+    /// Copies an upload's temp file into a new version behind the key header
+    /// and makes it current, then drops the temp file and logs the add.
+    async fn commit_upload(
+        &self,
+        name: &str,
+        key: String,
+        user: &str,
+    ) -> Result<(), anyhow::Error> {
+        let part = self.upload_path(name);
+        let shard_dir = self.dir(&self.bytes_shard_dir(name));
+        let mut transaction = InternalMessage::new(DataType::Bytes, Action::Add, name.into(), user);
+        transaction.new_path = Self::version_path(&transaction);
+        let version = format!("{shard_dir}{}", transaction.new_path);
+        let current = format!("{shard_dir}{name}.current");
+
+        info!("Finalize upload at {version}");
+
+        fs::create_dir_all(&shard_dir).await?;
+
+        let copied = async {
+            let mut blob = OpenOptions::new()
+                .write(true)
+                .create_new(true)
+                .open(&version)
+                .await?;
+            blob.write_all(&(key.len() as u64).to_be_bytes()).await?;
+            blob.write_all(key.as_bytes()).await?;
+            tokio::io::copy(&mut File::open(&part).await?, &mut blob).await?;
+            blob.sync_all().await?;
+
+            // fails rather than replace a blob stored meanwhile
+            make_current(&version, &current, false).await
+        }
+        .await;
+        if let Err(e) = copied {
+            let _ = fs::remove_file(&version).await;
+            return Err(e);
+        }
+
+        fs::remove_file(&part).await?;
+
+        let checksum = file_checksum(&version).await?;
+
+        self.transaction_log
+            .log_transaction(transaction.with_checksum(checksum))
+            .await
+    }
+
+    #[instrument(skip(self, data))]
+    pub async fn bytes_edit(
+        &self,
+        key: String,
+        data: &[u8],
+        user: &str,
+    ) -> Result<(), anyhow::Error> {
+        let desired_key = key;
+
+        self.write_version(
+            DataType::Bytes,
+            Action::Edit,
+            &(&desired_key).digest(),
+            &[
+                &(desired_key.len() as u64).to_be_bytes(),
+                desired_key.as_bytes(),
+                data,
+            ]
+            .concat(),
+            user,
+        )
+        .await?;
+
+        self.metrics.blob_bytes_written.add(data.len() as u64, &[]);
+
+        Ok(())
+    }
+
+    #[instrument(skip(self))]
+    pub async fn bytes_delete(&self, key: String, user: &str) -> Result<(), anyhow::Error> {
+        let name = key.digest();
+        let shard_dir = self.bytes_shard_dir(&name);
+        let old = format!("{}.{}", &name, Uuid::new_v4());
+        let name = format!("{name}.current");
+
+        self.raw_delete(&name, &old, &shard_dir).await?;
+
+        self.transaction_log
+            .log_transaction(InternalMessage::new(
+                DataType::Bytes,
+                Action::Delete,
+                old,
+                user,
+            ))
+            .await
+    }
+
+    #[instrument(skip(self))]
+    pub async fn bytes_list(&self) -> Result<Vec<String>, anyhow::Error> {
+        let pattern = format!("{}*/*/*.current", self.dir(&self.bytes_dir()));
+        let mut keys: Vec<String> = Vec::new();
+
+        for path in glob(&pattern)?.filter_map(Result::ok) {
+            let mut f = File::open(path).await?;
+            let len = f.read_u64().await?;
+            let mut bytes = vec![0_u8; len as usize];
+
+            f.read_exact(&mut bytes).await?;
+
+            keys.push(String::from_utf8_lossy(&bytes[..]).to_string());
+        }
+
+        Ok(keys)
+    }
+
+    #[instrument(skip(self))]
+    pub async fn bytes_deleted(&self, key: &str) -> Result<bool, anyhow::Error> {
+        self.blob_deleted(DataType::Bytes, &key.digest()).await
+    }
+
+    /// The size of a blob's data, without the key header, if it is stored.
+    /// Only the header is read.
+    #[instrument(skip(self))]
+    pub async fn bytes_size(&self, key: String) -> Result<Option<u64>, anyhow::Error> {
+        let name = key.digest();
+        let path = format!("{}{name}.current", self.dir(&self.bytes_shard_dir(&name)));
+
+        let mut f = match File::open(path).await {
+            Ok(f) => f,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(None),
+            Err(e) => return Err(e.into()),
+        };
+        let truncated = || StorageError::Truncated(name.clone());
+        let len = match f.read_u64().await {
+            Ok(len) => len,
+            Err(e) if e.kind() == std::io::ErrorKind::UnexpectedEof => {
+                return Err(truncated().into())
+            }
+            Err(e) => return Err(e.into()),
+        };
+
+        let size = f
+            .metadata()
+            .await?
+            .len()
+            .checked_sub(8)
+            .and_then(|size| size.checked_sub(len))
+            .ok_or_else(truncated)?;
+
+        Ok(Some(size))
+    }
+
+    /// Reads `start..=end` of a blob's data, seeking past the rest. The range
+    /// must lie within [`Self::bytes_size`].
+    #[instrument(skip(self))]
+    pub async fn bytes_get_range(
+        &self,
+        key: String,
+        start: u64,
+        end: u64,
+    ) -> Result<Vec<u8>, anyhow::Error> {
+        let name = key.digest();
+        let path = format!("{}{name}.current", self.dir(&self.bytes_shard_dir(&name)));
+
+        let mut f = File::open(path).await?;
+        let len = f.read_u64().await?;
+        f.seek(SeekFrom::Current(len as i64 + start as i64)).await?;
+
+        let mut data = vec![0_u8; (end - start + 1) as usize];
+        f.read_exact(&mut data).await?;
+
+        Ok(data)
+    }
+
+    /// Reads a blob's data. A missing blob is reported as `StorageError::Deleted`
+    /// or `StorageError::NotFound` depending on whether it was ever stored.
+    /// With `verify` the file is checked against the checksum recorded when it
+    /// was written, failing with `StorageError::ChecksumMismatch`. A file shorter
+    /// than its header fails with `StorageError::Truncated`.
+    #[instrument(skip(self))]
+    pub async fn bytes_get(&self, key: String, verify: bool) -> Result<Vec<u8>, anyhow::Error> {
+        let name = key.digest();
+        let shard_dir = self.bytes_shard_dir(&name);
+        let file_name = format!("{name}.current");
+
+        let bytes = match self.raw_get(&file_name, &shard_dir).await {
+            Ok(bytes) => bytes,
+            Err(e) => {
+                return Err(match e.downcast_ref::<std::io::Error>() {
+                    Some(io) if io.kind() == std::io::ErrorKind::NotFound => {
+                        match self.blob_deleted(DataType::Bytes, &name).await? {
+                            true => StorageError::Deleted.into(),
+                            false => StorageError::NotFound.into(),
+                        }
+                    }
+                    _ => e,
+                })
+            }
+        };
+
+        if verify {
+            self.verify_checksum(&DataType::Bytes, &name, bytes.as_slice().digest())
+                .await?;
+        }
+
+        let data = bytes
+            .get(..8)
+            .map(|header| u64::from_be_bytes(header.try_into().unwrap()))
+            .and_then(|len| usize::try_from(len).ok()?.checked_add(8))
+            .and_then(|start| bytes.get(start..))
+            .ok_or_else(|| StorageError::Truncated(name.clone()))?;
+
+        Ok(Vec::from(data))
+    }
+
+    /// Compares an item's current file checksum with the one its latest
+    /// transaction recorded. Items written before checksums were kept pass.
+    async fn verify_checksum(
+        &self,
+        data_type: &DataType,
+        alt_key: &str,
+        checksum: String,
+    ) -> Result<(), anyhow::Error> {
+        let recorded = self
+            .transaction_log
+            .latest_for(data_type, alt_key)
+            .await?
+            .and_then(|t| t.checksum);
+
+        match recorded {
+            Some(recorded) if recorded != checksum => {
+                Err(StorageError::ChecksumMismatch(alt_key.to_string()).into())
+            }
+            _ => Ok(()),
+        }
+    }
+
+    /// Checks every live item's current file against its recorded checksum and
+    /// returns the storage-relative paths that are missing or do not match.
+    #[instrument(skip(self))]
+    pub async fn verify_blobs(&self) -> Result<Vec<String>, anyhow::Error> {
+        let mut bad = vec![];
+
+        for transaction in self.transaction_log.latest_live().await? {
+            if transaction.checksum.is_none() {
+                continue;
+            }
+
+            let alt_key = transaction.alt_key();
+            let path = format!(
+                "{}{alt_key}.current",
+                self.sub_path(&transaction.data_type, alt_key)
+            );
+
+            let verified = match file_checksum(self.dir(&path)).await {
+                Ok(checksum) => {
+                    self.verify_checksum(&transaction.data_type, alt_key, checksum)
+                        .await
+                }
+                Err(e) => Err(e),
+            };
+
+            if let Err(e) = verified {
+                warn!("{path} failed verification: {e}");
+                bad.push(path);
+            }
+        }
+
+        Ok(bad)
+    }
+
+    pub async fn get_first(&self) -> Result<InternalMessage, anyhow::Error> {
+        self.transaction_log.get_first().await
+    }
+
+    /// Appends a transaction that originated on another instance, keeping its
+    /// id but stamping it with the time it was applied here. The time and seq
+    /// it was first written with are kept as its origin. Transactions that
+    /// carry no user are attributed to [`SYSTEM_USER`].
+    #[instrument(skip(self))]
+    pub async fn write_foreign_transaction(
+        &self,
+        transaction: InternalMessage,
+    ) -> Result<(), anyhow::Error> {
+        let mut transaction = transaction;
+        transaction
+            .original_timestamp
+            .get_or_insert(transaction.timestamp);
+        transaction.original_seq.get_or_insert(transaction.seq);
+        transaction.timestamp = Utc::now().timestamp_micros();
+        transaction
+            .user
+            .get_or_insert_with(|| SYSTEM_USER.to_string());
+
+        self.transaction_log.log_transaction(transaction).await
+    }
+
+    /// Replays newline-delimited transactions, skipping any whose id is already
+    /// in the log, and reports the referenced files that still need copying over.
+    ///
+    /// The last already-known transaction in the input is taken as the common
+    /// ancestor. An imported transaction for an item that was also changed
+    /// locally after that point is resolved by the merge policy, and the losing
+    /// version is logged as [`Action::Superseded`] under its own path.
+    #[instrument(skip(self, ndjson))]
+    pub async fn import_transactions(&self, ndjson: &str) -> Result<ImportSummary, anyhow::Error> {
+        // counted before any line is parsed
+        self.check_batch_items(ndjson.lines().filter(|l| !l.trim().is_empty()).count())?;
+
+        let local = self.transaction_log.read_all().await?;
+        let seqs: HashMap<Uuid, u64> = local.iter().map(|t| (t.id, t.seq)).collect();
+        let mut known: HashSet<Uuid> = seqs.keys().copied().collect();
+        let mut local_latest: HashMap<(DataType, String), InternalMessage> = HashMap::new();
+
+        for transaction in local {
+            if transaction.action != Action::Superseded {
+                local_latest.insert(
+                    (
+                        transaction.data_type.clone(),
+                        transaction.alt_key().to_string(),
+                    ),
+                    transaction,
+                );
+            }
+        }
+
+        let mut ancestor_seq = 0;
+        let mut summary = ImportSummary::default();
+
+        for line in ndjson.lines().filter(|l| !l.trim().is_empty()) {
+            let transaction: InternalMessage = serde_json::from_str(line)?;
+
+            if !known.insert(transaction.id) {
+                if let Some(seq) = seqs.get(&transaction.id) {
+                    ancestor_seq = ancestor_seq.max(*seq);
+                }
+                summary.skipped += 1;
+                continue;
+            }
+
+            let key = (
+                transaction.data_type.clone(),
+                transaction.alt_key().to_string(),
+            );
+
+            if let Some(local) = local_latest
+                .remove(&key)
+                .filter(|local| local.seq > ancestor_seq)
+            {
+                summary.superseded += 1;
+
+                if self.merge_policy.resolve(&local, &transaction) == Resolution::KeepLocal {
+                    info!("kept local {} over imported {}", local.id, transaction.id);
+                    local_latest.insert(key, local);
+                    self.write_superseded(transaction).await?;
+                    continue;
+                }
+
+                info!("took imported {} over local {}", transaction.id, local.id);
+                self.set_aside(&local).await?;
+            }
+
+            let path = format!(
+                "{}{}",
+                self.sub_path(&transaction.data_type, &transaction.new_path),
+                transaction.new_path
+            );
+
+            if fs::metadata(format!("{}{}", self.dir(""), path))
+                .await
+                .is_err()
+            {
+                summary.missing.push(path);
+            }
+
+            self.write_foreign_transaction(transaction).await?;
+            summary.applied += 1;
+        }
+
+        Ok(summary)
+    }
+
+    fn check_batch_items(&self, count: usize) -> Result<(), StorageError> {
+        let max = self.batch_limits.max_items;
+
+        if count > max {
+            return Err(StorageError::BatchTooLarge(format!(
+                "{count} items, at most {max} allowed"
+            )));
+        }
+
+        Ok(())
+    }
+
+    /// Items with divergent local and imported changes resolved after `micros`,
+    /// oldest first, pairing the version kept with the one set aside.
+    #[instrument(skip(self))]
+    pub async fn detect_sync_conflicts(
+        &self,
+        micros: i64,
+    ) -> Result<Vec<SyncConflict>, anyhow::Error> {
+        let mut conflicts = vec![];
+
+        for transaction in self.transactions_since(micros).await? {
+            if transaction.action != Action::Superseded {
+                continue;
+            }
+
+            let Some(superseded) = transaction
+                .new_path
+                .split_once('.')
+                .and_then(|(_, id)| Uuid::parse_str(id).ok())
+            else {
+                continue;
+            };
+
+            let current = self
+                .transaction_log
+                .latest_for(&transaction.data_type, transaction.alt_key())
+                .await?
+                .map(|t| t.id);
+
+            conflicts.push(SyncConflict {
+                alt_key: transaction.alt_key().to_string(),
+                data_type: transaction.data_type,
+                current,
+                superseded,
+                superseded_path: transaction.new_path,
+                timestamp: transaction.timestamp,
+            });
+        }
+
+        Ok(conflicts)
+    }
+
+    /// The file a transaction's version of an item is kept under. It is never
+    /// rewritten, so it always matches the checksum the transaction logged.
+    fn version_path(transaction: &InternalMessage) -> String {
+        format!("{}.{}", transaction.alt_key(), transaction.id)
+    }
+
+    /// Logs an imported transaction that lost to a local change, keeping its id
+    /// so it is not imported again. Its file, if copied over, lands aside.
+    async fn write_superseded(&self, transaction: InternalMessage) -> Result<(), anyhow::Error> {
+        let mut transaction = transaction;
+        transaction.new_path = Self::version_path(&transaction);
+        transaction.action = Action::Superseded;
+
+        self.write_foreign_transaction(transaction).await
+    }
+
+    /// Moves the local current version of an item that lost to an imported
+    /// change out of the way, so the imported file can take its place.
+    async fn set_aside(&self, local: &InternalMessage) -> Result<(), anyhow::Error> {
+        let dir = self.dir(&self.sub_path(&local.data_type, local.alt_key()));
+        let aside = Self::version_path(local);
+        let current = format!("{dir}{}.current", local.alt_key());
+
+        // a version written here already keeps its own file, which the current
+        // one is a link to
+        let moved = if fs::metadata(format!("{dir}{aside}")).await.is_ok() {
+            fs::remove_file(&current).await
+        } else {
+            fs::rename(&current, format!("{dir}{aside}")).await
+        };
+
+        match moved {
+            Ok(()) => {}
+            // a local delete has no current file
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => {}
+            Err(e) => return Err(e.into()),
+        }
+
+        self.transaction_log
+            .log_transaction(InternalMessage::new(
+                local.data_type.clone(),
+                Action::Superseded,
+                aside,
+                SYSTEM_USER,
+            ))
+            .await
+    }
+
+    pub async fn get_after(&self, id: Uuid) -> Result<InternalMessage, anyhow::Error> {
+        self.transaction_log.get_after(id).await
+    }
+
+    /// Checks that the transaction log can be opened and the bytes directory
+    /// written to, for readiness probes. The log is not read, so probes stay
+    /// cheap however long it grows.
+    #[instrument(skip(self))]
+    pub async fn health_check(&self) -> Result<(), anyhow::Error> {
+        self.transaction_log
+            .check_readable()
+            .await
+            .map_err(|e| anyhow!("transaction log unreadable: {e}"))?;
+
+        let probe = format!("{}.health-{}", self.dir(&self.bytes_dir()), Uuid::new_v4());
+
+        fs::write(&probe, b"ok")
+            .await
+            .map_err(|e| anyhow!("bytes directory not writable: {e}"))?;
+        fs::remove_file(&probe)
+            .await
+            .map_err(|e| anyhow!("bytes directory not writable: {e}"))
+    }
+
+    /// Live and deleted item counts, the transaction total and the size of the
+    /// bytes directory, which is remeasured at most every [`BLOB_BYTES_TTL`].
+    #[instrument(skip(self))]
+    pub async fn overview(&self) -> Result<StorageOverview, anyhow::Error> {
+        let mut overview = self.transaction_log.overview().await?;
+
+        let mut cached = self.blob_bytes.lock().await;
+
+        overview.blob_bytes = match *cached {
+            Some((size, measured)) if measured.elapsed() < BLOB_BYTES_TTL => size,
+            _ => {
+                let size = dir_size(self.dir(&self.bytes_dir())).await?;
+                *cached = Some((size, Instant::now()));
+                size
+            }
+        };
+
+        Ok(overview)
+    }
+
+    /// Every transaction in the log, in log order.
+    #[instrument(skip(self))]
+    pub async fn transactions(&self) -> Result<Vec<InternalMessage>, anyhow::Error> {
+        self.transaction_log.read_all().await
+    }
+
+    /// A feed of every transaction written from now on.
+    pub fn subscribe(&self) -> broadcast::Receiver<InternalMessage> {
+        self.transaction_log.events.0.subscribe()
+    }
+
+    pub fn sync_child_allowed(&self, email: &str) -> bool {
+        self.sync_children
+            .iter()
+            .any(|child| child.eq_ignore_ascii_case(email))
+    }
+
+    /// Whether a transaction wrote a version that a child could push here:
+    /// one with a recorded checksum and a file of its own.
+    fn pushable(transaction: &InternalMessage) -> bool {
+        transaction.checksum.is_some() && !transaction.new_path.ends_with(".current")
+    }
+
+    /// Ids of logged transactions whose files are missing here and can be
+    /// pushed, sorted. Versions of deleted items are left out.
+    #[instrument(skip(self))]
+    pub async fn needed_blobs(&self) -> Result<Vec<Uuid>, anyhow::Error> {
+        let mut needed = vec![];
+
+        for transaction in self.transaction_log.read_all().await? {
+            if !Self::pushable(&transaction) || self.transaction_file_exists(&transaction).await {
+                continue;
+            }
+
+            let deleted = self
+                .transaction_log
+                .latest_for(&transaction.data_type, transaction.alt_key())
+                .await?
+                .is_some_and(|t| t.action == Action::Delete);
+
+            if !deleted {
+                needed.push(transaction.id);
+            }
+        }
+
+        needed.sort();
+
+        Ok(needed)
+    }
+
+    async fn transaction_file_exists(&self, transaction: &InternalMessage) -> bool {
+        let path = format!(
+            "{}{}",
+            self.dir(&self.sub_path(&transaction.data_type, &transaction.new_path)),
+            transaction.new_path
+        );
+
+        fs::metadata(path).await.is_ok()
+    }
+
+    /// A logged transaction and where the file it wrote lives on disk.
+    async fn transaction_file(&self, id: Uuid) -> Result<(InternalMessage, String), anyhow::Error> {
+        let transaction = self
+            .transaction_log
+            .read_all()
+            .await?
+            .into_iter()
+            .find(|t| t.id == id)
+            .ok_or(StorageError::NotFound)?;
+
+        let path = format!(
+            "{}{}",
+            self.dir(&self.sub_path(&transaction.data_type, &transaction.new_path)),
+            transaction.new_path
+        );
+
+        Ok((transaction, path))
+    }
+
+    /// The raw file written by a transaction, for a child to copy.
+    #[instrument(skip(self))]
+    pub async fn sync_blob_read(&self, id: Uuid) -> Result<Vec<u8>, anyhow::Error> {
+        let (transaction, path) = self.transaction_file(id).await?;
+
+        match fs::read(&path).await {
+            // older logs name the current file, which may hold a later version
+            Ok(data)
+                if transaction
+                    .checksum
+                    .as_ref()
+                    .is_some_and(|c| *c != data.as_slice().digest()) =>
+            {
+                Err(StorageError::NotFound.into())
+            }
+            Ok(data) => Ok(data),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => {
+                Err(StorageError::NotFound.into())
+            }
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    /// Stores the raw file for a transaction already in the log, once its
+    /// sha256 matches the checksum the transaction recorded. Only files that
+    /// are missing here are written, and never an item's current version.
+    #[instrument(skip(self, data))]
+    pub async fn sync_blob_write(&self, id: Uuid, data: &[u8]) -> Result<(), anyhow::Error> {
+        let (transaction, path) = self.transaction_file(id).await?;
+
+        if transaction.checksum.as_deref() != Some(data.digest().as_str()) {
+            return Err(StorageError::DigestMismatch.into());
+        }
+
+        if transaction.new_path.ends_with(".current") || fs::metadata(&path).await.is_ok() {
+            return Err(StorageError::AlreadyExists {
+                data_type: transaction.data_type,
+                key: transaction.new_path,
+            }
+            .into());
+        }
+
+        if let Some(parent) = Path::new(&path).parent() {
+            fs::create_dir_all(parent).await?;
+        }
+
+        // linking fails if the file appeared since the check above
+        let tmp = format!("{path}.syncing-{}", Uuid::new_v4());
+        fs::write(&tmp, data).await?;
+        let linked = fs::hard_link(&tmp, &path).await;
+        fs::remove_file(&tmp).await?;
+        linked?;
+
+        // the latest version of an item is also its current file
+        let latest = self
+            .transaction_log
+            .latest_for(&transaction.data_type, transaction.alt_key())
+            .await?;
+        if latest.is_some_and(|t| t.id == id && matches!(t.action, Action::Add | Action::Edit)) {
+            let current = format!(
+                "{}{}.current",
+                self.dir(&self.sub_path(&transaction.data_type, transaction.alt_key())),
+                transaction.alt_key()
+            );
+            make_current(&path, &current, true).await?;
+        }
+
+        self.metrics.blob_bytes_written.add(data.len() as u64, &[]);
+
+        Ok(())
+    }
+
+    /// Transactions written after `micros`, oldest first. Ties on timestamp keep
+    /// log order.
+    #[instrument(skip(self))]
+    pub async fn transactions_since(
+        &self,
+        micros: i64,
+    ) -> Result<Vec<InternalMessage>, anyhow::Error> {
+        let mut transactions: Vec<InternalMessage> = self
+            .transaction_log
+            .read_all()
+            .await?
+            .into_iter()
+            .filter(|t| t.timestamp > micros)
+            .collect();
+
+        transactions.sort_by_key(|t| (t.timestamp, t.seq));
+
+        Ok(transactions)
+    }
+
+    /// Every transaction that touched an item, in the order they were written.
+    #[instrument(skip(self))]
+    pub async fn transactions_for_alt_key(
+        &self,
+        alt_key: &str,
+    ) -> Result<Vec<InternalMessage>, anyhow::Error> {
+        let mut transactions: Vec<InternalMessage> = self
+            .transaction_log
+            .read_all()
+            .await?
+            .into_iter()
+            .filter(|t| t.alt_key() == alt_key)
+            .collect();
+
+        transactions.sort_by_key(|t| t.seq);
+
+        Ok(transactions)
+    }
+
+    /// Compares a parent's transaction log against this one without applying
+    /// anything.
+    #[instrument(skip(self, parent))]
+    pub async fn sync_diff(&self, parent: &[InternalMessage]) -> Result<SyncDiff, anyhow::Error> {
+        let known: HashSet<Uuid> = self
+            .transaction_log
+            .read_all()
+            .await?
+            .into_iter()
+            .map(|t| t.id)
+            .collect();
+        let mut diff = SyncDiff {
+            parent_head: parent.last().map(|t| t.id),
+            ..Default::default()
+        };
+
+        for transaction in parent.iter().filter(|t| !known.contains(&t.id)) {
+            diff.missing_transactions += 1;
+
+            if Self::pushable(transaction) && !self.transaction_file_exists(transaction).await {
+                diff.missing_blobs += 1;
+            }
+        }
+
+        Ok(diff)
+    }
+
+    /// Moves blobs stored flat in the bytes directory, as they were before
+    /// sharding, into their shard directories. Files not named after a digest,
+    /// such as a health probe left by a crash, stay where they are.
+    #[instrument(skip(self))]
+    async fn shard_bytes(&self) -> Result<usize, anyhow::Error> {
+        let bytes_dir = self.dir(&self.bytes_dir());
+        let mut entries = fs::read_dir(&bytes_dir).await?;
+        let mut moved = 0;
+
+        while let Some(entry) = entries.next_entry().await? {
+            if !entry.file_type().await?.is_file() {
+                continue;
+            }
+
+            let name = entry.file_name().to_string_lossy().to_string();
+            let digest = name.split('.').next().unwrap_or_default();
+
+            if digest.len() != 64
+                || !digest
+                    .bytes()
+                    .all(|b| b.is_ascii_digit() || (b'a'..=b'f').contains(&b))
+            {
+                continue;
+            }
+
+            let shard_dir = self.dir(&self.bytes_shard_dir(&name));
+
+            fs::create_dir_all(&shard_dir).await?;
+            fs::rename(entry.path(), format!("{shard_dir}{name}")).await?;
+            moved += 1;
+        }
+
+        Ok(moved)
+    }
+
+    /// Lays out a fresh storage directory and brings an older transaction log
+    /// up to date. Safe to run on every start.
+    #[instrument(skip(self))]
+    pub async fn migrate(&self) -> Result<(), anyhow::Error> {
+        for dir in ["forms", TEMPLATES_TABLE, SCHEDULES_TABLE, "uploads"] {
+            fs::create_dir_all(format!("{}{dir}", self.path)).await?;
+        }
+
+        fs::create_dir_all(self.dir(&self.bytes_dir())).await?;
+
+        let sharded = self.shard_bytes().await?;
+
+        if sharded > 0 {
+            info!("Moved {sharded} blobs into sharded directories");
+        }
+
+        if let Some(parent) = Path::new(&self.transaction_log.path).parent() {
+            fs::create_dir_all(parent).await?;
+        }
+
+        let backfilled = self.transaction_log.backfill_seq().await?;
+
+        if backfilled > 0 {
+            info!("Backfilled seq on {backfilled} transactions");
+        }
+
+        Ok(())
+    }
+
+    /// Adds every template in the template directory whose name is not taken
+    /// yet and returns how many were added. Files that fail to load are logged
+    /// and skipped.
+    #[instrument(skip(self))]
+    pub async fn import_templates(&self) -> Result<usize, anyhow::Error> {
+        let Some(dir) = &self.template_directory else {
+            return Ok(0);
+        };
+
+        let mut added = 0;
+
+        for path in glob(&format!("{}/*.json", dir.trim_end_matches('/')))?.filter_map(Result::ok) {
+            let template: FormTemplate = match fs::read(&path)
+                .await
+                .map_err(anyhow::Error::from)
+                .and_then(|bytes| serde_json::from_slice(&bytes).map_err(Into::into))
+            {
+                Ok(template) => template,
+                Err(e) => {
+                    warn!("skipping template {}: {e}", path.display());
+                    continue;
+                }
+            };
+
+            if self.templates_get(template.name.clone()).await.is_ok() {
+                continue;
+            }
+
+            let name = template.name.clone();
+
+            match self.templates_add(template, SYSTEM_USER).await {
+                Ok(()) => {
+                    info!("Added template {name} from {}", path.display());
+                    added += 1;
+                }
+                Err(e) => warn!("could not add template {name}: {e}"),
+            }
+        }
+
+        Ok(added)
+    }
+
+    #[allow(dead_code)]
+    pub async fn list_files(&self) -> Result<Vec<String>, anyhow::Error> {
+        self.transaction_log.list_files().await
+    }
+
+    #[allow(dead_code)]
+    pub async fn get_file(&self, path: String) -> Result<Vec<u8>, anyhow::Error> {
+        self.transaction_log.get_file(path).await
+    }
+}
+
+/// What the log needs to answer writes and latest-version lookups without
+/// rescanning the file.
+#[derive(Debug, Default)]
+struct LogIndex {
+    last_seq: u64,
+    /// Transactions inserted, i.e. the length of the log.
+    count: usize,
+    latest: HashMap<(DataType, String), InternalMessage>,
+}
+
+impl LogIndex {
+    fn from_transactions(transactions: Vec<InternalMessage>) -> Self {
+        let mut index = Self::default();
+
+        for transaction in transactions {
+            index.insert(transaction);
+        }
+
+        index
+    }
+
+    /// Records a transaction, keeping the highest `seq` per item. Equal `seq`
+    /// values, only possible before a backfill, resolve to the later one.
+    fn insert(&mut self, transaction: InternalMessage) {
+        self.last_seq = self.last_seq.max(transaction.seq);
+        self.count += 1;
+
+        if transaction.action == Action::Superseded {
+            return;
+        }
+
+        let key = (
+            transaction.data_type.clone(),
+            transaction.alt_key().to_string(),
+        );
+
+        match self.latest.get(&key) {
+            Some(existing) if existing.seq > transaction.seq => {}
+            _ => {
+                self.latest.insert(key, transaction);
+            }
+        }
+    }
+}
+
+#[derive(Debug, Default, Deserialize)]
+struct TransactionLog {
+    path: String,
+    /// Loaded from the log on first use and kept current on every write.
+    #[serde(skip)]
+    index: Mutex<Option<LogIndex>>,
+    #[serde(skip)]
+    events: TransactionEvents,
+}
+
+/// Publishes every transaction written to the log to live subscribers.
+#[derive(Debug)]
+struct TransactionEvents(broadcast::Sender<InternalMessage>);
+
+impl Default for TransactionEvents {
+    fn default() -> Self {
+        Self(broadcast::channel(EVENT_CAPACITY).0)
+    }
+}
+
+impl TransactionLog {
+    async fn load_index(&self) -> Result<MutexGuard<'_, Option<LogIndex>>, anyhow::Error> {
+        let mut index = self.index.lock().await;
+
+        if index.is_none() {
+            *index = Some(LogIndex::from_transactions(self.read_all().await?));
+        }
+
+        Ok(index)
+    }
+
+    #[instrument]
+    async fn log_transaction(&self, transaction: InternalMessage) -> Result<(), anyhow::Error> {
+        let mut index = self.load_index().await?;
+        let index = index.get_or_insert_with(LogIndex::default);
+
+        let mut transaction = transaction;
+        transaction.seq = index.last_seq + 1;
+
+        let mut file = OpenOptions::new()
+            .write(true)
+            .append(true)
+            .create(true)
+            .open(&self.path)
+            .await?;
+
+        file.write_all(format!("{}\n", serde_json::to_string(&transaction)?).as_bytes())
+            .await?;
+
+        // the write has landed, so nobody listening must not fail it
+        if self.events.0.send(transaction.clone()).is_err() {
+            debug!("no live subscribers for {}", transaction.id);
+        }
+        index.insert(transaction);
+
+        Ok(())
+    }
+
+    /// Numbers transactions written before `seq` existed, in timestamp order
+    /// with ties kept in log order, and rewrites the log in place.
+    #[instrument]
+    async fn backfill_seq(&self) -> Result<usize, anyhow::Error> {
+        let mut index = self.index.lock().await;
+        let mut transactions = self.read_all().await?;
+
+        if transactions.iter().all(|t| t.seq != 0) {
+            return Ok(0);
+        }
+
+        let mut order: Vec<usize> = (0..transactions.len()).collect();
+        order.sort_by_key(|&i| transactions[i].timestamp);
+
+        for (seq, i) in order.into_iter().enumerate() {
+            transactions[i].seq = seq as u64 + 1;
+        }
+
+        let mut contents = String::new();
+        for transaction in &transactions {
+            contents.push_str(&serde_json::to_string(transaction)?);
+            contents.push('\n');
+        }
+
+        let tmp = format!("{}.migrating", self.path);
+        fs::write(&tmp, contents).await?;
+        fs::rename(&tmp, &self.path).await?;
+
+        let backfilled = transactions.len();
+        *index = Some(LogIndex::from_transactions(transactions));
+
+        Ok(backfilled)
+    }
+
+    /// Opens the log without reading it. A log that was never written counts
+    /// as readable, as it does for `read_all`.
+    #[instrument]
+    async fn check_readable(&self) -> Result<(), anyhow::Error> {
+        match File::open(&self.path).await {
+            Ok(file) if file.metadata().await?.is_file() => Ok(()),
+            Ok(_) => Err(anyhow!("{} is not a file", self.path)),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(()),
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    async fn read_all(&self) -> Result<Vec<InternalMessage>, anyhow::Error> {
+        let file = match File::open(&self.path).await {
+            Ok(file) => file,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(vec![]),
+            Err(e) => return Err(e.into()),
+        };
+        let mut lines = BufReader::new(file).lines();
+        let mut transactions = vec![];
+
+        while let Some(line) = lines.next_line().await? {
+            transactions.push(serde_json::from_str::<InternalMessage>(&line)?);
+        }
+
+        Ok(transactions)
+    }
+
+    /// The last logged transaction for an item, if it was ever written.
+    /// Timestamps are not unique, so ordering goes by `seq`.
+    #[instrument]
+    async fn latest_for(
+        &self,
+        data_type: &DataType,
+        alt_key: &str,
+    ) -> Result<Option<InternalMessage>, anyhow::Error> {
+        Ok(self.load_index().await?.as_ref().and_then(|index| {
+            index
+                .latest
+                .get(&(data_type.clone(), alt_key.to_string()))
+                .cloned()
+        }))
+    }
+
+    /// The latest transaction of every item that is not deleted.
+    #[instrument]
+    async fn latest_live(&self) -> Result<Vec<InternalMessage>, anyhow::Error> {
+        Ok(self
+            .load_index()
+            .await?
+            .as_ref()
+            .map(|index| {
+                index
+                    .latest
+                    .values()
+                    .filter(|t| t.action != Action::Delete)
+                    .cloned()
+                    .collect()
+            })
+            .unwrap_or_default())
+    }
+
+    /// Item counts by data type and the number of transactions, from the index.
+    #[instrument]
+    async fn overview(&self) -> Result<StorageOverview, anyhow::Error> {
+        let mut overview = StorageOverview::default();
+        let index = self.load_index().await?;
+
+        if let Some(index) = index.as_ref() {
+            overview.transactions = index.count;
+
+            for transaction in index.latest.values() {
+                let counts = match transaction.data_type {
+                    DataType::Form(_) => &mut overview.forms,
+                    DataType::Template => &mut overview.templates,
+                    DataType::Schedule => &mut overview.schedules,
+                    DataType::Bytes => &mut overview.blobs,
+                };
+
+                counts.count(&transaction.action);
+            }
+        }
+
+        Ok(overview)
+    }
+
+    /// The number of live forms under each template that has any.
+    #[instrument]
+    async fn live_forms_by_template(&self) -> Result<HashMap<String, usize>, anyhow::Error> {
+        let mut counts = HashMap::new();
+
+        if let Some(index) = self.load_index().await?.as_ref() {
+            for transaction in index.latest.values() {
+                match &transaction.data_type {
+                    DataType::Form(template) if transaction.action != Action::Delete => {
+                        *counts.entry(template.clone()).or_insert(0) += 1;
+                    }
+                    _ => {}
+                }
+            }
+        }
+
+        Ok(counts)
+    }
+
+    /// The latest transaction of every item of a data type, deletes included.
+    #[instrument]
+    async fn latest_of(&self, data_type: &DataType) -> Result<Vec<InternalMessage>, anyhow::Error> {
+        Ok(self
+            .load_index()
+            .await?
+            .as_ref()
+            .map(|index| {
+                index
+                    .latest
+                    .values()
+                    .filter(|t| &t.data_type == data_type)
+                    .cloned()
+                    .collect()
+            })
+            .unwrap_or_default())
+    }
+
+    /// Items of a data type whose last transaction is a delete, as those deletes.
+    #[instrument]
+    async fn latest_deletes(
+        &self,
+        data_type: &DataType,
+    ) -> Result<Vec<InternalMessage>, anyhow::Error> {
+        Ok(self
+            .load_index()
+            .await?
+            .as_ref()
+            .map(|index| {
+                index
+                    .latest
+                    .values()
+                    .filter(|t| &t.data_type == data_type && t.action == Action::Delete)
+                    .cloned()
+                    .collect()
+            })
+            .unwrap_or_default())
+    }
+
+    /// The most recent transactions of a data type, newest first.
+    #[instrument]
+    async fn latest(
+        &self,
+        data_type: &DataType,
+        count: usize,
+    ) -> Result<Vec<InternalMessage>, anyhow::Error> {
+        let mut transactions = self.read_all().await?;
+        transactions.retain(|t| &t.data_type == data_type);
+        transactions.sort_by_key(|t| std::cmp::Reverse(t.seq));
+        transactions.truncate(count);
+
+        Ok(transactions)
+    }
+
+    #[instrument]
+    pub async fn get_first(&self) -> Result<InternalMessage, anyhow::Error> {
+        let file = File::open(&self.path).await?;
+        let mut line: String = String::new();
+
+        BufReader::new(file).read_line(&mut line).await?;
+
+        Ok(serde_json::from_str(&line)?)
+    }
+
+    #[instrument]
+    pub async fn get_after(&self, id: Uuid) -> Result<InternalMessage, anyhow::Error> {
+        let file = File::open(&self.path).await?;
+        let mut lines = BufReader::new(file).lines();
+
+        while let Some(line) = lines.next_line().await? {
+            let de = serde_json::from_str::<InternalMessage>(&line)?;
+
+            if de.id == id {
+                let line = lines.next_line().await?;
+
+                return match line {
+                    None => Err(anyhow!("explode")),
+                    Some(line) => Ok(serde_json::from_str::<InternalMessage>(&line)?),
+                };
+            }
+        }
+
+        Err(anyhow!("dfasdfjkh"))
+    }
+
+    #[allow(dead_code)]
+    #[instrument]
+    pub async fn get_file(&self, path: String) -> Result<Vec<u8>, anyhow::Error> {
+        let mut buf = vec![];
+
+        File::open(path).await?.read_to_end(&mut buf).await?;
+        Ok(buf)
+    }
+
+    #[allow(dead_code)]
+    #[instrument]
+    pub async fn list_files(&self) -> Result<Vec<String>, anyhow::Error> {
+        let glob = glob("data/*")
+            .unwrap()
+            .filter_map(|p| p.ok())
+            .filter(|p| p.is_file())
+            .map(|p| p.as_path().to_string_lossy().to_string())
+            .collect();
+
+        Ok(glob)
+    }
+}
+
+/// Two directory levels taken from the first four characters of a digest,
+/// e.g. `ab/cd/`, so no single directory collects every blob.
+pub fn shard(digest: &str) -> String {
+    match (digest.get(0..2), digest.get(2..4)) {
+        (Some(a), Some(b)) => format!("{a}/{b}/"),
+        _ => "00/00/".into(),
+    }
+}
+
+fn default_bytes_dir() -> String {
+    "bytes/".into()
+}
+
+fn default_idempotency_ttl() -> u64 {
+    24 * 60 * 60
+}
+
+/// Turns the error from writing over an existing file into a typed
+/// `StorageError::AlreadyExists` for the item.
+fn already_exists(error: anyhow::Error, data_type: DataType, key: &str) -> anyhow::Error {
+    match error.downcast_ref::<std::io::Error>() {
+        Some(e) if e.kind() == std::io::ErrorKind::AlreadyExists => StorageError::AlreadyExists {
+            data_type,
+            key: key.to_string(),
+        }
+        .into(),
+        _ => error,
+    }
+}
+
+/// Total size of the files under a directory. A missing directory is empty.
+async fn dir_size(path: impl AsRef<Path>) -> Result<u64, anyhow::Error> {
+    let mut size = 0;
+    let mut dirs = vec![path.as_ref().to_path_buf()];
+
+    while let Some(dir) = dirs.pop() {
+        let mut entries = match fs::read_dir(&dir).await {
+            Ok(entries) => entries,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => continue,
+            Err(e) => return Err(e.into()),
+        };
+
+        while let Some(entry) = entries.next_entry().await? {
+            let metadata = entry.metadata().await?;
+
+            if metadata.is_dir() {
+                dirs.push(entry.path());
+            } else {
+                size += metadata.len();
+            }
+        }
+    }
+
+    Ok(size)
+}
+
+/// sha256 of a file, read in chunks so large blobs are never held in memory.
+async fn file_checksum(path: impl AsRef<Path>) -> Result<String, anyhow::Error> {
+    let mut f = File::open(path).await?;
+    let mut hasher = Sha256::new();
+    let mut buf = vec![0_u8; 64 * 1024];
+
+    loop {
+        let read = f.read(&mut buf).await?;
+
+        if read == 0 {
+            break;
+        }
+
+        hasher.update(&buf[..read]);
+    }
+
+    Ok(format!("{:x}", hasher.finalize()))
+}
+
+/// Links `version` in as an item's current file. Without `replace` this fails
+/// if the item already has one.
+async fn make_current(version: &str, current: &str, replace: bool) -> Result<(), anyhow::Error> {
+    if !replace {
+        return fs::hard_link(version, current).await.map_err(Into::into);
+    }
+
+    let tmp = format!("{current}.{}.tmp", Uuid::new_v4());
+    fs::hard_link(version, &tmp).await?;
+    if let Err(e) = fs::rename(&tmp, current).await {
+        let _ = fs::remove_file(&tmp).await;
+        return Err(e.into());
+    }
+
+    Ok(())
+}
+
+async fn write_non_create(
+    path: impl AsRef<Path>,
+    contents: impl AsRef<[u8]>,
+) -> Result<(), anyhow::Error> {
+    OpenOptions::new()
+        .write(true)
+        .create_new(true)
+        .open(path)
+        .await?
+        .write_all(contents.as_ref())
+        .await
+        .map_err(Into::into)
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+    use crate::datatypes::FieldData;
+    use tempfile::TempDir;
+
+    /// A storage manager over `dir` that has not been migrated yet.
+    fn storage_in(dir: &TempDir) -> StorageManager {
+        let root = dir.path().to_string_lossy();
+        serde_json::from_value(serde_json::json!({
+            "path": format!("{root}/"),
+            "transaction_log": { "path": format!("{root}/transactions.log") },
+        }))
+        .unwrap()
+    }
+
+    /// A migrated storage manager rooted in a fresh temporary directory.
+    pub(crate) async fn storage() -> (TempDir, StorageManager) {
+        let dir = TempDir::new().unwrap();
+        let storage_manager = storage_in(&dir);
+
+        storage_manager.migrate().await.unwrap();
+
+        (dir, storage_manager)
+    }
+
+    pub(crate) fn template(name: &str, fields: serde_json::Value) -> FormTemplate {
+        serde_json::from_value(serde_json::json!({
+            "name": name,
+            "year": 2024,
+            "fields": fields,
+        }))
+        .unwrap()
+    }
+
+    pub(crate) fn scores_template() -> FormTemplate {
+        template(
+            "scores",
+            serde_json::json!([{ "data_type": "Number", "name": "score" }]),
+        )
+    }
+
+    pub(crate) fn form(team: i64, match_number: i64, scouter: &str, score: i64) -> Form {
+        let mut form = Form::default();
+        form.scouter = scouter.into();
+        form.team = team;
+        form.match_number = match_number;
+        form.event_key = "2024nyro".into();
+        form.add_field("score", FieldData::Number(score));
+
+        form
+    }
+
+    /// Logs a transaction for a file that only exists on another node.
+    async fn foreign_file(storage_manager: &StorageManager, data: &[u8]) -> InternalMessage {
+        let transaction = InternalMessage::new(
+            DataType::Bytes,
+            Action::Superseded,
+            format!("{}.{}", "key".digest(), Uuid::new_v4()),
+            "parent@example.com",
+        )
+        .with_checksum(data.digest());
+
+        storage_manager
+            .write_foreign_transaction(transaction.clone())
+            .await
+            .unwrap();
+
+        transaction
+    }
+
+    #[tokio::test]
+    async fn sync_blob_write_checks_recorded_checksum() {
+        let (_dir, storage_manager) = storage().await;
+        let transaction = foreign_file(&storage_manager, b"parent data").await;
+
+        let err = storage_manager
+            .sync_blob_write(transaction.id, b"something else")
+            .await
+            .unwrap_err();
+        assert!(matches!(
+            err.downcast_ref::<StorageError>(),
+            Some(StorageError::DigestMismatch)
+        ));
+
+        storage_manager
+            .sync_blob_write(transaction.id, b"parent data")
+            .await
+            .unwrap();
+        assert_eq!(
+            storage_manager
+                .sync_blob_read(transaction.id)
+                .await
+                .unwrap(),
+            b"parent data"
+        );
+    }
+
+    #[tokio::test]
+    async fn sync_blob_write_refuses_present_files() {
+        let (_dir, storage_manager) = storage().await;
+        let transaction = foreign_file(&storage_manager, b"parent data").await;
+
+        storage_manager
+            .sync_blob_write(transaction.id, b"parent data")
+            .await
+            .unwrap();
+        let err = storage_manager
+            .sync_blob_write(transaction.id, b"parent data")
+            .await
+            .unwrap_err();
+        assert!(matches!(
+            err.downcast_ref::<StorageError>(),
+            Some(StorageError::AlreadyExists { .. })
+        ));
+    }
+
+    #[tokio::test]
+    async fn sync_blob_write_keeps_local_versions() {
+        let (_dir, storage_manager) = storage().await;
+
+        storage_manager
+            .bytes_add("key".into(), b"local data", "user@example.com")
+            .await
+            .unwrap();
+        let add = storage_manager.transactions().await.unwrap().remove(0);
+        let stored = storage_manager.sync_blob_read(add.id).await.unwrap();
+
+        let err = storage_manager
+            .sync_blob_write(add.id, &stored)
+            .await
+            .unwrap_err();
+        assert!(matches!(
+            err.downcast_ref::<StorageError>(),
+            Some(StorageError::AlreadyExists { .. })
+        ));
+        assert_eq!(
+            storage_manager.bytes_get("key".into(), true).await.unwrap(),
+            b"local data"
+        );
+    }
+
+    /// A parent with a template and a form that were both added and edited,
+    /// the form's id, and the parent's log as ndjson.
+    async fn edited_parent() -> (TempDir, StorageManager, String, String) {
+        let (dir, parent) = storage().await;
+        let user = "parent@example.com";
+
+        parent.templates_add(scores_template(), user).await.unwrap();
+        let id = parent
+            .forms_add("scores".into(), form(5907, 1, "ada", 10), false, user)
+            .await
+            .unwrap();
+        parent
+            .templates_edit(scores_template(), false, user)
+            .await
+            .unwrap();
+        parent
+            .forms_edit(
+                "scores".into(),
+                form(5907, 1, "ada", 20),
+                id.clone(),
+                None,
+                user,
+            )
+            .await
+            .unwrap();
+
+        let ndjson = parent
+            .transactions()
+            .await
+            .unwrap()
+            .iter()
+            .map(|t| serde_json::to_string(t).unwrap())
+            .collect::<Vec<_>>()
+            .join("\n");
+
+        (dir, parent, id, ndjson)
+    }
+
+    #[tokio::test]
+    async fn sync_blob_read_serves_the_logged_version() {
+        let (_dir, parent, _, _) = edited_parent().await;
+        let transactions = parent.transactions().await.unwrap();
+
+        let mut template_versions = vec![];
+        let mut scores = vec![];
+        for transaction in &transactions {
+            let data = parent.sync_blob_read(transaction.id).await.unwrap();
+            assert_eq!(
+                transaction.checksum.as_deref(),
+                Some(data.as_slice().digest().as_str())
+            );
+
+            match transaction.data_type {
+                DataType::Template => {
+                    let template: FormTemplate = serde_json::from_slice(&data).unwrap();
+                    template_versions.push(template.version);
+                }
+                _ => {
+                    let form: Form = serde_json::from_slice(&data).unwrap();
+                    scores.push(form.get_field("score").cloned());
+                }
+            }
+        }
+
+        assert_eq!(template_versions.len(), 2);
+        assert_eq!(template_versions[1], template_versions[0] + 1);
+        assert!(matches!(
+            scores.as_slice(),
+            [Some(FieldData::Number(10)), Some(FieldData::Number(20))]
+        ));
+    }
+
+    #[tokio::test]
+    async fn pushed_versions_become_current_on_the_child() {
+        let (_parent_dir, parent, id, ndjson) = edited_parent().await;
+        let (_dir, child) = storage().await;
+
+        child.import_transactions(&ndjson).await.unwrap();
+        for transaction in parent.transactions().await.unwrap() {
+            let data = parent.sync_blob_read(transaction.id).await.unwrap();
+            child.sync_blob_write(transaction.id, &data).await.unwrap();
+        }
+
+        assert_eq!(
+            child.templates_get("scores".into()).await.unwrap().version,
+            parent.templates_get("scores".into()).await.unwrap().version
+        );
+        assert!(matches!(
+            child
+                .forms_get("scores".into(), id)
+                .await
+                .unwrap()
+                .get_field("score"),
+            Some(FieldData::Number(20))
+        ));
+    }
+
+    #[tokio::test]
+    async fn pushing_every_needed_blob_empties_the_list() {
+        let (_parent_dir, parent, _, _) = edited_parent().await;
+        let user = "parent@example.com";
+        let deleted = parent
+            .forms_add("scores".into(), form(5907, 2, "ada", 30), false, user)
+            .await
+            .unwrap();
+        parent
+            .forms_delete("scores".into(), deleted, user)
+            .await
+            .unwrap();
+        let ndjson = parent
+            .transactions()
+            .await
+            .unwrap()
+            .iter()
+            .map(|t| serde_json::to_string(t).unwrap())
+            .collect::<Vec<_>>()
+            .join("\n");
+        let (_dir, child) = storage().await;
+
+        child.import_transactions(&ndjson).await.unwrap();
+        let needed = child.needed_blobs().await.unwrap();
+        assert_eq!(needed.len(), 4);
+
+        for id in needed {
+            let data = parent.sync_blob_read(id).await.unwrap();
+            child.sync_blob_write(id, &data).await.unwrap();
+        }
+
+        assert!(child.needed_blobs().await.unwrap().is_empty());
+    }
+
+    #[tokio::test]
+    async fn template_edit_keeps_forms() {
+        let (_dir, storage_manager) = storage().await;
+        storage_manager
+            .templates_add(scores_template(), "lead@example.com")
+            .await
+            .unwrap();
+        let id = storage_manager
+            .forms_add(
+                "scores".into(),
+                form(5907, 1, "a", 3),
+                false,
+                "a@example.com",
+            )
+            .await
+            .unwrap();
+
+        let edited = template(
+            "scores",
+            serde_json::json!([
+                { "data_type": "Number", "name": "score" },
+                { "data_type": "LongText", "name": "notes", "optional": true },
+            ]),
+        );
+        storage_manager
+            .templates_edit(edited, false, "lead@example.com")
+            .await
+            .unwrap();
+
+        assert_eq!(
+            storage_manager.forms_list("scores".into()).await.unwrap(),
+            vec![id.clone()]
+        );
+        assert_eq!(
+            storage_manager
+                .forms_get("scores".into(), id)
+                .await
+                .unwrap()
+                .team,
+            5907
+        );
+    }
+
+    #[tokio::test]
+    async fn forms_keep_their_template_version() {
+        let (_dir, storage_manager) = storage().await;
+        storage_manager
+            .templates_add(scores_template(), "lead@example.com")
+            .await
+            .unwrap();
+        let old = storage_manager
+            .forms_add(
+                "scores".into(),
+                form(5907, 1, "a", 3),
+                false,
+                "a@example.com",
+            )
+            .await
+            .unwrap();
+
+        storage_manager
+            .templates_edit(scores_template(), false, "lead@example.com")
+            .await
+            .unwrap();
+        let new = storage_manager
+            .forms_add(
+                "scores".into(),
+                form(5907, 2, "a", 4),
+                false,
+                "a@example.com",
+            )
+            .await
+            .unwrap();
+
+        let old_form = storage_manager
+            .forms_get("scores".into(), old.clone())
+            .await
+            .unwrap();
+        let new_form = storage_manager
+            .forms_get("scores".into(), new)
+            .await
+            .unwrap();
+        assert_eq!(old_form.template_version, Some(1));
+        assert_eq!(new_form.template_version, Some(2));
+
+        let v1 = storage_manager
+            .forms_filter(
+                "scores".into(),
+                Filter {
+                    template_version: Some(1),
+                    ..Default::default()
+                },
+            )
+            .await
+            .unwrap();
+        assert_eq!(v1.len(), 1);
+        assert_eq!(v1[0].id, Some(old));
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 4)]
+    async fn concurrent_template_edits_get_distinct_versions() {
+        let (_dir, storage_manager) = storage().await;
+        let storage_manager = Arc::new(storage_manager);
+        storage_manager
+            .templates_add(scores_template(), "lead@example.com")
+            .await
+            .unwrap();
+        let before = storage_manager
+            .templates_get("scores".into())
+            .await
+            .unwrap()
+            .version;
+
+        let edits: Vec<_> = (0..8)
+            .map(|_| {
+                let storage_manager = storage_manager.clone();
+                tokio::spawn(async move {
+                    storage_manager
+                        .templates_edit(scores_template(), false, "lead@example.com")
+                        .await
+                })
+            })
+            .collect();
+        for edit in edits {
+            edit.await.unwrap().unwrap();
+        }
+
+        assert_eq!(
+            storage_manager
+                .templates_get("scores".into())
+                .await
+                .unwrap()
+                .version,
+            before + 8
+        );
+    }
+
+    #[tokio::test]
+    async fn template_patch_keeps_forms() {
+        let (_dir, storage_manager) = storage().await;
+        storage_manager
+            .templates_add(scores_template(), "lead@example.com")
+            .await
+            .unwrap();
+        let id = storage_manager
+            .forms_add(
+                "scores".into(),
+                form(5907, 1, "a", 3),
+                false,
+                "a@example.com",
+            )
+            .await
+            .unwrap();
+
+        let add_notes = PatchOperation::Add {
+            path: "/fields/-".into(),
+            value: serde_json::json!({ "data_type": "LongText", "name": "notes", "optional": true }),
+        };
+        storage_manager
+            .templates_patch("scores".into(), &[add_notes], false, "lead@example.com")
+            .await
+            .unwrap();
+
+        assert_eq!(
+            storage_manager.forms_list("scores".into()).await.unwrap(),
+            vec![id]
+        );
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 4)]
+    async fn concurrent_template_patches_keep_every_field() {
+        let (_dir, storage_manager) = storage().await;
+        let storage_manager = Arc::new(storage_manager);
+        storage_manager
+            .templates_add(scores_template(), "lead@example.com")
+            .await
+            .unwrap();
+
+        let patches: Vec<_> = ["notes", "comments", "fouls", "defense"]
+            .into_iter()
+            .map(|name| {
+                let storage_manager = storage_manager.clone();
+                tokio::spawn(async move {
+                    let add = PatchOperation::Add {
+                        path: "/fields/-".into(),
+                        value: serde_json::json!({ "data_type": "LongText", "name": name, "optional": true }),
+                    };
+                    storage_manager
+                        .templates_patch("scores".into(), &[add], false, "lead@example.com")
+                        .await
+                })
+            })
+            .collect();
+        for patch in patches {
+            patch.await.unwrap().unwrap();
+        }
+
+        let template = serde_json::to_value(
+            storage_manager
+                .templates_get("scores".into())
+                .await
+                .unwrap(),
+        )
+        .unwrap();
+        let mut names: Vec<&str> = template["fields"]
+            .as_array()
+            .unwrap()
+            .iter()
+            .filter_map(|field| field["name"].as_str())
+            .collect();
+        names.sort();
+        assert_eq!(names, ["comments", "defense", "fouls", "notes", "score"]);
+    }
+
+    fn indexed_scores_template() -> FormTemplate {
+        let mut template = serde_json::to_value(scores_template()).unwrap();
+        template["high_volume"] = true.into();
+        template["indexed_fields"] = serde_json::json!(["score"]);
+
+        serde_json::from_value(template).unwrap()
+    }
+
+    fn score_filter(score: i64) -> Filter {
+        Filter {
+            field: Some("score".into()),
+            value: Some(score),
+            ..Default::default()
+        }
+    }
+
+    #[tokio::test]
+    async fn indexed_fields_filter_in_the_query() {
+        let (_dir, storage_manager) = storage().await;
+        storage_manager
+            .templates_add(indexed_scores_template(), "lead@example.com")
+            .await
+            .unwrap();
+        for (team, score) in [(5907, 3), (254, 5), (1114, 3)] {
+            storage_manager
+                .forms_add(
+                    "scores".into(),
+                    form(team, 1, "a", score),
+                    false,
+                    "a@example.com",
+                )
+                .await
+                .unwrap();
+        }
+
+        let forms = storage_manager
+            .forms_filter("scores".into(), score_filter(3))
+            .await
+            .unwrap();
+
+        assert_eq!(forms.len(), 2);
+        assert!(forms.iter().all(|f| f.indexed.get("score") == Some(&3)));
+
+        // only the indexed column disagrees with the fields, so it alone decides
+        let id = storage_manager
+            .forms_add(
+                "scores".into(),
+                form(4414, 1, "a", 3),
+                false,
+                "a@example.com",
+            )
+            .await
+            .unwrap();
+        let mut stored = storage_manager
+            .forms_get("scores".into(), id.clone())
+            .await
+            .unwrap();
+        stored.indexed.insert("score".into(), 5);
+        let current = format!(
+            "{}{}.current",
+            storage_manager.dir(&format!("forms/{}.current/", "scores".digest())),
+            (&id).digest()
+        );
+        fs::remove_file(&current).await.unwrap();
+        fs::write(&current, serde_json::to_vec(&stored).unwrap())
+            .await
+            .unwrap();
+
+        let teams = |forms: Vec<Form>| {
+            let mut teams: Vec<i64> = forms.iter().map(|f| f.team).collect();
+            teams.sort();
+            teams
+        };
+        assert_eq!(
+            teams(
+                storage_manager
+                    .forms_filter("scores".into(), score_filter(5))
+                    .await
+                    .unwrap()
+            ),
+            [254, 4414]
+        );
+        assert_eq!(
+            teams(
+                storage_manager
+                    .forms_filter("scores".into(), score_filter(3))
+                    .await
+                    .unwrap()
+            ),
+            [1114, 5907]
+        );
+    }
+
+    #[tokio::test]
+    async fn indexed_filter_finds_forms_stored_before_indexing() {
+        let (_dir, storage_manager) = storage().await;
+        storage_manager
+            .templates_add(scores_template(), "lead@example.com")
+            .await
+            .unwrap();
+        storage_manager
+            .forms_add(
+                "scores".into(),
+                form(5907, 1, "a", 3),
+                false,
+                "a@example.com",
+            )
+            .await
+            .unwrap();
+        storage_manager
+            .forms_add(
+                "scores".into(),
+                form(254, 1, "a", 5),
+                false,
+                "a@example.com",
+            )
+            .await
+            .unwrap();
+
+        storage_manager
+            .templates_edit(indexed_scores_template(), false, "lead@example.com")
+            .await
+            .unwrap();
+        storage_manager
+            .forms_add(
+                "scores".into(),
+                form(1114, 2, "a", 3),
+                false,
+                "a@example.com",
+            )
+            .await
+            .unwrap();
+
+        let mut teams: Vec<i64> = storage_manager
+            .forms_filter("scores".into(), score_filter(3))
+            .await
+            .unwrap()
+            .iter()
+            .map(|f| f.team)
+            .collect();
+        teams.sort();
+
+        assert_eq!(teams, vec![1114, 5907]);
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 4)]
+    async fn concurrent_duplicate_forms_are_stored_once() {
+        let (_dir, storage_manager) = storage().await;
+        let storage_manager = Arc::new(storage_manager);
+        storage_manager
+            .templates_add(scores_template(), "lead@example.com")
+            .await
+            .unwrap();
+        // enough stored forms that the duplicate check takes a while
+        for team in 1..=50 {
+            storage_manager
+                .forms_add(
+                    "scores".into(),
+                    form(team, 1, "b", 3),
+                    false,
+                    "b@example.com",
+                )
+                .await
+                .unwrap();
+        }
+
+        let submissions: Vec<_> = (0..8)
+            .map(|_| {
+                let storage_manager = storage_manager.clone();
+                tokio::spawn(async move {
+                    storage_manager
+                        .forms_add(
+                            "scores".into(),
+                            form(5907, 1, "a", 3),
+                            true,
+                            "a@example.com",
+                        )
+                        .await
+                })
+            })
+            .collect();
+        let mut ids = HashSet::new();
+        for submission in submissions {
+            ids.insert(submission.await.unwrap().unwrap());
+        }
+
+        assert_eq!(ids.len(), 1);
+        assert_eq!(
+            storage_manager
+                .forms_list("scores".into())
+                .await
+                .unwrap()
+                .len(),
+            51
+        );
+    }
+
+    #[tokio::test]
+    async fn forms_list_follows_the_last_logged_action() {
+        let (_dir, storage_manager) = storage().await;
+        let user = "a@example.com";
+        storage_manager
+            .templates_add(scores_template(), user)
+            .await
+            .unwrap();
+        let mut ids = vec![];
+        for match_number in 1..=3 {
+            ids.push(
+                storage_manager
+                    .forms_add(
+                        "scores".into(),
+                        form(5907, match_number, "a", 3),
+                        false,
+                        user,
+                    )
+                    .await
+                    .unwrap(),
+            );
+        }
+        storage_manager
+            .forms_edit(
+                "scores".into(),
+                form(5907, 1, "a", 4),
+                ids[0].clone(),
+                None,
+                user,
+            )
+            .await
+            .unwrap();
+        storage_manager
+            .forms_delete("scores".into(), ids[1].clone(), user)
+            .await
+            .unwrap();
+
+        // a current file left behind for the deleted form does not bring it back
+        let delete = storage_manager.transactions().await.unwrap().pop().unwrap();
+        let dir = storage_manager.dir(&storage_manager.sub_path(&delete.data_type, ""));
+        fs::copy(
+            format!("{dir}{}", delete.new_path),
+            format!("{dir}{}.current", delete.alt_key()),
+        )
+        .await
+        .unwrap();
+
+        let mut listed = storage_manager.forms_list("scores".into()).await.unwrap();
+        listed.sort();
+        let mut live = vec![ids[0].clone(), ids[2].clone()];
+        live.sort();
+        assert_eq!(listed, live);
+    }
+
+    #[tokio::test]
+    async fn forms_list_orders_by_seq_after_a_clock_step() {
+        let (dir, storage_manager) = storage().await;
+        let user = "a@example.com";
+        storage_manager
+            .templates_add(scores_template(), user)
+            .await
+            .unwrap();
+        let deleted = storage_manager
+            .forms_add("scores".into(), form(5907, 1, "a", 3), false, user)
+            .await
+            .unwrap();
+        let kept = storage_manager
+            .forms_add("scores".into(), form(5907, 2, "a", 3), false, user)
+            .await
+            .unwrap();
+        storage_manager
+            .forms_delete("scores".into(), deleted.clone(), user)
+            .await
+            .unwrap();
+
+        // the clock stepped back an hour before the delete was written
+        let hour = 60 * 60 * 1_000_000;
+        let log: String = storage_manager
+            .transaction_log
+            .read_all()
+            .await
+            .unwrap()
+            .into_iter()
+            .map(|mut t| {
+                if t.action == Action::Delete {
+                    t.timestamp -= hour;
+                }
+                serde_json::to_string(&t).unwrap() + "\n"
+            })
+            .collect();
+        fs::write(&storage_manager.transaction_log.path, log)
+            .await
+            .unwrap();
+        let storage_manager = storage_in(&dir);
+
+        assert_eq!(
+            storage_manager.forms_list("scores".into()).await.unwrap(),
+            vec![kept]
+        );
+        assert!(storage_manager
+            .blob_deleted(DataType::Form("scores".into()), &deleted.digest())
+            .await
+            .unwrap());
+    }
+
+    #[tokio::test]
+    async fn repeated_deduped_submissions_return_the_first_id() {
+        let (_dir, storage_manager) = storage().await;
+        storage_manager
+            .templates_add(scores_template(), "lead@example.com")
+            .await
+            .unwrap();
+        let submit = || {
+            storage_manager.forms_add(
+                "scores".into(),
+                form(5907, 1, "a", 3),
+                true,
+                "a@example.com",
+            )
+        };
+
+        let first = submit().await.unwrap();
+        let second = submit().await.unwrap();
+
+        assert_eq!(first, second);
+        assert_eq!(
+            storage_manager.forms_list("scores".into()).await.unwrap(),
+            vec![first]
+        );
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 4)]
+    async fn idempotency_key_repeats_return_the_first_form() {
+        let (_dir, storage_manager) = storage().await;
+        let storage_manager = Arc::new(storage_manager);
+        storage_manager
+            .templates_add(scores_template(), "lead@example.com")
+            .await
+            .unwrap();
+
+        let submissions: Vec<_> = (0..4)
+            .map(|score| {
+                let storage_manager = storage_manager.clone();
+                tokio::spawn(async move {
+                    storage_manager
+                        .forms_add_idempotent(
+                            "scores".into(),
+                            form(5907, 1, "a", score),
+                            false,
+                            "retry".into(),
+                            "a@example.com",
+                        )
+                        .await
+                })
+            })
+            .collect();
+        let mut ids = HashSet::new();
+        for submission in submissions {
+            ids.insert(submission.await.unwrap().unwrap());
+        }
+
+        let other = storage_manager
+            .forms_add_idempotent(
+                "scores".into(),
+                form(5907, 1, "a", 3),
+                false,
+                "other".into(),
+                "a@example.com",
+            )
+            .await
+            .unwrap();
+
+        assert_eq!(ids.len(), 1);
+        assert!(!ids.contains(&other));
+        assert_eq!(
+            storage_manager
+                .forms_list("scores".into())
+                .await
+                .unwrap()
+                .len(),
+            2
+        );
+    }
+
+    #[tokio::test]
+    async fn idempotency_keys_are_per_user() {
+        let (_dir, storage_manager) = storage().await;
+        storage_manager
+            .templates_add(scores_template(), "lead@example.com")
+            .await
+            .unwrap();
+        let submit = |scouter: &'static str, user: &'static str| {
+            storage_manager.forms_add_idempotent(
+                "scores".into(),
+                form(5907, 1, scouter, 3),
+                false,
+                "retry".into(),
+                user,
+            )
+        };
+
+        let first = submit("a", "a@example.com").await.unwrap();
+        let repeat = submit("a", "a@example.com").await.unwrap();
+        let other = submit("b", "b@example.com").await.unwrap();
+
+        assert_eq!(first, repeat);
+        assert_ne!(first, other);
+        let mut listed = storage_manager.forms_list("scores".into()).await.unwrap();
+        listed.sort();
+        let mut stored = vec![first, other];
+        stored.sort();
+        assert_eq!(listed, stored);
+    }
+
+    #[tokio::test]
+    async fn upload_resumes_after_interruption() {
+        let (_dir, storage_manager) = storage().await;
+        let data = b"first chunk|second chunk|third chunk";
+        let (first, rest) = data.split_at(12);
+        let (second, third) = rest.split_at(13);
+        let total = Some(data.len() as u64);
+
+        storage_manager.upload_start("photo".into()).await.unwrap();
+        storage_manager
+            .upload_append("photo".into(), 0, total, first)
+            .await
+            .unwrap();
+        storage_manager
+            .upload_append("photo".into(), 12, total, second)
+            .await
+            .unwrap();
+
+        // the client lost the connection and asks where to carry on from
+        let resumed = storage_manager.upload_start("photo".into()).await.unwrap();
+        assert_eq!(resumed.offset, 25);
+        let err = storage_manager
+            .upload_append("photo".into(), 12, total, second)
+            .await
+            .unwrap_err();
+        assert!(matches!(
+            err.downcast_ref::<StorageError>(),
+            Some(StorageError::UploadOffsetMismatch(25))
+        ));
+
+        storage_manager
+            .upload_append("photo".into(), resumed.offset, total, third)
+            .await
+            .unwrap();
+        storage_manager
+            .upload_finalize("photo".into(), "a@example.com")
+            .await
+            .unwrap();
+
+        assert_eq!(
+            storage_manager
+                .bytes_get("photo".into(), true)
+                .await
+                .unwrap(),
+            data
+        );
+    }
+
+    #[tokio::test]
+    async fn retried_chunk_replaces_a_partial_write() {
+        let (_dir, storage_manager) = storage().await;
+        let total = Some(8);
+
+        storage_manager.upload_start("photo".into()).await.unwrap();
+        storage_manager
+            .upload_append("photo".into(), 0, total, b"data")
+            .await
+            .unwrap();
+
+        // a chunk that failed partway left some of its bytes behind
+        let part = storage_manager.upload_path(&"photo".digest());
+        let mut file = OpenOptions::new().append(true).open(&part).await.unwrap();
+        file.write_all(b"mo").await.unwrap();
+        file.flush().await.unwrap();
+
+        storage_manager
+            .upload_append("photo".into(), 4, total, b"more")
+            .await
+            .unwrap();
+        storage_manager
+            .upload_finalize("photo".into(), "a@example.com")
+            .await
+            .unwrap();
+
+        assert_eq!(
+            storage_manager
+                .bytes_get("photo".into(), true)
+                .await
+                .unwrap(),
+            b"datamore"
+        );
+    }
+
+    #[tokio::test]
+    async fn prune_removes_stale_uploads_and_keeps_active_ones() {
+        let (_dir, storage_manager) = storage().await;
+        for key in ["stale", "active"] {
+            storage_manager.upload_start(key.into()).await.unwrap();
+            storage_manager
+                .upload_append(key.into(), 0, None, b"chunk")
+                .await
+                .unwrap();
+
+            // both files look long idle, so only the session tells them apart
+            let part = std::fs::File::options()
+                .write(true)
+                .open(storage_manager.upload_path(&key.digest()))
+                .unwrap();
+            part.set_modified(std::time::SystemTime::now() - Duration::from_secs(2 * 24 * 60 * 60))
+                .unwrap();
+        }
+        storage_manager
+            .upload_sessions
+            .lock()
+            .await
+            .get_mut(&"stale".digest())
+            .unwrap()
+            .expires_at = 0;
+
+        assert_eq!(storage_manager.prune_uploads().await.unwrap(), 1);
+
+        assert!(fs::metadata(storage_manager.upload_path(&"stale".digest()))
+            .await
+            .is_err());
+        let err = storage_manager
+            .upload_append("stale".into(), 5, None, b"more")
+            .await
+            .unwrap_err();
+        assert!(matches!(
+            err.downcast_ref::<StorageError>(),
+            Some(StorageError::UploadNotFound)
+        ));
+        assert_eq!(
+            storage_manager
+                .upload_append("active".into(), 5, None, b"more")
+                .await
+                .unwrap()
+                .offset,
+            9
+        );
+    }
+
+    #[tokio::test]
+    async fn upload_finalize_needs_the_declared_total() {
+        let (_dir, storage_manager) = storage().await;
+
+        storage_manager.upload_start("photo".into()).await.unwrap();
+        storage_manager
+            .upload_append("photo".into(), 0, None, b"data")
+            .await
+            .unwrap();
+        let err = storage_manager
+            .upload_finalize("photo".into(), "a@example.com")
+            .await
+            .unwrap_err();
+        assert!(matches!(
+            err.downcast_ref::<StorageError>(),
+            Some(StorageError::UploadIncomplete { total: None, .. })
+        ));
+
+        storage_manager
+            .upload_append("photo".into(), 4, Some(8), b"da")
+            .await
+            .unwrap();
+        let err = storage_manager
+            .upload_finalize("photo".into(), "a@example.com")
+            .await
+            .unwrap_err();
+        assert!(matches!(
+            err.downcast_ref::<StorageError>(),
+            Some(StorageError::UploadIncomplete {
+                received: 6,
+                total: Some(8)
+            })
+        ));
+    }
+
+    #[tokio::test]
+    async fn failed_upload_finalize_keeps_the_session() {
+        let (_dir, storage_manager) = storage().await;
+        storage_manager
+            .bytes_add("photo".into(), b"already here", "a@example.com")
+            .await
+            .unwrap();
+
+        storage_manager.upload_start("photo".into()).await.unwrap();
+        storage_manager
+            .upload_append("photo".into(), 0, Some(4), b"data")
+            .await
+            .unwrap();
+        assert!(storage_manager
+            .upload_finalize("photo".into(), "a@example.com")
+            .await
+            .is_err());
+
+        let session = storage_manager.upload_start("photo".into()).await.unwrap();
+        assert_eq!(session.offset, 4);
+        assert!(!session.finalizing);
+        assert_eq!(
+            storage_manager
+                .bytes_get("photo".into(), true)
+                .await
+                .unwrap(),
+            b"already here"
+        );
+    }
+
+    #[tokio::test]
+    async fn dashboard_covers_one_template() {
+        let (_dir, storage_manager) = storage().await;
+        storage_manager
+            .templates_add(scores_template(), "lead@example.com")
+            .await
+            .unwrap();
+        storage_manager
+            .templates_add(template("pits", serde_json::json!([])), "lead@example.com")
+            .await
+            .unwrap();
+        for (team, match_number) in [(5907, 1), (254, 1), (5907, 2)] {
+            storage_manager
+                .forms_add(
+                    "scores".into(),
+                    form(team, match_number, "a", 3),
+                    false,
+                    "a@example.com",
+                )
+                .await
+                .unwrap();
+        }
+        let mut other_event = form(1114, 1, "a", 3);
+        other_event.event_key = "2024onto".into();
+        storage_manager
+            .forms_add("scores".into(), other_event, false, "a@example.com")
+            .await
+            .unwrap();
+        let mut pit = Form::default();
+        pit.scouter = "b".into();
+        pit.team = 5907;
+        pit.event_key = "2024nyro".into();
+        storage_manager
+            .forms_add("pits".into(), pit, false, "b@example.com")
+            .await
+            .unwrap();
+
+        let dashboard = storage_manager
+            .dashboard("scores".into(), Some("2024nyro".into()), 2)
+            .await
+            .unwrap();
+
+        assert_eq!(dashboard.teams, vec![254, 5907]);
+        let counts: Vec<(i64, usize)> = dashboard
+            .form_counts
+            .iter()
+            .map(|c| (c.team, c.forms))
+            .collect();
+        assert_eq!(counts, vec![(254, 1), (5907, 2)]);
+        let gaps: Vec<(i64, usize)> = dashboard
+            .coverage_gaps
+            .iter()
+            .map(|g| (g.match_number, g.forms))
+            .collect();
+        assert_eq!(gaps, vec![(1, 2), (2, 1)]);
+        assert_eq!(dashboard.latest_transactions.len(), 2);
+        assert!(dashboard
+            .latest_transactions
+            .iter()
+            .all(|t| t.data_type == DataType::Form("scores".into())));
+
+        let capped = storage_manager
+            .dashboard("scores".into(), None, usize::MAX)
+            .await
+            .unwrap();
+        assert_eq!(capped.latest_transactions.len(), 4);
+    }
+
+    /// Overwrites a stored blob's file with `contents`, as a crash might.
+    async fn corrupt_blob(storage_manager: &StorageManager, key: &str, contents: &[u8]) {
+        let name = key.digest();
+        let path = format!(
+            "{}{name}.current",
+            storage_manager.dir(&storage_manager.bytes_shard_dir(&name))
+        );
+        fs::write(path, contents).await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn bytes_size_reports_truncated_blobs() {
+        let (_dir, storage_manager) = storage().await;
+        storage_manager
+            .bytes_add("photo".into(), b"image data", "scout@example.com")
+            .await
+            .unwrap();
+        assert_eq!(
+            storage_manager.bytes_size("photo".into()).await.unwrap(),
+            Some(10)
+        );
+
+        let mut header_too_long = 100_u64.to_be_bytes().to_vec();
+        header_too_long.extend_from_slice(b"short");
+        for contents in [&header_too_long[..], &[0, 0, 0]] {
+            corrupt_blob(&storage_manager, "photo", contents).await;
+
+            let err = storage_manager
+                .bytes_size("photo".into())
+                .await
+                .unwrap_err();
+            assert!(matches!(
+                err.downcast_ref::<StorageError>(),
+                Some(StorageError::Truncated(_))
+            ));
+        }
+    }
+
+    #[tokio::test]
+    async fn bytes_get_reports_truncated_blobs() {
+        let (_dir, storage_manager) = storage().await;
+        storage_manager
+            .bytes_add("photo".into(), b"image data", "scout@example.com")
+            .await
+            .unwrap();
+
+        let mut header_too_long = 100_u64.to_be_bytes().to_vec();
+        header_too_long.extend_from_slice(b"short");
+        for contents in [&header_too_long[..], &[0, 0, 0], &[]] {
+            corrupt_blob(&storage_manager, "photo", contents).await;
+
+            let err = storage_manager
+                .bytes_get("photo".into(), false)
+                .await
+                .unwrap_err();
+            assert!(matches!(
+                err.downcast_ref::<StorageError>(),
+                Some(StorageError::Truncated(_))
+            ));
+        }
+    }
+
+    #[tokio::test]
+    async fn startup_backfills_seq_in_timestamp_order() {
+        let dir = TempDir::new().unwrap();
+        let stamped = |key: &str, timestamp: i64| {
+            let mut transaction =
+                InternalMessage::new(DataType::Bytes, Action::Add, key.into(), "a@example.com");
+            transaction.timestamp = timestamp;
+            transaction
+        };
+        // log order differs from timestamp order, and "b" and "c" tie
+        let written = [
+            stamped("a", 300),
+            stamped("b", 200),
+            stamped("c", 200),
+            stamped("d", 100),
+        ];
+        let log: String = written
+            .iter()
+            .map(|t| serde_json::to_string(t).unwrap() + "\n")
+            .collect();
+        fs::write(dir.path().join("transactions.log"), log)
+            .await
+            .unwrap();
+
+        let storage_manager = storage_in(&dir);
+        storage_manager.migrate().await.unwrap();
+
+        let backfilled = storage_manager.transaction_log.read_all().await.unwrap();
+        let order: Vec<(&str, u64, i64)> = backfilled
+            .iter()
+            .map(|t| (t.new_path.as_str(), t.seq, t.timestamp))
+            .collect();
+        assert_eq!(
+            order,
+            vec![("a", 4, 300), ("b", 2, 200), ("c", 3, 200), ("d", 1, 100)]
+        );
+
+        // a second startup leaves the numbering alone
+        storage_in(&dir).migrate().await.unwrap();
+        let again = storage_manager.transaction_log.read_all().await.unwrap();
+        assert_eq!(
+            again.iter().map(|t| t.seq).collect::<Vec<_>>(),
+            vec![4, 2, 3, 1]
+        );
+    }
+
+    #[tokio::test]
+    async fn migrate_shards_flat_blobs_and_leaves_other_files() {
+        let dir = TempDir::new().unwrap();
+        let bytes = dir.path().join("bytes");
+        fs::create_dir_all(&bytes).await.unwrap();
+        let blob = format!("{}.current", "photo".digest());
+        let probe = format!(".health-{}", Uuid::new_v4());
+        fs::write(bytes.join(&blob), b"blob").await.unwrap();
+        fs::write(bytes.join(&probe), b"ok").await.unwrap();
+
+        storage_in(&dir).migrate().await.unwrap();
+
+        assert!(bytes.join(shard(&blob)).join(&blob).is_file());
+        assert!(!bytes.join(&blob).exists());
+        assert!(bytes.join(&probe).is_file());
     }
 
-    #[instrument(skip(self, template))]
-    pub async fn templates_edit(&self, template: FormTemplate) -> Result<(), anyhow::Error> {
-        let digested_name = (&template.name).digest();
-        let old = format!("{}.{}", &digested_name, Uuid::new_v4());
-        let digested_name = format!("{}.current", digested_name);
+    #[tokio::test]
+    async fn imports_count_applied_skipped_and_missing_transactions() {
+        let (_parent_dir, _parent, _, ndjson) = edited_parent().await;
+        let (_dir, child) = storage().await;
 
-        self.raw_edit(
-            &digested_name,
-            &old,
-            "templates/",
-            serde_json::to_string(&template)?.as_bytes(),
-        )
-        .await?;
+        let first = child.import_transactions(&ndjson).await.unwrap();
+        assert_eq!(first.applied, 4);
+        assert_eq!(first.skipped, 0);
+        assert_eq!(first.superseded, 0);
+        // the log arrives before the blobs
+        assert_eq!(first.missing.len(), 4);
 
-        self.template_dir(&digested_name, Some(&old)).await?;
-        self.template_dir(&digested_name, None).await?;
+        let again = child.import_transactions(&ndjson).await.unwrap();
+        assert_eq!(again.applied, 0);
+        assert_eq!(again.skipped, 4);
+        assert!(again.missing.is_empty());
+    }
 
-        self.transaction_log
-            .log_transaction(InternalMessage::new(DataType::Template, Action::Edit, old))
+    #[tokio::test]
+    async fn known_ancestors_keep_seen_changes_out_of_conflict() {
+        let (_dir, storage_manager) = storage().await;
+        storage_manager
+            .bytes_add("photo".into(), b"local", "child@example.com")
             .await
-    }
+            .unwrap();
+        let local = storage_manager.transactions().await.unwrap().remove(0);
+        let edit = || {
+            InternalMessage::new(
+                DataType::Bytes,
+                Action::Edit,
+                format!("{}.{}", "photo".digest(), Uuid::new_v4()),
+                "parent@example.com",
+            )
+        };
+        let ndjson = |transactions: &[&InternalMessage]| {
+            transactions
+                .iter()
+                .map(|t| serde_json::to_string(t).unwrap())
+                .collect::<Vec<_>>()
+                .join("\n")
+        };
 
-    #[instrument(skip(self))]
-    pub async fn templates_delete(&self, name: String) -> Result<(), anyhow::Error> {
-        let digested_name = name.digest();
-        let old = format!("{}.{}", &digested_name, Uuid::new_v4());
-        let digested_name = format!("{}.current", digested_name);
+        // the sender had already seen the local add, so its edit follows it
+        let seen = edit();
+        let summary = storage_manager
+            .import_transactions(&ndjson(&[&local, &seen]))
+            .await
+            .unwrap();
+        assert_eq!(summary.applied, 1);
+        assert_eq!(summary.skipped, 1);
+        assert_eq!(summary.superseded, 0);
+        assert_eq!(summary.missing.len(), 1);
 
-        self.raw_delete(&digested_name, &old, "templates/").await?;
+        // without a shared ancestor the latest local change is a conflict
+        let unseen = edit();
+        let summary = storage_manager
+            .import_transactions(&ndjson(&[&unseen]))
+            .await
+            .unwrap();
+        assert_eq!(summary.applied, 1);
+        assert_eq!(summary.superseded, 1);
+    }
 
-        self.template_dir(&digested_name, Some(&old)).await?;
+    #[tokio::test]
+    async fn last_write_wins_on_original_timestamps() {
+        let (_dir, storage_manager) = storage().await;
+        let stamped = |timestamp: i64| {
+            let mut transaction = InternalMessage::new(
+                DataType::Bytes,
+                Action::Add,
+                format!("{}.current", "photo".digest()),
+                "parent@example.com",
+            );
+            transaction.timestamp = timestamp;
+            transaction.seq = 1;
+            transaction
+        };
+        let ndjson = |transaction: &InternalMessage| serde_json::to_string(transaction).unwrap();
 
-        self.transaction_log
-            .log_transaction(InternalMessage::new(
-                DataType::Template,
-                Action::Delete,
-                old,
-            ))
+        // imported long after it was made, so re-stamped with a later time
+        let earlier = stamped(100);
+        storage_manager
+            .import_transactions(&ndjson(&earlier))
             .await
-    }
+            .unwrap();
 
-    #[instrument(skip(self))]
-    pub async fn templates_get(&self, name: String) -> Result<FormTemplate, anyhow::Error> {
-        let digested_name = name.digest();
-        let digested_name = format!("{}.current", digested_name);
-        let bytes = self.raw_get(&digested_name, "templates/").await?;
+        let later = stamped(200);
+        storage_manager
+            .import_transactions(&ndjson(&later))
+            .await
+            .unwrap();
+        let current = storage_manager
+            .transaction_log
+            .latest_for(&DataType::Bytes, &"photo".digest())
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(current.id, later.id);
+        assert_eq!(current.origin(), (200, 1));
 
-        serde_json::from_slice(bytes.as_slice()).map_err(Into::into)
+        // same original time: the higher seq wins
+        let mut tied = stamped(200);
+        tied.seq = 2;
+        storage_manager
+            .import_transactions(&ndjson(&tied))
+            .await
+            .unwrap();
+        let current = storage_manager
+            .transaction_log
+            .latest_for(&DataType::Bytes, &"photo".digest())
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(current.id, tied.id);
     }
 
-    #[instrument(skip(self), ret)]
-    pub async fn templates_list(&self) -> Result<Vec<String>, anyhow::Error> {
-        if !self.df_ctx.table_exist("templates")? {
-            let path = ListingTableUrl::parse(format!("{}templates", self.path))?;
-            let file_format = JsonFormat::default();
-            let listing_options =
-                ListingOptions::new(Arc::new(file_format)).with_file_extension(".current");
-            let schema = SchemaRef::new(Schema::new(vec![Field::new(
-                "name",
-                datafusion::arrow::datatypes::DataType::Utf8,
-                false,
-            )]));
-            let config = ListingTableConfig::new(path)
-                .with_listing_options(listing_options)
-                .with_schema(schema);
-            let provider = Arc::new(ListingTable::try_new(config)?);
+    #[tokio::test]
+    async fn oversized_templates_are_rejected() {
+        let (_dir, mut storage_manager) = storage().await;
+        storage_manager.template_limits = TemplateLimits {
+            max_fields: 3,
+            max_bytes: 512,
+        };
+        let fields = |count: usize, name_len: usize| {
+            (0..count)
+                .map(|i| {
+                    serde_json::json!({
+                        "data_type": "Number",
+                        "name": format!("{i}{}", "x".repeat(name_len)),
+                    })
+                })
+                .collect::<serde_json::Value>()
+        };
 
-            self.df_ctx.register_table("templates", provider)?;
+        for (name, oversized) in [
+            ("many", template("many", fields(4, 1))),
+            ("long", template("long", fields(2, 400))),
+        ] {
+            let err = storage_manager
+                .templates_add(oversized, "admin@example.com")
+                .await
+                .unwrap_err();
+            assert!(matches!(
+                err.downcast_ref::<StorageError>(),
+                Some(StorageError::TemplateTooLarge(_))
+            ));
+            assert!(storage_manager.templates_get(name.into()).await.is_err());
         }
 
-        let df = self.df_ctx.table("templates").await?;
-        let res = df.select(vec![col("name")])?.collect().await?;
-
-        let res: Vec<&RecordBatch> = res.iter().collect();
+        storage_manager
+            .templates_add(template("small", fields(3, 1)), "admin@example.com")
+            .await
+            .unwrap();
+    }
 
-        let res = record_batches_to_json_rows(res.as_slice())?;
+    #[tokio::test]
+    async fn retention_windows_are_per_data_type() {
+        let (_dir, mut storage_manager) = storage().await;
+        storage_manager.retention.form_days = Some(30);
+        storage_manager.retention.bytes_days = Some(1);
+        storage_manager
+            .templates_add(scores_template(), "admin@example.com")
+            .await
+            .unwrap();
+        let id = storage_manager
+            .forms_add(
+                "scores".into(),
+                form(5907, 1, "a", 10),
+                false,
+                "a@example.com",
+            )
+            .await
+            .unwrap();
+        storage_manager
+            .bytes_add("photo".into(), b"image data", "a@example.com")
+            .await
+            .unwrap();
+        storage_manager
+            .forms_delete("scores".into(), id, "a@example.com")
+            .await
+            .unwrap();
+        storage_manager
+            .bytes_delete("photo".into(), "a@example.com")
+            .await
+            .unwrap();
 
-        let res = res
-            .iter()
-            .filter_map(|m| m.get("name"))
-            .filter_map(|thing| match thing {
-                Value::String(s) => Some(s.clone()),
-                _ => None,
+        // both deletes happened two days ago
+        let two_days = 2 * 24 * 60 * 60 * 1_000_000;
+        let mut deletes = vec![];
+        let log: String = storage_manager
+            .transaction_log
+            .read_all()
+            .await
+            .unwrap()
+            .into_iter()
+            .map(|mut t| {
+                if t.action == Action::Delete {
+                    t.timestamp -= two_days;
+                    deletes.push(t.clone());
+                }
+                serde_json::to_string(&t).unwrap() + "\n"
             })
             .collect();
+        fs::write(&storage_manager.transaction_log.path, log)
+            .await
+            .unwrap();
 
-        Ok(res)
-    }
+        assert_eq!(deletes.len(), 2);
+        assert_eq!(storage_manager.gc_blobs().await.unwrap(), 1);
 
-    #[instrument(skip(self, data))]
-    pub async fn bytes_add(
-        &self,
-        name: String,
-        desired_key: String,
-        data: &[u8],
-    ) -> Result<(), anyhow::Error> {
-        let name = format!("{name}.current");
+        for deleted in deletes {
+            let path = format!(
+                "{}{}",
+                storage_manager
+                    .dir(&storage_manager.sub_path(&deleted.data_type, &deleted.new_path)),
+                deleted.new_path
+            );
+            let kept = fs::metadata(&path).await.is_ok();
+            match deleted.data_type {
+                DataType::Form(_) => assert!(kept, "form inside its window was collected"),
+                DataType::Bytes => assert!(!kept, "bytes past their window were kept"),
+                other => panic!("unexpected delete of {other:?}"),
+            }
+        }
+    }
 
-        self.raw_add(
-            &name,
-            "bytes/",
-            &[
-                &(desired_key.len() as u64).to_be_bytes(),
-                desired_key.as_bytes(),
-                data,
-            ]
-            .concat(),
-        )
-        .await?;
+    #[tokio::test]
+    async fn arrow_stream_reads_back_as_a_typed_batch() {
+        use datafusion::arrow::datatypes::DataType as ArrowType;
+        use datafusion::arrow::ipc::reader::StreamReader;
 
-        self.transaction_log
-            .log_transaction(InternalMessage::new(DataType::Bytes, Action::Add, name))
+        let (_dir, storage_manager) = storage().await;
+        storage_manager
+            .templates_add(scores_template(), "lead@example.com")
             .await
-    }
+            .unwrap();
+        for team in [254, 1678, 5907] {
+            storage_manager
+                .forms_add(
+                    "scores".into(),
+                    form(team, 1, "a", 10),
+                    false,
+                    "a@example.com",
+                )
+                .await
+                .unwrap();
+        }
 
-    #[instrument(skip(self, data))]
-    pub async fn bytes_edit(
-        &self,
-        name: String,
-        desired_key: String,
-        data: &[u8],
-    ) -> Result<(), anyhow::Error> {
-        let old = format!("{}.{}", &name, Uuid::new_v4());
-        let name = format!("{name}.current");
+        let bytes = storage_manager
+            .forms_arrow("scores".into(), Filter::default())
+            .await
+            .unwrap();
+        let batches = StreamReader::try_new(std::io::Cursor::new(bytes), None)
+            .unwrap()
+            .collect::<Result<Vec<_>, _>>()
+            .unwrap();
 
-        self.raw_edit(
-            &name,
-            &old,
-            "bytes/",
-            &[
-                &(desired_key.len() as u64).to_be_bytes(),
-                desired_key.as_bytes(),
-                data,
+        let columns: Vec<(String, ArrowType)> = batches[0]
+            .schema()
+            .fields()
+            .iter()
+            .map(|f| (f.name().clone(), f.data_type().clone()))
+            .collect();
+        assert_eq!(
+            columns,
+            vec![
+                ("id".into(), ArrowType::Utf8),
+                ("scouter".into(), ArrowType::Utf8),
+                ("team".into(), ArrowType::Int64),
+                ("match_number".into(), ArrowType::Int64),
+                ("event_key".into(), ArrowType::Utf8),
+                ("score".into(), ArrowType::Int64),
             ]
-            .concat(),
-        )
-        .await?;
+        );
+        assert_eq!(batches.iter().map(|b| b.num_rows()).sum::<usize>(), 3);
+    }
 
-        self.transaction_log
-            .log_transaction(InternalMessage::new(DataType::Bytes, Action::Add, old))
+    #[tokio::test]
+    async fn strict_mode_rejects_undeclared_fields() {
+        let (_dir, mut storage_manager) = storage().await;
+        storage_manager
+            .templates_add(scores_template(), "lead@example.com")
             .await
-    }
+            .unwrap();
+        let mut extra = form(5907, 1, "a", 10);
+        extra.add_field("comment", FieldData::ShortText("fast".into()));
 
-    #[instrument(skip(self))]
-    pub async fn bytes_delete(&self, name: String) -> Result<(), anyhow::Error> {
-        let old = format!("{}.{}", &name, Uuid::new_v4());
-        let name = format!("{name}.current");
+        storage_manager
+            .forms_add("scores".into(), extra.clone(), false, "a@example.com")
+            .await
+            .unwrap();
 
-        self.raw_delete(&name, &old, "bytes/").await?;
+        storage_manager.strict_forms = true;
+        let err = storage_manager
+            .forms_add("scores".into(), extra, false, "a@example.com")
+            .await
+            .unwrap_err();
+        assert!(matches!(
+            err.downcast_ref::<StorageError>(),
+            Some(StorageError::UnknownFields(names)) if names == &["comment"]
+        ));
 
-        self.transaction_log
-            .log_transaction(InternalMessage::new(DataType::Bytes, Action::Add, old))
+        let stored = storage_manager
+            .forms_filter("scores".into(), Filter::default())
             .await
+            .unwrap();
+        assert_eq!(stored.len(), 1);
     }
 
-    #[instrument(skip(self))]
-    pub async fn bytes_list(&self) -> Result<Vec<String>, anyhow::Error> {
-        let mut entries = fs::read_dir(format!("{}bytes/", self.path)).await?;
-        let mut keys: Vec<String> = Vec::new();
-
-        while let Some(entry) = entries.next_entry().await? {
-            if entry.path().to_string_lossy().ends_with(".current") {
-                let mut f = File::open(entry.path()).await?;
-                let len = f.read_u64().await?;
-                let mut bytes = vec![0_u8; len as usize];
+    #[tokio::test]
+    async fn forms_filter_by_scouter() {
+        let (_dir, storage_manager) = storage().await;
+        storage_manager
+            .templates_add(scores_template(), "lead@example.com")
+            .await
+            .unwrap();
+        let mut elsewhere = form(254, 2, "alice", 30);
+        elsewhere.event_key = "2024nyli".into();
+        for scouted in [
+            form(5907, 1, "alice", 10),
+            form(1678, 1, "bob", 20),
+            elsewhere,
+        ] {
+            storage_manager
+                .forms_add("scores".into(), scouted, false, "a@example.com")
+                .await
+                .unwrap();
+        }
 
-                f.read_exact(&mut bytes).await?;
+        let by_scouter = |event: Option<&str>| Filter {
+            scouter: Some("alice".into()),
+            event: event.map(Into::into),
+            ..Default::default()
+        };
 
-                keys.push(String::from_utf8_lossy(&bytes[..]).to_string());
-            }
-        }
+        let mut teams: Vec<i64> = storage_manager
+            .forms_filter("scores".into(), by_scouter(None))
+            .await
+            .unwrap()
+            .iter()
+            .map(|f| f.team)
+            .collect();
+        teams.sort();
+        assert_eq!(teams, vec![254, 5907]);
 
-        Ok(keys)
+        let at_event = storage_manager
+            .forms_filter("scores".into(), by_scouter(Some("2024nyro")))
+            .await
+            .unwrap();
+        assert_eq!(at_event.len(), 1);
+        assert_eq!(at_event[0].team, 5907);
+        assert_eq!(at_event[0].scouter, "alice");
     }
 
-    #[instrument(skip(self))]
-    pub async fn bytes_get(&self, name: String) -> Result<Vec<u8>, anyhow::Error> {
-        let name = format!("{name}.current");
+    #[tokio::test]
+    async fn blob_deleted_breaks_timestamp_ties_by_log_order() {
+        let key = "photo".digest();
+        let at = |action: Action, new_path: String| {
+            let mut transaction =
+                InternalMessage::new(DataType::Bytes, action, new_path, "a@example.com");
+            transaction.timestamp = 1_700_000_000_000_000;
+            transaction
+        };
+        let add = at(Action::Add, format!("{key}.current"));
+        let delete = at(Action::Delete, format!("{key}.{}", Uuid::new_v4()));
 
-        let bytes = self.raw_get(&name, "bytes/").await?;
+        for (log, deleted) in [([&add, &delete], true), ([&delete, &add], false)] {
+            let dir = TempDir::new().unwrap();
+            let contents: String = log
+                .iter()
+                .map(|t| serde_json::to_string(t).unwrap() + "\n")
+                .collect();
+            fs::write(dir.path().join("transactions.log"), contents)
+                .await
+                .unwrap();
+            let storage_manager = storage_in(&dir);
+            storage_manager.migrate().await.unwrap();
 
-        let len = u64::from_be_bytes([
-            bytes[0], bytes[1], bytes[2], bytes[3], bytes[4], bytes[5], bytes[6], bytes[7],
-        ]);
-
-        Ok(Vec::from(&bytes[(len as usize + 8)..]))
+            assert_eq!(
+                storage_manager
+                    .blob_deleted(DataType::Bytes, &key)
+                    .await
+                    .unwrap(),
+                deleted
+            );
+        }
     }
 
-    pub async fn get_first(&self) -> Result<InternalMessage, anyhow::Error> {
-        self.transaction_log.get_first().await
-    }
+    #[tokio::test]
+    async fn team_compares_across_two_events() {
+        let (_dir, storage_manager) = storage().await;
+        storage_manager
+            .templates_add(scores_template(), "lead@example.com")
+            .await
+            .unwrap();
+        let at = |event: &str, team: i64, match_number: i64, score: i64| {
+            let mut scouted = form(team, match_number, "a", score);
+            scouted.event_key = event.into();
+            scouted
+        };
+        for scouted in [
+            at("2024nyro", 5907, 1, 10),
+            at("2024nyro", 5907, 2, 20),
+            at("2024nyro", 254, 1, 90),
+            at("2024nyli", 5907, 1, 30),
+            at("2024nyli", 5907, 2, 40),
+            at("2024nyli", 5907, 3, 80),
+        ] {
+            storage_manager
+                .forms_add("scores".into(), scouted, false, "a@example.com")
+                .await
+                .unwrap();
+        }
 
-    pub async fn get_after(&self, id: Uuid) -> Result<InternalMessage, anyhow::Error> {
-        self.transaction_log.get_after(id).await
-    }
+        let comparison = storage_manager
+            .team_event_comparison(
+                "scores".into(),
+                5907,
+                "score".into(),
+                "2024nyro".into(),
+                "2024nyli".into(),
+            )
+            .await
+            .unwrap();
 
-    pub async fn list_files(&self) -> Result<Vec<String>, anyhow::Error> {
-        self.transaction_log.list_files().await
+        assert_eq!(comparison.event_a.count, 2);
+        assert_eq!(comparison.event_a.mean, 15.0);
+        assert_eq!(comparison.event_a.median, 15.0);
+        assert_eq!(comparison.event_b.count, 3);
+        assert_eq!(comparison.event_b.mean, 50.0);
+        assert_eq!(comparison.event_b.median, 40.0);
+        assert_eq!(comparison.mean_delta, 35.0);
+        assert_eq!(comparison.median_delta, 25.0);
     }
 
-    pub async fn get_file(&self, path: String) -> Result<Vec<u8>, anyhow::Error> {
-        self.transaction_log.get_file(path).await
-    }
-}
+    #[tokio::test]
+    async fn sync_diff_counts_what_the_parent_is_ahead_by() {
+        let (_dir, storage_manager) = storage().await;
+        for key in ["a", "b"] {
+            storage_manager
+                .bytes_add(key.into(), b"shared", "a@example.com")
+                .await
+                .unwrap();
+        }
 
-#[derive(Debug, Default, Deserialize)]
-struct TransactionLog {
-    path: String,
-}
+        let mut parent = storage_manager.transactions().await.unwrap();
+        let ahead: Vec<InternalMessage> = ["c", "d", "e"]
+            .iter()
+            .map(|key| {
+                let mut transaction = InternalMessage::new(
+                    DataType::Bytes,
+                    Action::Add,
+                    key.digest(),
+                    "parent@example.com",
+                );
+                transaction.new_path = StorageManager::version_path(&transaction);
 
-impl TransactionLog {
-    #[instrument]
-    async fn log_transaction(&self, transaction: InternalMessage) -> Result<(), anyhow::Error> {
-        let mut file = OpenOptions::new()
-            .write(true)
-            .append(true)
-            .create(true)
-            .open(&self.path)
-            .await?;
+                transaction.with_checksum(key.digest())
+            })
+            .collect();
+        parent.extend(ahead.iter().cloned());
 
-        file.write_all(format!("{}\n", serde_json::to_string(&transaction)?).as_bytes())
+        // one of the parent's files was already copied over
+        let copied = &ahead[0].new_path;
+        let shard = storage_manager.dir(&storage_manager.bytes_shard_dir(copied));
+        fs::create_dir_all(&shard).await.unwrap();
+        fs::write(format!("{shard}{copied}"), b"copied")
             .await
-            .map_err(Into::into)
+            .unwrap();
+
+        let diff = storage_manager.sync_diff(&parent).await.unwrap();
+
+        assert_eq!(diff.parent_head, Some(ahead[2].id));
+        assert_eq!(diff.missing_transactions, 3);
+        assert_eq!(diff.missing_blobs, 2);
     }
 
-    #[instrument]
-    pub async fn get_first(&self) -> Result<InternalMessage, anyhow::Error> {
-        let file = File::open(&self.path).await?;
-        let mut line: String = String::new();
+    #[tokio::test]
+    async fn reserved_template_names_are_rejected() {
+        let (_dir, storage_manager) = storage().await;
+        let reserved = [
+            "templates",
+            "schedules",
+            "forms",
+            "bytes",
+            "uploads",
+            "transactions",
+            "Templates",
+            "BYTES",
+        ];
 
-        BufReader::new(file).read_line(&mut line).await?;
+        for name in reserved {
+            let err = storage_manager
+                .templates_add(template(name, serde_json::json!([])), "lead@example.com")
+                .await
+                .unwrap_err();
+            assert!(
+                matches!(
+                    err.downcast_ref::<StorageError>(),
+                    Some(StorageError::TemplateNameReserved(n)) if n == name
+                ),
+                "{name} was accepted"
+            );
+        }
 
-        Ok(serde_json::from_str(&line)?)
+        storage_manager
+            .templates_add(
+                template("forms2024", serde_json::json!([])),
+                "lead@example.com",
+            )
+            .await
+            .unwrap();
     }
 
-    #[instrument]
-    pub async fn get_after(&self, id: Uuid) -> Result<InternalMessage, anyhow::Error> {
-        let file = File::open(&self.path).await?;
-        let mut lines = BufReader::new(file).lines();
+    #[tokio::test]
+    async fn latest_lookups_on_a_large_log_come_from_the_index() {
+        const TRANSACTIONS: u64 = 100_000;
+        const ITEMS: u64 = 1_000;
 
-        while let Some(line) = lines.next_line().await? {
-            let de = serde_json::from_str::<InternalMessage>(&line)?;
+        let dir = TempDir::new().unwrap();
+        let mut log = String::new();
+        for seq in 1..=TRANSACTIONS {
+            let mut transaction = InternalMessage::new(
+                DataType::Bytes,
+                Action::Edit,
+                format!("{}.{}", (seq % ITEMS).to_string().digest(), Uuid::new_v4()),
+                "a@example.com",
+            );
+            transaction.seq = seq;
+            log.push_str(&serde_json::to_string(&transaction).unwrap());
+            log.push('\n');
+        }
+        fs::write(dir.path().join("transactions.log"), log)
+            .await
+            .unwrap();
+        let storage_manager = storage_in(&dir);
+        storage_manager.migrate().await.unwrap();
 
-            if de.id == id {
-                let line = lines.next_line().await?;
+        // the first lookup loads the index
+        let last = (TRANSACTIONS % ITEMS).to_string().digest();
+        assert!(storage_manager
+            .latest_blob_from_alt_key(DataType::Bytes, &last)
+            .await
+            .unwrap()
+            .is_some());
 
-                return match line {
-                    None => Err(anyhow!("explode")),
-                    Some(line) => Ok(serde_json::from_str::<InternalMessage>(&line)?),
-                };
-            }
-        }
+        // with the log gone, only the index can answer
+        let log = dir.path().join("transactions.log");
+        fs::rename(&log, dir.path().join("transactions.log.moved"))
+            .await
+            .unwrap();
+        fs::create_dir(&log).await.unwrap();
+        assert!(storage_manager.transaction_log.read_all().await.is_err());
 
-        Err(anyhow!("dfasdfjkh"))
+        for item in 0..ITEMS {
+            storage_manager
+                .latest_blob_from_alt_key(DataType::Bytes, &item.to_string().digest())
+                .await
+                .unwrap()
+                .unwrap();
+        }
     }
 
-    #[instrument]
-    pub async fn get_file(&self, path: String) -> Result<Vec<u8>, anyhow::Error> {
-        let mut buf = vec![];
+    #[tokio::test]
+    async fn clean_install_stores_and_reads_blobs() {
+        let dir = TempDir::new().unwrap();
+        let storage_manager = storage_in(&dir);
+        let bytes_dir = storage_manager.dir(&storage_manager.bytes_dir());
+        assert!(fs::metadata(&bytes_dir).await.is_err());
 
-        File::open(path).await?.read_to_end(&mut buf).await?;
-        Ok(buf)
+        storage_manager.migrate().await.unwrap();
+        assert!(fs::metadata(&bytes_dir).await.unwrap().is_dir());
+
+        storage_manager
+            .bytes_add("photo".into(), b"image data", "a@example.com")
+            .await
+            .unwrap();
+        assert_eq!(
+            storage_manager
+                .bytes_get("photo".into(), true)
+                .await
+                .unwrap(),
+            b"image data"
+        );
     }
 
-    #[instrument]
-    pub async fn list_files(&self) -> Result<Vec<String>, anyhow::Error> {
-        let glob = glob("data/*")
-            .unwrap()
-            .filter_map(|p| p.ok())
-            .filter(|p| p.is_file())
-            .map(|p| p.as_path().to_string_lossy().to_string())
-            .collect();
+    #[tokio::test]
+    async fn bytes_round_trip_through_raw_keys() {
+        let (_dir, storage_manager) = storage().await;
+        storage_manager
+            .bytes_add("robot photo".into(), b"image data", "a@example.com")
+            .await
+            .unwrap();
+        assert!(!storage_manager.bytes_deleted("robot photo").await.unwrap());
 
-        Ok(glob)
-    }
-}
+        storage_manager
+            .bytes_delete("robot photo".into(), "a@example.com")
+            .await
+            .unwrap();
 
-async fn write_non_create(
-    path: impl AsRef<Path>,
-    contents: impl AsRef<[u8]>,
-) -> Result<(), anyhow::Error> {
-    OpenOptions::new()
-        .write(true)
-        .create_new(true)
-        .open(path)
-        .await?
-        .write_all(contents.as_ref())
-        .await
-        .map_err(Into::into)
+        assert!(storage_manager.bytes_deleted("robot photo").await.unwrap());
+        assert_eq!(
+            storage_manager
+                .bytes_size("robot photo".into())
+                .await
+                .unwrap(),
+            None
+        );
+        let err = storage_manager
+            .bytes_get("robot photo".into(), false)
+            .await
+            .unwrap_err();
+        assert!(matches!(
+            err.downcast_ref::<StorageError>(),
+            Some(StorageError::Deleted)
+        ));
+
+        storage_manager
+            .bytes_add("robot photo".into(), b"new image", "a@example.com")
+            .await
+            .unwrap();
+        assert_eq!(
+            storage_manager
+                .bytes_get("robot photo".into(), true)
+                .await
+                .unwrap(),
+            b"new image"
+        );
+    }
 }
@@ -1,14 +1,16 @@
-use crate::storage_manager::StorageManager;
-use crate::transactions::InternalMessage;
-use anyhow::Error;
-use axum::extract::Path;
-use axum::http::StatusCode;
+use crate::auth::GoogleUser;
+use crate::storage_manager::{StorageError, StorageManager};
+use crate::transactions::{InternalMessage, SyncConflict, SyncDiff};
+use anyhow::anyhow;
+use axum::body::Bytes;
+use axum::extract::{Path, Query};
+use axum::http::{header, HeaderMap, StatusCode};
 use axum::response::{IntoResponse, Response};
 use axum::{Extension, Json};
+use serde::Deserialize;
+use std::io::Read;
 use std::sync::Arc;
-use tokio::fs::File;
-use tokio::io::AsyncReadExt;
-use tracing::{info, instrument};
+use tracing::{info, instrument, warn};
 use uuid::Uuid;
 
 #[instrument(skip(storage_manager))]
@@ -30,6 +32,229 @@ pub async fn sync(
     }
 }
 
+/// Content type of a zstd-compressed stream of length-prefixed transactions.
+const FRAMES_CONTENT_TYPE: &str = "application/vnd.scouting.frames+zstd";
+/// Largest frame a peer may send. Transactions are a few hundred bytes, so
+/// anything near this is a corrupt length rather than a real one.
+const MAX_FRAME_BYTES: usize = 1024 * 1024;
+
+#[instrument(skip(headers, storage_manager))]
+pub async fn log(
+    headers: HeaderMap,
+    storage_manager: Extension<Arc<StorageManager>>,
+) -> SyncResponse {
+    match storage_manager.transactions().await {
+        Ok(log) => SyncResponse::log(log, &headers),
+        Err(_) => SyncResponse::Internal,
+    }
+}
+
+#[instrument(skip(headers, storage_manager))]
+pub async fn since(
+    Path(micros): Path<i64>,
+    headers: HeaderMap,
+    storage_manager: Extension<Arc<StorageManager>>,
+) -> SyncResponse {
+    match storage_manager.transactions_since(micros).await {
+        Ok(log) => SyncResponse::log(log, &headers),
+        Err(_) => SyncResponse::Internal,
+    }
+}
+
+#[instrument(skip(storage_manager))]
+pub async fn pull_blob(
+    user: GoogleUser,
+    Path(id): Path<Uuid>,
+    storage_manager: Extension<Arc<StorageManager>>,
+) -> SyncResponse {
+    if !storage_manager.sync_child_allowed(&user.email) {
+        return SyncResponse::Forbidden;
+    }
+
+    match storage_manager.sync_blob_read(id).await {
+        Ok(data) => SyncResponse::File(data),
+        Err(e) => SyncResponse::from_error(e),
+    }
+}
+
+#[instrument(skip(storage_manager, body))]
+pub async fn push_blob(
+    user: GoogleUser,
+    Path(id): Path<Uuid>,
+    storage_manager: Extension<Arc<StorageManager>>,
+    body: Bytes,
+) -> SyncResponse {
+    if !storage_manager.sync_child_allowed(&user.email) {
+        return SyncResponse::Forbidden;
+    }
+
+    match storage_manager.sync_blob_write(id, &body).await {
+        Ok(_) => SyncResponse::Stored,
+        Err(e) => SyncResponse::from_error(e),
+    }
+}
+
+#[instrument(skip(storage_manager))]
+pub async fn needed_blobs(
+    user: GoogleUser,
+    storage_manager: Extension<Arc<StorageManager>>,
+) -> SyncResponse {
+    if !storage_manager.sync_child_allowed(&user.email) {
+        return SyncResponse::Forbidden;
+    }
+
+    match storage_manager.needed_blobs().await {
+        Ok(needed) => SyncResponse::Needed(needed),
+        Err(e) => SyncResponse::from_error(e),
+    }
+}
+
+#[instrument(skip(storage_manager))]
+pub async fn conflicts(
+    Query(query): Query<ConflictsQuery>,
+    storage_manager: Extension<Arc<StorageManager>>,
+) -> SyncResponse {
+    match storage_manager
+        .detect_sync_conflicts(query.since.unwrap_or(0))
+        .await
+    {
+        Ok(conflicts) => SyncResponse::Conflicts(conflicts),
+        Err(_) => SyncResponse::Internal,
+    }
+}
+
+#[derive(Debug, Deserialize)]
+pub struct ConflictsQuery {
+    /// Unix micros; only conflicts resolved after this are reported.
+    since: Option<i64>,
+}
+
+#[instrument(skip(storage_manager))]
+pub async fn diff_summary(storage_manager: Extension<Arc<StorageManager>>) -> SyncResponse {
+    let parent = match &storage_manager.sync_parent {
+        Some(parent) => parent,
+        None => return SyncResponse::NotFound,
+    };
+
+    let parent_log = match fetch_parent_log(&parent.url, &parent.jwt).await {
+        Ok(log) => log,
+        Err(e) => {
+            warn!("could not reach sync parent: {e}");
+            return SyncResponse::ParentUnavailable;
+        }
+    };
+
+    match storage_manager.sync_diff(&parent_log).await {
+        Ok(diff) => SyncResponse::Diff(diff),
+        Err(_) => SyncResponse::Internal,
+    }
+}
+
+async fn fetch_parent_log(url: &str, jwt: &str) -> Result<Vec<InternalMessage>, anyhow::Error> {
+    let res = reqwest::Client::new()
+        .get(format!("{}/protected/sync/log", url.trim_end_matches('/')))
+        .header("authorization", jwt)
+        .header("accept-encoding", "zstd")
+        .send()
+        .await?
+        .error_for_status()?;
+
+    let framed = res
+        .headers()
+        .get(reqwest::header::CONTENT_TYPE)
+        .is_some_and(|t| t == FRAMES_CONTENT_TYPE);
+
+    if framed {
+        decode_frames(&res.bytes().await?)
+    } else {
+        res.json().await.map_err(Into::into)
+    }
+}
+
+/// Writes each transaction as a big-endian `u32` length followed by its JSON,
+/// then zstd-compresses the lot.
+fn encode_frames(log: &[InternalMessage]) -> Result<Vec<u8>, anyhow::Error> {
+    let mut frames = vec![];
+
+    for transaction in log {
+        let json = serde_json::to_vec(transaction)?;
+        frames.extend_from_slice(&(json.len() as u32).to_be_bytes());
+        frames.extend_from_slice(&json);
+    }
+
+    Ok(zstd::encode_all(frames.as_slice(), 0)?)
+}
+
+/// Reverses [`encode_frames`]. The stream may only end between frames; a
+/// stream cut off inside one, or a frame over [`MAX_FRAME_BYTES`], is an error.
+fn decode_frames(compressed: &[u8]) -> Result<Vec<InternalMessage>, anyhow::Error> {
+    let mut frames = zstd::Decoder::new(compressed)?;
+    let mut log = vec![];
+    let mut len = [0u8; 4];
+
+    loop {
+        let started = loop {
+            match frames.read(&mut len[..1]) {
+                Err(e) if e.kind() == std::io::ErrorKind::Interrupted => continue,
+                read => break read?,
+            }
+        };
+
+        if started == 0 {
+            return Ok(log);
+        }
+
+        frames
+            .read_exact(&mut len[1..])
+            .map_err(|e| anyhow!("truncated frame length after {} frames: {e}", log.len()))?;
+
+        let len = u32::from_be_bytes(len) as usize;
+
+        if len > MAX_FRAME_BYTES {
+            return Err(anyhow!("frame of {len} bytes is over {MAX_FRAME_BYTES}"));
+        }
+
+        let mut json = vec![0u8; len];
+        frames
+            .read_exact(&mut json)
+            .map_err(|e| anyhow!("truncated frame after {} frames: {e}", log.len()))?;
+        log.push(serde_json::from_slice(&json)?);
+    }
+}
+
+fn accepts_zstd(headers: &HeaderMap) -> bool {
+    headers
+        .get_all(header::ACCEPT_ENCODING)
+        .iter()
+        .filter_map(|v| v.to_str().ok())
+        .flat_map(|v| v.split(','))
+        .any(|encoding| encoding.split(';').next().unwrap_or_default().trim() == "zstd")
+}
+
+impl SyncResponse {
+    fn from_error(error: anyhow::Error) -> SyncResponse {
+        match error.downcast::<StorageError>() {
+            Ok(StorageError::NotFound) => SyncResponse::NotFound,
+            Ok(StorageError::DigestMismatch) => SyncResponse::DigestMismatch,
+            Ok(StorageError::AlreadyExists { .. }) => SyncResponse::AlreadyStored,
+            _ => SyncResponse::Internal,
+        }
+    }
+
+    /// Frames the log when the peer asked for zstd, plain JSON otherwise.
+    fn log(log: Vec<InternalMessage>, headers: &HeaderMap) -> SyncResponse {
+        if !accepts_zstd(headers) {
+            return SyncResponse::Log(log);
+        }
+
+        match encode_frames(&log) {
+            Ok(frames) => SyncResponse::Frames(frames),
+            Err(_) => SyncResponse::Internal,
+        }
+    }
+}
+
+#[allow(dead_code)]
 #[instrument(skip(storage_manager))]
 pub async fn get_file(
     Path(path): Path<String>,
@@ -41,6 +266,7 @@ pub async fn get_file(
     }
 }
 
+#[allow(dead_code)]
 #[instrument(skip(storage_manager))]
 pub async fn list_files(storage_manager: Extension<Arc<StorageManager>>) -> SyncResponse {
     match storage_manager.list_files().await {
@@ -56,15 +282,105 @@ impl IntoResponse for SyncResponse {
             SyncResponse::NotFound => StatusCode::NOT_FOUND.into_response(),
             SyncResponse::File(f) => (StatusCode::OK, f).into_response(),
             SyncResponse::Files(f) => Json(f).into_response(),
+            SyncResponse::Log(log) => Json(log).into_response(),
+            // the identity encoding keeps the compression layer from wrapping the frames again
+            SyncResponse::Frames(frames) => (
+                StatusCode::OK,
+                [
+                    (header::CONTENT_TYPE, FRAMES_CONTENT_TYPE),
+                    (header::CONTENT_ENCODING, "identity"),
+                ],
+                frames,
+            )
+                .into_response(),
+            SyncResponse::Diff(diff) => Json(diff).into_response(),
+            SyncResponse::Conflicts(conflicts) => Json(conflicts).into_response(),
+            SyncResponse::ParentUnavailable => StatusCode::BAD_GATEWAY.into_response(),
+            SyncResponse::Stored => StatusCode::OK.into_response(),
+            SyncResponse::Needed(ids) => Json(ids).into_response(),
+            SyncResponse::Forbidden => StatusCode::FORBIDDEN.into_response(),
+            SyncResponse::DigestMismatch => StatusCode::UNPROCESSABLE_ENTITY.into_response(),
+            SyncResponse::AlreadyStored => StatusCode::CONFLICT.into_response(),
             SyncResponse::Internal => StatusCode::INTERNAL_SERVER_ERROR.into_response(),
         }
     }
 }
 
+#[allow(dead_code)]
 pub enum SyncResponse {
     OK(InternalMessage),
     File(Vec<u8>),
     Files(Vec<String>),
+    Log(Vec<InternalMessage>),
+    Frames(Vec<u8>),
+    Diff(SyncDiff),
+    Conflicts(Vec<SyncConflict>),
     NotFound,
+    ParentUnavailable,
+    Stored,
+    Needed(Vec<Uuid>),
+    Forbidden,
+    DigestMismatch,
+    AlreadyStored,
     Internal,
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::transactions::{Action, DataType};
+
+    fn log() -> Vec<InternalMessage> {
+        ["a", "b", "c"]
+            .iter()
+            .map(|key| {
+                InternalMessage::new(
+                    DataType::Bytes,
+                    Action::Add,
+                    format!("{key}.current"),
+                    "parent@example.com",
+                )
+            })
+            .collect()
+    }
+
+    fn frames(log: &[InternalMessage]) -> Vec<u8> {
+        zstd::decode_all(encode_frames(log).unwrap().as_slice()).unwrap()
+    }
+
+    #[test]
+    fn frames_round_trip() {
+        let log = log();
+
+        let decoded = decode_frames(&encode_frames(&log).unwrap()).unwrap();
+
+        let ids: Vec<Uuid> = decoded.iter().map(|t| t.id).collect();
+        assert_eq!(ids, log.iter().map(|t| t.id).collect::<Vec<_>>());
+        assert!(decode_frames(&encode_frames(&[]).unwrap())
+            .unwrap()
+            .is_empty());
+    }
+
+    #[test]
+    fn truncated_frames_are_rejected() {
+        let frames = frames(&log());
+        let first = 4 + u32::from_be_bytes(frames[..4].try_into().unwrap()) as usize;
+
+        // cut inside the second frame's length, then inside its JSON
+        for cut in [first + 2, first + 10] {
+            let truncated = zstd::encode_all(&frames[..cut], 0).unwrap();
+
+            assert!(decode_frames(&truncated).is_err(), "cut at {cut}");
+        }
+    }
+
+    #[test]
+    fn oversized_frames_are_rejected() {
+        let mut frames = (u32::MAX).to_be_bytes().to_vec();
+        frames.extend_from_slice(b"{}");
+
+        let err = decode_frames(&zstd::encode_all(frames.as_slice(), 0).unwrap()).unwrap_err();
+
+        assert!(err.to_string().contains("over"));
+    }
+}
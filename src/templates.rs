@@ -1,49 +1,154 @@
-use crate::datatypes::FormTemplate;
-use crate::storage_manager::StorageManager;
-use anyhow::Error;
-use axum::extract::Path;
-use axum::http::StatusCode;
+use crate::auth::GoogleUser;
+use crate::datatypes::{FieldData, FormTemplate, TemplateCount};
+use crate::patch::PatchOperation;
+use crate::storage_manager::{StorageError, StorageManager};
+use crate::transactions::DeletePreview;
+use axum::extract::{Path, Query};
+use axum::http::{header, HeaderMap, StatusCode};
 use axum::response::{IntoResponse, Response};
 use axum::{Extension, Json};
+use chrono::{DateTime, Utc};
+use serde::Deserialize;
+use serde_json::Value;
+use std::collections::HashMap;
 use std::sync::Arc;
 use tracing::instrument;
 
 #[instrument(skip(template, storage_manager))]
 pub async fn add_template(
+    user: GoogleUser,
     storage_manager: Extension<Arc<StorageManager>>,
     Json(template): Json<FormTemplate>,
 ) -> TemplatesResponse {
-    match storage_manager.templates_add(template).await {
+    match storage_manager.templates_add(template, &user.email).await {
         Ok(_) => TemplatesResponse::OK,
-        Err(_) => TemplatesResponse::FailedToAdd,
+        Err(e) => match e.downcast::<StorageError>() {
+            Ok(e @ StorageError::TemplateNameReserved(_)) => {
+                TemplatesResponse::NameReserved(e.to_string())
+            }
+            Ok(e @ (StorageError::InvalidDefaults(_) | StorageError::TemplateTooLarge(_))) => {
+                TemplatesResponse::Invalid(e.to_string())
+            }
+            Ok(e @ StorageError::AlreadyExists { .. }) => TemplatesResponse::Exists(e.to_string()),
+            _ => TemplatesResponse::FailedToAdd,
+        },
     }
 }
 
-#[instrument(skip(storage_manager))]
+#[instrument(skip(headers, storage_manager))]
 pub async fn get_template(
+    Path(name): Path<String>,
+    headers: HeaderMap,
+    storage_manager: Extension<Arc<StorageManager>>,
+) -> TemplatesResponse {
+    let last_modified = storage_manager
+        .template_last_modified(&name)
+        .await
+        .ok()
+        .flatten();
+
+    let since = headers
+        .get(header::IF_MODIFIED_SINCE)
+        .and_then(|v| v.to_str().ok())
+        .and_then(|v| DateTime::parse_from_rfc2822(v).ok());
+
+    if let (Some(modified), Some(since)) = (last_modified, since) {
+        if modified <= since.timestamp() {
+            return TemplatesResponse::NotModified;
+        }
+    }
+
+    match storage_manager.templates_get(name).await {
+        Ok(t) => TemplatesResponse::Template(t, last_modified),
+        Err(_) => TemplatesResponse::FailedToRead,
+    }
+}
+
+#[instrument(skip(storage_manager))]
+pub async fn get_template_schema(
     Path(name): Path<String>,
     storage_manager: Extension<Arc<StorageManager>>,
 ) -> TemplatesResponse {
     match storage_manager.templates_get(name).await {
-        Ok(t) => TemplatesResponse::Template(t),
+        Ok(t) => TemplatesResponse::Schema(t.json_schema()),
+        Err(_) => TemplatesResponse::FailedToRead,
+    }
+}
+
+#[instrument(skip(storage_manager))]
+pub async fn get_template_defaults(
+    Path(name): Path<String>,
+    storage_manager: Extension<Arc<StorageManager>>,
+) -> TemplatesResponse {
+    match storage_manager.template_defaults(name).await {
+        Ok(d) => TemplatesResponse::Defaults(d),
         Err(_) => TemplatesResponse::FailedToRead,
     }
 }
 
 #[instrument(skip(storage_manager, template))]
 pub async fn edit_template(
+    Query(query): Query<ForceQuery>,
+    user: GoogleUser,
     storage_manager: Extension<Arc<StorageManager>>,
     Json(template): Json<FormTemplate>,
 ) -> TemplatesResponse {
-    match storage_manager.templates_edit(template).await {
+    match storage_manager
+        .templates_edit(template, query.force.unwrap_or(false), &user.email)
+        .await
+    {
+        Ok(_) => TemplatesResponse::OK,
+        Err(e) => match e.downcast::<StorageError>() {
+            Ok(StorageError::FormsWouldBreak(ids)) => TemplatesResponse::WouldBreakForms(ids),
+            Ok(e @ StorageError::TemplateNameReserved(_)) => {
+                TemplatesResponse::NameReserved(e.to_string())
+            }
+            Ok(e @ (StorageError::InvalidDefaults(_) | StorageError::TemplateTooLarge(_))) => {
+                TemplatesResponse::Invalid(e.to_string())
+            }
+            _ => TemplatesResponse::FailedToEdit,
+        },
+    }
+}
+
+#[instrument(skip(storage_manager, operations))]
+pub async fn patch_template(
+    Path(name): Path<String>,
+    Query(query): Query<ForceQuery>,
+    user: GoogleUser,
+    storage_manager: Extension<Arc<StorageManager>>,
+    Json(operations): Json<Vec<PatchOperation>>,
+) -> TemplatesResponse {
+    match storage_manager
+        .templates_patch(name, &operations, query.force.unwrap_or(false), &user.email)
+        .await
+    {
         Ok(_) => TemplatesResponse::OK,
-        Err(_) => TemplatesResponse::FailedToEdit,
+        Err(e) => match e.downcast::<StorageError>() {
+            Ok(StorageError::FormsWouldBreak(ids)) => TemplatesResponse::WouldBreakForms(ids),
+            Ok(
+                e @ (StorageError::InvalidPatch(_)
+                | StorageError::InvalidDefaults(_)
+                | StorageError::TemplateTooLarge(_)),
+            ) => TemplatesResponse::Invalid(e.to_string()),
+            _ => TemplatesResponse::FailedToEdit,
+        },
     }
 }
 
 #[instrument(skip(storage_manager))]
-pub async fn list_templates(storage_manager: Extension<Arc<StorageManager>>) -> TemplatesResponse {
-    match storage_manager.templates_list().await {
+pub async fn list_templates(
+    Query(query): Query<ListQuery>,
+    storage_manager: Extension<Arc<StorageManager>>,
+) -> TemplatesResponse {
+    if query.counts.unwrap_or(false) {
+        return match storage_manager.templates_with_counts(query.year).await {
+            Ok(l) => TemplatesResponse::Counts(l),
+            Err(_) => TemplatesResponse::FailedToRead,
+        };
+    }
+
+    match storage_manager.templates_list(query.year).await {
         Ok(l) => TemplatesResponse::List(l),
         Err(_) => TemplatesResponse::FailedToRead,
     }
@@ -52,35 +157,219 @@ pub async fn list_templates(storage_manager: Extension<Arc<StorageManager>>) ->
 #[instrument(skip(storage_manager))]
 pub async fn delete_template(
     Path(name): Path<String>,
+    Query(query): Query<DeleteQuery>,
+    user: GoogleUser,
     storage_manager: Extension<Arc<StorageManager>>,
 ) -> TemplatesResponse {
-    match storage_manager.templates_delete(name).await {
+    if query.dry_run.unwrap_or(false) {
+        return match storage_manager
+            .templates_delete_preview(name, &user.email)
+            .await
+        {
+            Ok(preview) => TemplatesResponse::Preview(preview),
+            Err(_) => TemplatesResponse::FailedToRead,
+        };
+    }
+
+    match storage_manager
+        .templates_delete(name, query.force.unwrap_or(false), &user.email)
+        .await
+    {
         Ok(_) => TemplatesResponse::OK,
-        Err(_) => TemplatesResponse::FailedToDelete,
+        Err(e) => match e.downcast::<StorageError>() {
+            Ok(e @ StorageError::TemplateHasForms(_)) => TemplatesResponse::HasForms(e.to_string()),
+            _ => TemplatesResponse::FailedToDelete,
+        },
     }
 }
 
+#[derive(Debug, Deserialize)]
+pub struct ListQuery {
+    year: Option<i64>,
+    /// List each template with its live form count instead of just names.
+    counts: Option<bool>,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct ForceQuery {
+    force: Option<bool>,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct DeleteQuery {
+    force: Option<bool>,
+    /// Report what would be deleted instead of deleting.
+    dry_run: Option<bool>,
+}
+
 #[derive(Debug)]
 pub enum TemplatesResponse {
     OK,
-    Template(FormTemplate),
+    /// The template and its last modification, in unix seconds.
+    Template(FormTemplate, Option<i64>),
+    NotModified,
+    Schema(Value),
+    Defaults(HashMap<String, FieldData>),
     List(Vec<String>),
+    Counts(Vec<TemplateCount>),
     FailedToAdd,
     FailedToEdit,
     FailedToDelete,
     FailedToRead,
+    HasForms(String),
+    WouldBreakForms(Vec<String>),
+    NameReserved(String),
+    Invalid(String),
+    Exists(String),
+    Preview(DeletePreview),
 }
 
 impl IntoResponse for TemplatesResponse {
     fn into_response(self) -> Response {
         match self {
             TemplatesResponse::OK => StatusCode::OK.into_response(),
-            TemplatesResponse::Template(t) => (StatusCode::OK, Json(t)).into_response(),
+            TemplatesResponse::Template(t, last_modified) => {
+                let last_modified = last_modified
+                    .and_then(|secs| DateTime::<Utc>::from_timestamp(secs, 0))
+                    .map(|t| t.format("%a, %d %b %Y %H:%M:%S GMT").to_string());
+
+                match last_modified {
+                    Some(date) => {
+                        (StatusCode::OK, [(header::LAST_MODIFIED, date)], Json(t)).into_response()
+                    }
+                    None => (StatusCode::OK, Json(t)).into_response(),
+                }
+            }
+            TemplatesResponse::NotModified => StatusCode::NOT_MODIFIED.into_response(),
+            TemplatesResponse::Schema(s) => (StatusCode::OK, Json(s)).into_response(),
+            TemplatesResponse::Defaults(d) => (StatusCode::OK, Json(d)).into_response(),
+            TemplatesResponse::Preview(p) => (StatusCode::OK, Json(p)).into_response(),
             TemplatesResponse::FailedToAdd => StatusCode::BAD_REQUEST.into_response(),
             TemplatesResponse::FailedToEdit => StatusCode::BAD_REQUEST.into_response(),
             TemplatesResponse::FailedToDelete => StatusCode::BAD_REQUEST.into_response(),
             TemplatesResponse::FailedToRead => StatusCode::BAD_REQUEST.into_response(),
             TemplatesResponse::List(l) => (StatusCode::OK, Json(l)).into_response(),
+            TemplatesResponse::Counts(l) => (StatusCode::OK, Json(l)).into_response(),
+            TemplatesResponse::HasForms(message) => (StatusCode::CONFLICT, message).into_response(),
+            TemplatesResponse::Exists(message) => (StatusCode::CONFLICT, message).into_response(),
+            TemplatesResponse::WouldBreakForms(ids) => {
+                (StatusCode::CONFLICT, Json(ids)).into_response()
+            }
+            TemplatesResponse::NameReserved(message) => {
+                (StatusCode::BAD_REQUEST, message).into_response()
+            }
+            TemplatesResponse::Invalid(message) => {
+                (StatusCode::UNPROCESSABLE_ENTITY, message).into_response()
+            }
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::storage_manager::tests::{storage, template};
+
+    async fn get_since(
+        storage_manager: &Extension<Arc<StorageManager>>,
+        since: DateTime<Utc>,
+    ) -> Response {
+        let mut headers = HeaderMap::new();
+        headers.insert(
+            header::IF_MODIFIED_SINCE,
+            since.to_rfc2822().parse().unwrap(),
+        );
+
+        get_template(Path("scores".into()), headers, storage_manager.clone())
+            .await
+            .into_response()
+    }
+
+    #[tokio::test]
+    async fn unchanged_templates_are_not_modified() {
+        let (_dir, storage_manager) = storage().await;
+        storage_manager
+            .templates_add(
+                template("scores", serde_json::json!([])),
+                "lead@example.com",
+            )
+            .await
+            .unwrap();
+        let storage_manager = Extension(Arc::new(storage_manager));
+
+        let response = get_since(&storage_manager, Utc::now() + chrono::Duration::hours(1)).await;
+
+        assert_eq!(response.status(), StatusCode::NOT_MODIFIED);
+    }
+
+    #[tokio::test]
+    async fn changed_templates_are_sent_with_last_modified() {
+        let (_dir, storage_manager) = storage().await;
+        storage_manager
+            .templates_add(
+                template("scores", serde_json::json!([])),
+                "lead@example.com",
+            )
+            .await
+            .unwrap();
+        let storage_manager = Extension(Arc::new(storage_manager));
+
+        let response = get_since(&storage_manager, Utc::now() - chrono::Duration::hours(1)).await;
+
+        assert_eq!(response.status(), StatusCode::OK);
+        let last_modified = response.headers()[header::LAST_MODIFIED].to_str().unwrap();
+        assert!(DateTime::parse_from_rfc2822(last_modified).is_ok());
+    }
+
+    #[tokio::test]
+    async fn deleted_templates_are_never_not_modified() {
+        let (_dir, storage_manager) = storage().await;
+        storage_manager
+            .templates_add(
+                template("scores", serde_json::json!([])),
+                "lead@example.com",
+            )
+            .await
+            .unwrap();
+        storage_manager
+            .templates_delete("scores".into(), false, "lead@example.com")
+            .await
+            .unwrap();
+        let storage_manager = Extension(Arc::new(storage_manager));
+
+        let response = get_since(&storage_manager, Utc::now() + chrono::Duration::hours(1)).await;
+
+        assert_ne!(response.status(), StatusCode::NOT_MODIFIED);
+        assert!(response.headers().get(header::LAST_MODIFIED).is_none());
+    }
+
+    #[tokio::test]
+    async fn adding_an_existing_template_conflicts() {
+        let (_dir, storage_manager) = storage().await;
+        let storage_manager = Extension(Arc::new(storage_manager));
+        let add = || async {
+            add_template(
+                GoogleUser {
+                    id: "1".into(),
+                    email: "lead@example.com".into(),
+                    verified_email: true,
+                    picture: String::new(),
+                    hd: None,
+                },
+                storage_manager.clone(),
+                Json(template("scores", serde_json::json!([]))),
+            )
+            .await
+            .into_response()
+        };
+
+        assert_eq!(add().await.status(), StatusCode::OK);
+
+        let duplicate = add().await;
+        assert_eq!(duplicate.status(), StatusCode::CONFLICT);
+        let body = axum::body::to_bytes(duplicate.into_body(), usize::MAX)
+            .await
+            .unwrap();
+        assert_eq!(body, "Template scores already exists");
+    }
+}
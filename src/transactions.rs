@@ -1,26 +1,158 @@
+use chrono::Utc;
 use serde::{Deserialize, Serialize};
 use uuid::Uuid;
 
+/// Who transactions not written on behalf of a request are attributed to, such
+/// as those applied by sync.
+pub const SYSTEM_USER: &str = "system";
+
 impl InternalMessage {
-    pub fn new(data_type: DataType, action: Action, new_path: String) -> Self {
+    /// The digested name of the item this transaction touched, shared by every
+    /// version of that item.
+    pub fn alt_key(&self) -> &str {
+        self.new_path.split('.').next().unwrap_or_default()
+    }
+
+    pub fn new(data_type: DataType, action: Action, new_path: String, user: &str) -> Self {
         Self {
             data_type,
             action,
             new_path,
             id: Uuid::new_v4(),
+            timestamp: Utc::now().timestamp_micros(),
+            seq: 0,
+            user: Some(user.to_string()),
+            checksum: None,
+            original_timestamp: None,
+            original_seq: None,
         }
     }
+
+    /// Records the sha256 of the file the transaction left current.
+    pub fn with_checksum(mut self, checksum: String) -> Self {
+        self.checksum = Some(checksum);
+        self
+    }
+
+    /// `timestamp` and `seq` on the instance that first made the change.
+    pub fn origin(&self) -> (i64, u64) {
+        (
+            self.original_timestamp.unwrap_or(self.timestamp),
+            self.original_seq.unwrap_or(self.seq),
+        )
+    }
 }
 
-#[derive(Serialize, Deserialize, Debug)]
+#[derive(Serialize, Deserialize, Debug, Clone)]
 pub struct InternalMessage {
     pub id: Uuid,
     pub data_type: DataType,
     pub action: Action,
     pub new_path: String,
+    #[serde(default)]
+    pub timestamp: i64,
+    /// Position in the local log, assigned when the transaction is written.
+    #[serde(default)]
+    pub seq: u64,
+    /// Email of the user whose request wrote the transaction.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub user: Option<String>,
+    /// sha256 of the item's current file as this add or edit wrote it.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub checksum: Option<String>,
+    /// `timestamp` on the instance the transaction came from, kept when it is
+    /// re-stamped on import.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub original_timestamp: Option<i64>,
+    /// `seq` on the instance the transaction came from.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub original_seq: Option<u64>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Default)]
+pub struct ImportSummary {
+    pub applied: usize,
+    pub skipped: usize,
+    /// Transactions, local or imported, that lost a conflict.
+    pub superseded: usize,
+    /// Storage-relative paths referenced by applied transactions that are not on disk.
+    pub missing: Vec<String>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Default)]
+pub struct RestoreSummary {
+    pub restored: usize,
+    pub ids: Vec<String>,
+}
+
+/// Live and deleted items of one data type.
+#[derive(Serialize, Deserialize, Debug, Default)]
+pub struct ItemCounts {
+    pub live: usize,
+    pub deleted: usize,
+}
+
+impl ItemCounts {
+    pub fn count(&mut self, action: &Action) {
+        match action {
+            Action::Delete => self.deleted += 1,
+            _ => self.live += 1,
+        }
+    }
 }
 
+/// Totals across the whole store, for dashboards.
+#[derive(Serialize, Deserialize, Debug, Default)]
+pub struct StorageOverview {
+    pub forms: ItemCounts,
+    pub templates: ItemCounts,
+    pub schedules: ItemCounts,
+    pub blobs: ItemCounts,
+    pub transactions: usize,
+    /// Everything under the bytes directory, old versions and uploads included.
+    pub blob_bytes: u64,
+}
+
+/// What deleting a template or schedule would do, without doing it.
 #[derive(Serialize, Deserialize, Debug)]
+pub struct DeletePreview {
+    /// Forms stored against the template.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub forms: Option<usize>,
+    /// Shifts in the schedule.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub shifts: Option<usize>,
+    /// The transaction the delete would log.
+    pub transaction: InternalMessage,
+}
+
+/// An item both this node and an imported log changed since their last shared
+/// transaction.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct SyncConflict {
+    pub data_type: DataType,
+    pub alt_key: String,
+    /// The item's latest transaction, i.e. the version that was kept.
+    pub current: Option<Uuid>,
+    /// The transaction of the version that was set aside.
+    pub superseded: Uuid,
+    /// Where the set-aside version's file is kept, relative to its directory.
+    pub superseded_path: String,
+    /// When the conflict was resolved, in unix micros.
+    pub timestamp: i64,
+}
+
+/// How far this node is behind its parent.
+#[derive(Serialize, Deserialize, Debug, Default)]
+pub struct SyncDiff {
+    pub parent_head: Option<Uuid>,
+    pub missing_transactions: usize,
+    /// Files written by the missing transactions that are not on disk here and
+    /// can be pushed once the transactions are imported.
+    pub missing_blobs: usize,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq, Hash)]
 pub enum DataType {
     Bytes,
     Form(String),
@@ -28,9 +160,42 @@ pub enum DataType {
     Template,
 }
 
-#[derive(Serialize, Deserialize, Debug)]
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
 pub enum Action {
     Add,
     Delete,
     Edit,
+    /// A version that lost a sync conflict, kept aside under `new_path`. Never
+    /// the current state of its item.
+    Superseded,
+}
+
+/// How an imported transaction is reconciled with a local change to the same
+/// item made since the last transaction both nodes share.
+#[derive(Deserialize, Debug, Clone, Copy, Default)]
+pub enum MergePolicy {
+    /// The change with the later original timestamp wins, then the one with
+    /// the higher seq, then local.
+    #[default]
+    LastWriteWins,
+    /// Local changes are kept and imported ones set aside, to be reviewed
+    /// through the sync conflicts report.
+    Manual,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Resolution {
+    KeepLocal,
+    TakeForeign,
+}
+
+impl MergePolicy {
+    pub fn resolve(&self, local: &InternalMessage, foreign: &InternalMessage) -> Resolution {
+        match self {
+            MergePolicy::LastWriteWins if foreign.origin() > local.origin() => {
+                Resolution::TakeForeign
+            }
+            MergePolicy::LastWriteWins | MergePolicy::Manual => Resolution::KeepLocal,
+        }
+    }
 }